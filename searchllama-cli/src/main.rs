@@ -0,0 +1,411 @@
+use std::{
+    fmt::Display,
+    io::{self, BufRead, IsTerminal, Write},
+    process::{self, ExitCode},
+};
+
+use clap::{Args, Parser, Subcommand};
+use futures::{pin_mut, StreamExt};
+use searchllama_types::{
+    types::{
+        upsert_entry, Citation, Entry, SafeSearch, SearchEvent, SearchRequest, StageError, Status,
+        TimeRange,
+    },
+    Searchllama,
+};
+use serde::Serialize;
+
+/// Command line client for a searchllama server
+#[derive(Debug, Parser)]
+#[command(name = "searchllama", version, about)]
+struct Cli {
+    /// Base URL of the searchllama server
+    #[arg(
+        long,
+        global = true,
+        env = "SEARCHLLAMA_SERVER",
+        default_value = "http://127.0.0.1:3030"
+    )]
+    server: String,
+    /// Print every event as a line of JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Search the web and print the ranked results
+    Search {
+        #[command(flatten)]
+        options: SearchOptions,
+        #[arg(required = true)]
+        query: Vec<String>,
+    },
+    /// Answer a question from the search results, citing the sources
+    Ask {
+        #[command(flatten)]
+        options: SearchOptions,
+        #[arg(required = true)]
+        question: Vec<String>,
+    },
+    /// Chat about the sources of an earlier answer
+    Chat {
+        /// Session to continue, as printed by `ask`; starts a new one if left out
+        #[arg(long)]
+        session: Option<String>,
+        /// Message to send; read line by line from stdin if left out
+        message: Vec<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+struct SearchOptions {
+    /// Number of web results to fetch
+    #[arg(long)]
+    max_results: Option<usize>,
+    /// Region code, e.g. us-en
+    #[arg(long)]
+    region: Option<String>,
+    /// Only results from the last day, week, month or year
+    #[arg(long)]
+    time_range: Option<TimeRange>,
+    /// Safe search level: strict, moderate or off
+    #[arg(long)]
+    safe_search: Option<SafeSearch>,
+    /// Only results from this domain; can be repeated
+    #[arg(long = "source")]
+    sources: Vec<String>,
+    /// Don't search for related queries
+    #[arg(long)]
+    no_related: bool,
+    /// Don't record the search in the history
+    #[arg(long)]
+    private: bool,
+}
+
+impl SearchOptions {
+    fn request(self, query: &[String], answer: bool) -> SearchRequest {
+        SearchRequest {
+            max_results: self.max_results,
+            region: self.region,
+            time_range: self.time_range,
+            safe_search: self.safe_search,
+            answer: Some(answer),
+            related_queries: self.no_related.then_some(false),
+            sources: self.sources,
+            private: self.private.then_some(true),
+            ..SearchRequest::new(&query.join(" "))
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let client = match Searchllama::builder().base_url(&cli.server).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let ok = match cli.command {
+        Command::Search { options, query } => {
+            search(&client, options.request(&query, false), cli.json).await
+        }
+        Command::Ask { options, question } => {
+            search(&client, options.request(&question, true), cli.json).await
+        }
+        Command::Chat { session, message } => chat(&client, session, message, cli.json).await,
+    };
+
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Runs a search, streaming the answer if one was asked for. Returns whether
+/// the search succeeded.
+async fn search(client: &Searchllama, request: SearchRequest, json: bool) -> bool {
+    let answer = request.answer.unwrap_or(true);
+    let events = match client.search(&request).await {
+        Ok(events) => events,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    pin_mut!(events);
+
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut citations: Vec<Citation> = Vec::new();
+    let mut session_id = None;
+    let mut status = Status::Failed;
+    while let Some(event) = events.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return false;
+            }
+        };
+        if json {
+            print_json(&event);
+        }
+
+        match event {
+            SearchEvent::Session { session_id: id } => session_id = Some(id),
+            SearchEvent::CachedResults { results } => {
+                for entry in results {
+                    upsert_entry(&mut entries, entry);
+                }
+            }
+            SearchEvent::ResultUpsert { entry } => upsert_entry(&mut entries, entry),
+            SearchEvent::AnswerToken { text } if !json => {
+                output(text);
+            }
+            SearchEvent::Citation(citation) => citations.push(citation),
+            SearchEvent::Error(error) if !json => print_error(&error),
+            SearchEvent::Done { status: done } => status = done,
+            _ => {}
+        }
+    }
+
+    if !json {
+        if answer {
+            output("\n");
+            citations.sort_by_key(|c| c.index);
+            if !citations.is_empty() {
+                output("\n");
+            }
+            for citation in &citations {
+                output(format_args!(
+                    "[{}] {} <{}>\n",
+                    citation.index, citation.title, citation.url
+                ));
+            }
+            if let Some(session_id) = session_id {
+                eprintln!("\nsession: {}", session_id);
+            }
+        } else {
+            print_entries(&mut entries);
+        }
+    }
+
+    status == Status::Done
+}
+
+/// Sends `message`, or every line of stdin, to the chat session. Returns
+/// whether all messages were answered.
+async fn chat(
+    client: &Searchllama,
+    mut session_id: Option<String>,
+    message: Vec<String>,
+    json: bool,
+) -> bool {
+    if !message.is_empty() {
+        return send_message(client, &message.join(" "), &mut session_id, json).await;
+    }
+
+    let interactive = io::stdin().is_terminal();
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        if interactive {
+            eprint!("> ");
+        }
+        let Some(Ok(line)) = lines.next() else {
+            return true;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !send_message(client, &line, &mut session_id, json).await {
+            return false;
+        }
+    }
+}
+
+async fn send_message(
+    client: &Searchllama,
+    message: &str,
+    session_id: &mut Option<String>,
+    json: bool,
+) -> bool {
+    let started = session_id.is_none();
+    let responses = match client.chat(message, session_id.as_deref()).await {
+        Ok(responses) => responses,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    pin_mut!(responses);
+
+    let mut status = Status::Failed;
+    while let Some(response) = responses.next().await {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return false;
+            }
+        };
+        if json {
+            print_json(&response);
+        } else {
+            output(&response.response);
+            if let Some(error) = &response.error {
+                eprintln!("error: {}", error.message);
+            }
+        }
+
+        if let Some(id) = response.session_id {
+            *session_id = Some(id);
+        }
+        if let Some(done) = response.status {
+            status = done;
+        }
+    }
+
+    if !json {
+        output("\n");
+        if started {
+            if let Some(session_id) = session_id {
+                eprintln!("session: {}", session_id);
+            }
+        }
+    }
+
+    status == Status::Done
+}
+
+fn print_entries(entries: &mut [Entry]) {
+    output(format_entries(entries));
+}
+
+/// `entries` ranked by score, best first, with their title and URL.
+fn format_entries(entries: &mut [Entry]) -> String {
+    entries.sort_by(|a, b| b.score.total_cmp(&a.score));
+    entries
+        .iter()
+        .enumerate()
+        .map(|(rank, entry)| {
+            format!(
+                "{:>2}. {:.3}  {}\n           {}\n",
+                rank + 1,
+                entry.score,
+                entry.title,
+                entry.url
+            )
+        })
+        .collect()
+}
+
+fn print_error(error: &StageError) {
+    match &error.subject {
+        Some(subject) => eprintln!(
+            "warning: {:?} failed for {}: {}",
+            error.stage, subject, error.message
+        ),
+        None => eprintln!("warning: {:?} failed: {}", error.stage, error.message),
+    }
+}
+
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(line) => output(format_args!("{}\n", line)),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+/// Writes to stdout, stopping quietly once the reader went away, e.g. when
+/// piped into `head`.
+fn output(text: impl Display) {
+    let mut stdout = io::stdout().lock();
+    if write!(stdout, "{}", text)
+        .and_then(|_| stdout.flush())
+        .is_err()
+    {
+        process::exit(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    fn search_request(args: &[&str]) -> SearchRequest {
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Command::Search { options, query } => options.request(&query, false),
+            Command::Ask { options, question } => options.request(&question, true),
+            Command::Chat { .. } => panic!("not a search"),
+        }
+    }
+
+    #[test]
+    fn flags_map_onto_the_request() {
+        let request = search_request(&["searchllama", "search", "rust", "borrow", "checker"]);
+        assert_eq!(request.query, "rust borrow checker");
+        assert_eq!(request.answer, Some(false));
+        // Left to the server's defaults
+        assert_eq!(request.related_queries, None);
+        assert_eq!(request.private, None);
+        assert!(request.sources.is_empty());
+
+        let request = search_request(&[
+            "searchllama",
+            "ask",
+            "--no-related",
+            "--private",
+            "--source",
+            "doc.rust-lang.org",
+            "--source",
+            "github.com",
+            "--time-range",
+            "week",
+            "what",
+            "is",
+            "new",
+        ]);
+        assert_eq!(request.query, "what is new");
+        assert_eq!(request.answer, Some(true));
+        assert_eq!(request.related_queries, Some(false));
+        assert_eq!(request.private, Some(true));
+        assert_eq!(request.sources, vec!["doc.rust-lang.org", "github.com"]);
+        assert_eq!(request.time_range, Some(TimeRange::Week));
+    }
+
+    #[test]
+    fn entries_are_printed_best_first() {
+        let entry = |score: f64, url: &str| Entry {
+            score,
+            url: url.to_string(),
+            title: url.to_uppercase(),
+            description: String::new(),
+        };
+        let mut entries = vec![entry(0.2, "low"), entry(0.9, "high"), entry(0.5, "middle")];
+
+        let printed = format_entries(&mut entries);
+        let urls = printed
+            .lines()
+            .skip(1)
+            .step_by(2)
+            .map(str::trim)
+            .collect::<Vec<&str>>();
+        assert_eq!(urls, vec!["high", "middle", "low"]);
+        assert!(printed.starts_with(" 1. 0.900  HIGH\n           high\n"));
+    }
+}
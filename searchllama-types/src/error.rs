@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Errors raised by the client.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The builder was given a URL or header that cannot be used.
+    #[error("Invalid client configuration: {0}")]
+    Config(String),
+    /// The request could not be sent or the response could not be read.
+    #[error("Request failed: {0}")]
+    Transport(#[from] reqwest::Error),
+    #[error("No response within {0:?}")]
+    Timeout(Duration),
+    /// The server answered with an error status; `message` is the body.
+    #[error("Server returned {status}: {message}")]
+    Status { status: u16, message: String },
+    /// The server sent something the client does not understand.
+    #[error("Invalid response: {0}")]
+    Decode(String),
+}
+
+impl ClientError {
+    /// Whether sending the request again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Transport(_) | ClientError::Timeout(_) => true,
+            ClientError::Status { status, .. } => *status == 429 || *status >= 500,
+            ClientError::Config(_) | ClientError::Decode(_) => false,
+        }
+    }
+}
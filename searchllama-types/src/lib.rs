@@ -1,91 +1,570 @@
-use std::sync::Mutex;
+use std::time::Duration;
 
-use futures::{future, Stream, StreamExt};
-use lazy_static::lazy_static;
-use log::{debug, info};
-use types::{ChatRequest, ChatResponse, SearchRequest, SearchResponse};
+use futures::{stream, Stream, StreamExt};
+use log::debug;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    RequestBuilder, Response, Url,
+};
+use serde::de::DeserializeOwned;
+use sse::SseDecoder;
+use types::{
+    upsert_entry, ChatRequest, ChatResponse, Entry, HistoryEntry, ResultsPage, ResultsRequest,
+    SearchEvent, SearchFrame, SearchRequest, SearchResponse, PROTOCOL_VERSION,
+};
 
+pub use error::ClientError;
+
+mod error;
+pub mod sse;
+mod timer;
 pub mod types;
 
-lazy_static! {
-    static ref CLIENT: reqwest::Client = reqwest::Client::new();
-}
+/// Address of a server running with the default settings.
+pub const DEFAULT_URL: &str = "http://127.0.0.1:3030";
 
+/// Client for the searchllama server API.
+#[derive(Debug, Clone)]
 pub struct Searchllama {
+    client: reqwest::Client,
     api_url: String,
-    context: Mutex<Option<Vec<i32>>>,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
 }
 
-impl Searchllama {
-    pub fn new(api_url: &str) -> Self {
+/// Configures a [`Searchllama`] client.
+#[derive(Debug, Clone)]
+pub struct SearchllamaBuilder {
+    api_url: String,
+    timeout: Option<Duration>,
+    auth_header: Option<String>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl Default for SearchllamaBuilder {
+    fn default() -> Self {
         Self {
-            api_url: api_url.to_string(),
-            context: Mutex::new(None),
+            api_url: DEFAULT_URL.to_string(),
+            timeout: None,
+            auth_header: None,
+            retries: 2,
+            retry_delay: Duration::from_millis(250),
         }
     }
-    pub async fn search(&self, query: &str) -> impl Stream<Item = Result<SearchResponse, String>> {
-        let query = SearchRequest {
-            query: query.into(),
-        };
-        //let query_json = serde_json::to_string(&query).unwrap();
+}
 
-        let stream = CLIENT
-            .post(&format!("{}/search", self.api_url))
-            .header("Content-Type", "application/json")
-            .json(&query)
-            .send()
-            .await
-            .expect("Failed to send request")
-            .bytes_stream();
+impl SearchllamaBuilder {
+    pub fn base_url(mut self, api_url: &str) -> Self {
+        self.api_url = api_url.trim_end_matches('/').to_string();
+        self
+    }
 
-        debug!("Sent request: {:?}", query);
+    /// How long to wait for the server to respond. Streams are not cut off
+    /// once they started.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        let stream = stream.filter(|res| future::ready(res.is_ok())).map(|res| {
-            serde_json::from_slice::<SearchResponse>(&res.unwrap()).map_err(|e| e.to_string())
-        });
+    /// Value of the `Authorization` header sent with every request, e.g.
+    /// `Bearer <token>`.
+    pub fn auth_header(mut self, value: &str) -> Self {
+        self.auth_header = Some(value.to_string());
+        self
+    }
+
+    /// How often a failed idempotent request is sent again.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled for every following one.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    pub fn build(self) -> Result<Searchllama, ClientError> {
+        Url::parse(&self.api_url)
+            .map_err(|e| ClientError::Config(format!("{}: {}", self.api_url, e)))?;
 
-        stream
+        let mut headers = HeaderMap::new();
+        if let Some(auth_header) = &self.auth_header {
+            let mut value = HeaderValue::from_str(auth_header)
+                .map_err(|e| ClientError::Config(format!("Authorization header: {}", e)))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+
+        Ok(Searchllama {
+            client,
+            api_url: self.api_url,
+            timeout: self.timeout,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+        })
     }
+}
+
+impl Searchllama {
+    pub fn builder() -> SearchllamaBuilder {
+        SearchllamaBuilder::default()
+    }
+
+    /// Client for the server at `api_url` with the default settings.
+    ///
+    /// Panics if `api_url` is not a valid URL; use [`Searchllama::builder`]
+    /// to handle that.
+    pub fn new(api_url: &str) -> Self {
+        Self::builder()
+            .base_url(api_url)
+            .build()
+            .expect("Failed to build client")
+    }
+
+    /// Streams the events of a search.
+    ///
+    /// Not retried, as the server records every search in the history and
+    /// starts a session and a crawl for it before it answers.
+    pub async fn search(
+        &self,
+        query: &SearchRequest,
+    ) -> Result<impl Stream<Item = Result<SearchEvent, ClientError>>, ClientError> {
+        let response = self
+            .send(
+                || {
+                    self.client
+                        .post(format!("{}/search", self.api_url))
+                        .header("Accept", "text/event-stream")
+                        .json(query)
+                },
+                false,
+            )
+            .await?;
+        debug!("Sent request: {:?}", query);
+
+        Ok(search_events(response))
+    }
+
+    /// Continues the session `session_id`, or starts a new one.
+    ///
+    /// Not retried, as the message is added to the session history.
     pub async fn chat(
         &self,
         message: &str,
-        context: Option<Vec<i32>>,
-    ) -> impl Stream<Item = Result<Vec<ChatResponse>, String>> {
+        session_id: Option<&str>,
+    ) -> Result<impl Stream<Item = Result<ChatResponse, ClientError>>, ClientError> {
         let query = ChatRequest {
+            session_id: session_id.map(str::to_string),
             message: message.into(),
-            context: context.unwrap_or(vec![]),
         };
 
-        let stream = CLIENT
-            .post(&format!("{}/chat", self.api_url))
-            .header("Content-Type", "application/json")
-            .json(&query)
-            .send()
-            .await
-            .expect("Failed to send request")
-            .bytes_stream();
+        let response = self
+            .send(
+                || {
+                    self.client
+                        .post(format!("{}/chat", self.api_url))
+                        .header("Accept", "text/event-stream")
+                        .json(&query)
+                },
+                false,
+            )
+            .await?;
 
-        let stream = stream.filter(|res| future::ready(res.is_ok())).map(|res| {
-            let str = String::from_utf8(res.unwrap().to_vec()).expect("Invalid UTF-8");
-            let responses = str.split("\t").collect::<Vec<&str>>();
+        Ok(decode_events(response.bytes_stream()))
+    }
 
-            //info!("Received responses: {:?}", responses);
+    /// Runs a search and waits for all of it, with the results ranked best
+    /// first.
+    pub async fn collect_search(
+        &self,
+        query: &SearchRequest,
+    ) -> Result<SearchResponse, ClientError> {
+        let events = self.search(query).await?;
+        let mut events = std::pin::pin!(events);
 
-            let responses = responses
-                .into_iter()
-                .filter(|s| !s.is_empty())
-                .map(|s| serde_json::from_str(s.trim()).map_err(|e| e.to_string()))
-                .collect::<Result<Vec<ChatResponse>, String>>();
+        let mut session_id = None;
+        let mut results: Vec<Entry> = Vec::new();
+        let mut answer = String::new();
+        let mut citations = Vec::new();
+        let mut errors = Vec::new();
+        while let Some(event) = events.next().await {
+            match event? {
+                SearchEvent::Session { session_id: id } => session_id = Some(id),
+                SearchEvent::CachedResults { results: cached } => {
+                    for entry in cached {
+                        upsert_entry(&mut results, entry);
+                    }
+                }
+                SearchEvent::ResultUpsert { entry } => upsert_entry(&mut results, entry),
+                SearchEvent::AnswerToken { text } => answer.push_str(&text),
+                SearchEvent::Citation(citation) => citations.push(citation),
+                SearchEvent::Error(error) => errors.push(error),
+                SearchEvent::StageStatus { .. } => {}
+                SearchEvent::Done { status } => {
+                    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+                    return Ok(SearchResponse {
+                        session_id,
+                        results,
+                        answer,
+                        citations,
+                        errors,
+                        status,
+                    });
+                }
+            }
+        }
 
-            responses
-        });
+        Err(ClientError::Decode(
+            "Search ended before it was done".to_string(),
+        ))
+    }
+
+    /// Sends a chat message and waits for the whole reply.
+    pub async fn collect_chat(
+        &self,
+        message: &str,
+        session_id: Option<&str>,
+    ) -> Result<ChatResponse, ClientError> {
+        let responses = self.chat(message, session_id).await?;
+        let mut responses = std::pin::pin!(responses);
+
+        let mut reply = ChatResponse::default();
+        while let Some(response) = responses.next().await {
+            let response = response?;
+            reply.response.push_str(&response.response);
+            reply.error = response.error.or(reply.error);
+            if response.status.is_some() {
+                reply.session_id = response.session_id;
+                reply.status = response.status;
+                return Ok(reply);
+            }
+        }
+
+        Err(ClientError::Decode(
+            "Chat ended before it was done".to_string(),
+        ))
+    }
+
+    /// A page of the server's index ranked for a query, without searching
+    /// the web.
+    pub async fn results(&self, request: &ResultsRequest) -> Result<ResultsPage, ClientError> {
+        let response = self
+            .send(
+                || {
+                    self.client
+                        .post(format!("{}/results", self.api_url))
+                        .json(request)
+                },
+                true,
+            )
+            .await?;
+
+        decode_json(response).await
+    }
+
+    /// Recorded searches, newest first.
+    pub async fn history(
+        &self,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<Vec<HistoryEntry>, ClientError> {
+        let mut params = Vec::new();
+        params.extend(limit.map(|limit| ("limit", limit)));
+        params.extend(offset.map(|offset| ("offset", offset)));
+        let response = self
+            .send(
+                || {
+                    self.client
+                        .get(format!("{}/history", self.api_url))
+                        .query(&params)
+                },
+                true,
+            )
+            .await?;
 
-        stream
+        decode_json(response).await
     }
+
+    pub async fn history_entry(&self, id: &str) -> Result<HistoryEntry, ClientError> {
+        let response = self
+            .send(
+                || self.client.get(format!("{}/history/{}", self.api_url, id)),
+                true,
+            )
+            .await?;
+
+        decode_json(response).await
+    }
+
+    /// Deletes a search from the history, with its chat session.
+    ///
+    /// A retry that finds the entry gone counts as a success, as the
+    /// response to an earlier attempt may have been lost.
+    pub async fn delete_history_entry(&self, id: &str) -> Result<(), ClientError> {
+        let (result, retries) = self
+            .send_counting_retries(
+                || {
+                    self.client
+                        .delete(format!("{}/history/{}", self.api_url, id))
+                },
+                true,
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(ClientError::Status { status: 404, .. }) if retries > 0 => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs a recorded search again with the same options.
+    ///
+    /// Not retried, like [`Searchllama::search`].
+    pub async fn rerun(
+        &self,
+        id: &str,
+    ) -> Result<impl Stream<Item = Result<SearchEvent, ClientError>>, ClientError> {
+        let response = self
+            .send(
+                || {
+                    self.client
+                        .post(format!("{}/history/{}/rerun", self.api_url, id))
+                        .header("Accept", "text/event-stream")
+                },
+                false,
+            )
+            .await?;
+
+        Ok(search_events(response))
+    }
+
+    /// Sends the request made by `request`, retrying with exponential backoff
+    /// if it is `idempotent` and failed in a way that may pass.
+    async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        idempotent: bool,
+    ) -> Result<Response, ClientError> {
+        self.send_counting_retries(request, idempotent).await.0
+    }
+
+    /// Like [`Searchllama::send`], also returning how often the request was
+    /// sent again.
+    async fn send_counting_retries(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        idempotent: bool,
+    ) -> (Result<Response, ClientError>, u32) {
+        let mut attempt = 0;
+        loop {
+            match self.send_once(request()).await {
+                Err(e) if idempotent && attempt < self.retries && e.is_retryable() => {
+                    let delay = self.retry_delay * 2u32.saturating_pow(attempt);
+                    debug!("Request failed, retrying in {:?}: {}", delay, e);
+                    timer::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return (result, attempt),
+            }
+        }
+    }
+
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let response = match self.timeout {
+            Some(timeout) => timer::timeout(timeout, request.send())
+                .await
+                .ok_or(ClientError::Timeout(timeout))??,
+            None => request.send().await?,
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClientError::Status {
+                status: status.as_u16(),
+                message: response.text().await.unwrap_or_default(),
+            });
+        }
+        Ok(response)
+    }
+}
+
+/// Decodes a stream of search events, checking the protocol version.
+fn search_events(response: Response) -> impl Stream<Item = Result<SearchEvent, ClientError>> {
+    decode_events(response.bytes_stream()).map(|frame: Result<SearchFrame, ClientError>| {
+        let frame = frame?;
+        if frame.version != PROTOCOL_VERSION {
+            return Err(ClientError::Decode(format!(
+                "Unsupported protocol version {} (expected {})",
+                frame.version, PROTOCOL_VERSION
+            )));
+        }
+        Ok(frame.event)
+    })
+}
+
+async fn decode_json<T: DeserializeOwned>(response: Response) -> Result<T, ClientError> {
+    let body = response.bytes().await?;
+    serde_json::from_slice(&body).map_err(|e| ClientError::Decode(e.to_string()))
+}
+
+/// Decodes the JSON payload of every server-sent event in `stream`.
+fn decode_events<T, B>(
+    stream: impl Stream<Item = Result<B, reqwest::Error>>,
+) -> impl Stream<Item = Result<T, ClientError>>
+where
+    T: DeserializeOwned,
+    B: AsRef<[u8]>,
+{
+    let mut decoder = SseDecoder::new();
+    stream.flat_map(move |chunk| {
+        let events = match chunk {
+            Ok(bytes) => decoder
+                .push(bytes.as_ref())
+                .into_iter()
+                .map(|event| {
+                    serde_json::from_str::<T>(&event.data)
+                        .map_err(|e| ClientError::Decode(e.to_string()))
+                })
+                .collect(),
+            Err(e) => vec![Err(e.into())],
+        };
+        stream::iter(events)
+    })
 }
 
 impl Default for Searchllama {
     fn default() -> Self {
-        Searchllama::new("http://127.0.0.1:3030")
+        Searchllama::new(DEFAULT_URL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+    use crate::types::{Status, PROTOCOL_VERSION};
+
+    /// Answers one request per response, then closes the connection.
+    fn serve(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request_complete(&request) {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    fn request_complete(request: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(request);
+        let Some((head, body)) = text.split_once("\r\n\r\n") else {
+            return false;
+        };
+        let length = head
+            .lines()
+            .find_map(|l| {
+                l.to_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|v| v.trim().parse().unwrap())
+            })
+            .unwrap_or(0);
+        body.len() >= length
+    }
+
+    fn frame(json: &str) -> String {
+        format!("data: {{\"version\":{},{}}}\n\n", PROTOCOL_VERSION, json)
+    }
+
+    #[tokio::test]
+    async fn collects_a_search() {
+        let body = [
+            frame(r#""type":"session","session_id":"s1""#),
+            frame(r#""type":"result_upsert","entry":{"score":0.2,"url":"https://a.com","title":"A","description":""}"#),
+            frame(r#""type":"result_upsert","entry":{"score":0.7,"url":"https://b.com","title":"B","description":""}"#),
+            frame(r#""type":"answer_token","text":"Hello ""#),
+            frame(r#""type":"answer_token","text":"[1]""#),
+            frame(r#""type":"done","status":"done""#),
+        ]
+        .concat();
+        let url = serve(vec![format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n{}",
+            body
+        )]);
+
+        let client = Searchllama::new(&url);
+        let response = client
+            .collect_search(&SearchRequest::new("q"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.session_id.as_deref(), Some("s1"));
+        assert_eq!(response.answer, "Hello [1]");
+        assert_eq!(response.status, Status::Done);
+        let urls = response
+            .results
+            .iter()
+            .map(|e| e.url.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(urls, vec!["https://b.com", "https://a.com"]);
+    }
+
+    const BUSY: &str =
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbusy";
+    const NOT_FOUND: &str =
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nConnection: close\r\n\r\ngone";
+
+    #[tokio::test]
+    async fn does_not_retry_a_chat_or_a_search() {
+        let url = serve(vec![BUSY.to_string(), BUSY.to_string()]);
+
+        let client = Searchllama::new(&url);
+        let error = client.collect_chat("hi", None).await.unwrap_err();
+        assert!(
+            matches!(error, ClientError::Status { status: 503, ref message } if message == "busy")
+        );
+        let error = client
+            .collect_search(&SearchRequest::new("q"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ClientError::Status { status: 503, .. }));
+    }
+
+    #[tokio::test]
+    async fn a_retried_delete_that_finds_nothing_succeeds() {
+        let url = serve(vec![
+            NOT_FOUND.to_string(),
+            BUSY.to_string(),
+            NOT_FOUND.to_string(),
+        ]);
+
+        let client = Searchllama::builder()
+            .base_url(&url)
+            .retry_delay(Duration::from_millis(1))
+            .build()
+            .unwrap();
+        // Without a retry the entry never existed
+        let error = client.delete_history_entry("h1").await.unwrap_err();
+        assert!(matches!(error, ClientError::Status { status: 404, .. }));
+        client.delete_history_entry("h1").await.unwrap();
     }
 }
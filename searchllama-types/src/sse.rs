@@ -0,0 +1,145 @@
+/// One server-sent event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: String,
+    pub data: String,
+}
+
+/// Incrementally decodes a `text/event-stream` body.
+///
+/// Network chunks can end anywhere, even inside a UTF-8 character, so input
+/// is buffered until a line is complete.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a chunk of the body and returns the events it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line = self.buffer.drain(..=newline).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                events.extend(self.dispatch());
+                continue;
+            }
+            if line.starts_with(':') {
+                // Comment, used for keep-alives
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "id" => self.id = Some(value.to_string()),
+                "event" => self.event = Some(value.to_string()),
+                "data" => {
+                    self.data.push_str(value);
+                    self.data.push('\n');
+                }
+                _ => {}
+            }
+        }
+
+        events
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut data = std::mem::take(&mut self.data);
+        data.pop();
+
+        Some(SseEvent {
+            id: self.id.clone(),
+            event: event.unwrap_or_else(|| "message".to_string()),
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{SearchFrame, PROTOCOL_VERSION};
+
+    const BODY: &str = ": keep-alive\n\nid:0\nevent:answer_token\ndata:{\"version\":2,\"type\":\"answer_token\",\"text\":\"Grüße\"}\n\nid: 1\r\nevent: done\r\ndata: {\"version\":2,\"type\":\"done\",\"status\":\"done\"}\r\n\r\n";
+
+    fn expected() -> Vec<SseEvent> {
+        vec![
+            SseEvent {
+                id: Some("0".to_string()),
+                event: "answer_token".to_string(),
+                data: "{\"version\":2,\"type\":\"answer_token\",\"text\":\"Grüße\"}".to_string(),
+            },
+            SseEvent {
+                id: Some("1".to_string()),
+                event: "done".to_string(),
+                data: "{\"version\":2,\"type\":\"done\",\"status\":\"done\"}".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn decodes_whole_body() {
+        let events = SseDecoder::new().push(BODY.as_bytes());
+        assert_eq!(events, expected());
+        // The fixture is made of current frames
+        for event in events {
+            let frame: SearchFrame = serde_json::from_str(&event.data).unwrap();
+            assert_eq!(frame.version, PROTOCOL_VERSION);
+            assert_eq!(frame.event.kind(), event.event);
+        }
+    }
+
+    #[test]
+    fn decodes_across_every_chunk_boundary() {
+        let bytes = BODY.as_bytes();
+        for split in 0..=bytes.len() {
+            let mut decoder = SseDecoder::new();
+            let mut events = decoder.push(&bytes[..split]);
+            events.extend(decoder.push(&bytes[split..]));
+            assert_eq!(events, expected(), "split at byte {}", split);
+        }
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let mut decoder = SseDecoder::new();
+        let events = BODY
+            .as_bytes()
+            .iter()
+            .flat_map(|b| decoder.push(&[*b]))
+            .collect::<Vec<SseEvent>>();
+        assert_eq!(events, expected());
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let events = SseDecoder::new().push(b"data: a\ndata: b\n\n");
+        assert_eq!(events[0].data, "a\nb");
+        assert_eq!(events[0].event, "message");
+    }
+
+    #[test]
+    fn waits_for_the_blank_line() {
+        let mut decoder = SseDecoder::new();
+        assert!(decoder.push(b"event: status\ndata: {}\n").is_empty());
+        assert_eq!(decoder.push(b"\n").len(), 1);
+    }
+}
@@ -0,0 +1,25 @@
+//! Timers for both native targets and the browser.
+
+use std::{future::Future, time::Duration};
+
+use futures::future::{self, Either};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: Duration) {
+    gloo_timers::future::sleep(duration).await
+}
+
+/// Runs `fut` for at most `duration`, returning `None` if it did not finish.
+pub async fn timeout<F: Future>(duration: Duration, fut: F) -> Option<F::Output> {
+    let fut = std::pin::pin!(fut);
+    let sleep = std::pin::pin!(sleep(duration));
+    match future::select(fut, sleep).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
@@ -1,9 +1,191 @@
-use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
 
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeSearch {
+    Strict,
+    #[default]
+    Moderate,
+    Off,
+}
+
+impl FromStr for SafeSearch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(SafeSearch::Strict),
+            "moderate" => Ok(SafeSearch::Moderate),
+            "off" => Ok(SafeSearch::Off),
+            _ => Err(format!(
+                "invalid safe search level '{}', expected strict, moderate or off",
+                s
+            )),
+        }
+    }
+}
+
+/// How recent results must be.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeRange {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl TimeRange {
+    pub fn duration(self) -> Duration {
+        let days = match self {
+            TimeRange::Day => 1,
+            TimeRange::Week => 7,
+            TimeRange::Month => 31,
+            TimeRange::Year => 365,
+        };
+        Duration::from_secs(days * 24 * 60 * 60)
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeRange::Day => "day",
+            TimeRange::Week => "week",
+            TimeRange::Month => "month",
+            TimeRange::Year => "year",
+        })
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(TimeRange::Day),
+            "week" => Ok(TimeRange::Week),
+            "month" => Ok(TimeRange::Month),
+            "year" => Ok(TimeRange::Year),
+            _ => Err(format!(
+                "invalid time range '{}', expected day, week, month or year",
+                s
+            )),
+        }
+    }
+}
+
+/// A search. Every option left out uses the server default.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SearchRequest {
-    pub query: String
+    pub query: String,
+    /// Web results fetched for the query itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<usize>,
+    /// Region or language code for the search engine, e.g. `us-en` for
+    /// DuckDuckGo or `en-US` for SearXNG.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Only return pages from this period. Cached results must have been
+    /// indexed within it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_range: Option<TimeRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safe_search: Option<SafeSearch>,
+    /// Whether to generate an answer. Defaults to `true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<bool>,
+    /// Whether to also search for related queries generated by the LLM.
+    /// Defaults to `true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub related_queries: Option<bool>,
+    /// Domains results may come from, subdomains included. Empty allows all.
+    /// Query strings take a comma separated list.
+    #[serde(
+        default,
+        deserialize_with = "string_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub sources: Vec<String>,
+    /// Keeps the search out of the history. Defaults to `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<bool>,
+}
+
+impl SearchRequest {
+    pub fn new(query: &str) -> Self {
+        Self {
+            query: query.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A page of indexed pages ranked by similarity to `query`, without
+/// searching the web.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ResultsRequest {
+    pub query: String,
+    /// Results per page. Defaults to the server's `search.max_entries`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Results to skip. Ignored when `cursor` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    /// The `next_cursor` of the previous page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    /// Only pages indexed within this period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_range: Option<TimeRange>,
+    /// Domains results may come from, as in `SearchRequest`.
+    #[serde(
+        default,
+        deserialize_with = "string_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub sources: Vec<String>,
+}
+
+impl ResultsRequest {
+    pub fn new(query: &str) -> Self {
+        Self {
+            query: query.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResultsPage {
+    /// Best match first.
+    pub results: Vec<Entry>,
+    /// Pass as `cursor` to get the next page. Not set on the last page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// Indexed pages matching the filters.
+    pub total: usize,
+}
+
+/// Accepts a list, or a comma separated string as sent in query strings.
+fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringList {
+        Joined(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringList::deserialize(deserializer)? {
+        StringList::Joined(joined) => joined
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        StringList::List(list) => list,
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -14,21 +196,243 @@ pub struct Entry {
     pub description: String,
 }
 
+/// Adds `entry` to `entries`, replacing the entry with the same URL.
+pub fn upsert_entry(entries: &mut Vec<Entry>, entry: Entry) {
+    match entries.iter_mut().find(|e| e.url == entry.url) {
+        Some(existing) => *existing = entry,
+        None => entries.push(entry),
+    }
+}
+
+/// Step of the search pipeline.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    QueryEmbedding,
+    Database,
+    RelatedQueries,
+    WebSearch,
+    Fetch,
+    Ranking,
+    Snippets,
+    Answer,
+    Chat,
+}
+
+/// A failure in one stage of the pipeline. The rest of the pipeline keeps
+/// running unless the stream ends with [`Status::Failed`].
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct SearchResponse {
-    pub results: Vec<Entry>,
-    pub summary: String,
-    pub summary_context: Option<Vec<i32>>,
+pub struct StageError {
+    pub stage: Stage,
+    pub message: String,
+    /// The URL or query the stage was working on, if any.
+    pub subject: Option<String>,
 }
 
+/// Outcome of a stream, sent with its last frame.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Done,
+    Failed,
+}
+
+/// Progress of a [`Stage`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StageState {
+    Started,
+    Finished,
+}
+
+/// A source the answer was generated from. `index` starts at 1.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Citation {
+    pub index: usize,
+    pub url: String,
+    pub title: String,
+    pub snippet: String,
+}
+
+/// Version of the search event protocol, bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// One update of a search stream.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchEvent {
+    /// The chat session of this search, to continue it with `/chat`.
+    Session {
+        session_id: String,
+    },
+    /// Results already in the database, sent once before crawling starts.
+    CachedResults {
+        results: Vec<Entry>,
+    },
+    /// A crawled result. Replaces any earlier entry with the same URL.
+    ResultUpsert {
+        entry: Entry,
+    },
+    StageStatus {
+        stage: Stage,
+        state: StageState,
+    },
+    /// The next piece of the answer.
+    AnswerToken {
+        text: String,
+    },
+    Citation(Citation),
+    Error(StageError),
+    /// The last event of every stream.
+    Done {
+        status: Status,
+    },
+}
+
+impl SearchEvent {
+    /// The `type` tag of the event.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SearchEvent::Session { .. } => "session",
+            SearchEvent::CachedResults { .. } => "cached_results",
+            SearchEvent::ResultUpsert { .. } => "result_upsert",
+            SearchEvent::StageStatus { .. } => "stage_status",
+            SearchEvent::AnswerToken { .. } => "answer_token",
+            SearchEvent::Citation(_) => "citation",
+            SearchEvent::Error(_) => "error",
+            SearchEvent::Done { .. } => "done",
+        }
+    }
+}
+
+/// A [`SearchEvent`] as sent over the wire.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchFrame {
+    pub version: u32,
+    #[serde(flatten)]
+    pub event: SearchEvent,
+}
+
+impl From<SearchEvent> for SearchFrame {
+    fn from(event: SearchEvent) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            event,
+        }
+    }
+}
+
+/// A message in a chat session. Without a session id a new session is
+/// started.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
     pub message: String,
-    pub context: Vec<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ChatResponse {
     pub response: String,
-    pub context: Option<Vec<i32>>,
-}
\ No newline at end of file
+    /// Sent with the final status frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<StageError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+/// A search as recorded in the history.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: String,
+    /// The search with its options, as sent.
+    pub request: SearchRequest,
+    /// Unix time the search was run.
+    pub created_at: i64,
+    /// Session to chat about the answer in.
+    pub session_id: Option<String>,
+    /// Best match first.
+    pub results: Vec<Entry>,
+    pub answer: String,
+}
+
+/// A search read to the end, as returned by `Searchllama::collect_search`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchResponse {
+    pub session_id: Option<String>,
+    /// Best match first.
+    pub results: Vec<Entry>,
+    pub answer: String,
+    pub citations: Vec<Citation>,
+    pub errors: Vec<StageError>,
+    pub status: Status,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> Vec<SearchEvent> {
+        let entry = Entry {
+            score: 0.5,
+            url: "https://example.com".into(),
+            title: "Example".into(),
+            description: String::new(),
+        };
+        vec![
+            SearchEvent::Session {
+                session_id: "id".into(),
+            },
+            SearchEvent::CachedResults {
+                results: vec![entry.clone()],
+            },
+            SearchEvent::ResultUpsert { entry },
+            SearchEvent::StageStatus {
+                stage: Stage::WebSearch,
+                state: StageState::Started,
+            },
+            SearchEvent::AnswerToken { text: "The".into() },
+            SearchEvent::Citation(Citation {
+                index: 1,
+                url: "https://example.com".into(),
+                title: "Example".into(),
+                snippet: "text".into(),
+            }),
+            SearchEvent::Error(StageError {
+                stage: Stage::Fetch,
+                message: "timeout".into(),
+                subject: None,
+            }),
+            SearchEvent::Done {
+                status: Status::Done,
+            },
+        ]
+    }
+
+    #[test]
+    fn frames_are_tagged_and_versioned() {
+        for event in events() {
+            let kind = event.kind();
+            let json = serde_json::to_value(SearchFrame::from(event)).unwrap();
+            assert_eq!(json["type"], kind);
+            assert_eq!(json["version"], PROTOCOL_VERSION);
+
+            let frame: SearchFrame = serde_json::from_value(json).unwrap();
+            assert_eq!(frame.event.kind(), kind);
+        }
+    }
+    #[test]
+    fn sources_accept_a_list_or_a_joined_string() {
+        let list: SearchRequest =
+            serde_json::from_str(r#"{"query":"q","sources":["a.com","b.org"]}"#).unwrap();
+        let joined: SearchRequest =
+            serde_json::from_str(r#"{"query":"q","sources":"a.com, b.org"}"#).unwrap();
+        assert_eq!(list.sources, vec!["a.com", "b.org"]);
+        assert_eq!(joined.sources, list.sources);
+
+        let minimal: SearchRequest = serde_json::from_str(r#"{"query":"q"}"#).unwrap();
+        assert!(minimal.sources.is_empty() && minimal.answer.is_none());
+    }
+}
@@ -1,6 +1,6 @@
 use log::info;
-use search::{ChatParams, SearchParams};
-use searchllama_types::types::Entry;
+use search::{ChatParams, ResultsParams, SearchParams};
+use searchllama_types::types::{upsert_entry, Citation, Entry, ResultsPage, ResultsRequest};
 use web_sys::HtmlInputElement;
 use yew::prelude::*;
 use yew_hooks::prelude::*;
@@ -22,18 +22,29 @@ struct Model {
     query: String,
     entries: Vec<Entry>,
     summary: String,
+    /// Sources cited in the summary, by index.
+    citations: Vec<Citation>,
     chat_prompt: String,
-    summary_embedding: Option<Vec<i32>>,
+    /// Session of the last search, continued by the chat.
+    session_id: Option<String>,
+    /// Results loaded from the index after the search's own.
+    more_entries: Vec<Entry>,
+    /// Where the next "load more" page starts.
+    results_cursor: Option<String>,
+    all_results_loaded: bool,
 }
 
 // Define messages for component state updates
 enum Msg {
     SearchInput(InputEvent),
     Search,
-    UpdateEntries((Vec<Entry>, String, Option<Vec<i32>>)),
+    UpdateEntries((Vec<Entry>, String, Option<String>)),
+    AddCitation(Citation),
     Chat,
     ChatInput(InputEvent),
-    UpdateChat((String, Option<Vec<i32>>)),
+    UpdateChat((String, Option<String>)),
+    LoadMore,
+    MoreResults(ResultsPage),
 }
 
 // Define the routing enum
@@ -55,8 +66,12 @@ impl Component for Model {
             query: ctx.props().query.clone(),
             entries: Vec::new(),
             summary: String::new(),
+            citations: Vec::new(),
             chat_prompt: String::new(),
-            summary_embedding: None,
+            session_id: None,
+            more_entries: Vec::new(),
+            results_cursor: None,
+            all_results_loaded: false,
         }
     }
 
@@ -75,11 +90,22 @@ impl Component for Model {
                     });
                 }
                 self.summary = String::new();
+                self.citations = Vec::new();
+                self.session_id = None;
                 self.entries = Vec::new();
+                self.more_entries = Vec::new();
+                self.results_cursor = None;
+                self.all_results_loaded = false;
 
                 let on_entries_update = ctx.link().callback(|entries| Msg::UpdateEntries(entries));
-                SearchParams::new(self.query.clone(), self.entries.clone(), on_entries_update)
-                    .search();
+                let on_citation = ctx.link().callback(Msg::AddCitation);
+                SearchParams::new(
+                    self.query.clone(),
+                    self.entries.clone(),
+                    on_entries_update,
+                    on_citation,
+                )
+                .search();
 
                 true
             }
@@ -90,7 +116,7 @@ impl Component for Model {
                 let on_chat_update = ctx.link().callback(|chat| Msg::UpdateChat(chat));
                 ChatParams::new(
                     self.chat_prompt.clone(),
-                    self.summary_embedding.clone(),
+                    self.session_id.clone(),
                     on_chat_update,
                 )
                 .send_chat();
@@ -98,24 +124,50 @@ impl Component for Model {
 
                 true
             }
-            Msg::UpdateEntries((entries, summary, summary_embedding)) => {
+            Msg::UpdateEntries((entries, summary, session_id)) => {
                 self.entries = entries;
                 self.summary.push_str(&summary);
-                if summary_embedding.is_some() {
-                    self.summary_embedding = summary_embedding;
+                if session_id.is_some() {
+                    self.session_id = session_id;
                 }
                 true
             }
+            Msg::AddCitation(citation) => {
+                self.citations.push(citation);
+                self.citations.sort_by_key(|c| c.index);
+                true
+            }
             Msg::ChatInput(input) => {
                 let element: HtmlInputElement = input.target_unchecked_into();
                 self.chat_prompt = element.value(); // Update the query with the new value from the chat box
                 true
             }
-            Msg::UpdateChat((message, context)) => {
+            Msg::UpdateChat((message, session_id)) => {
                 self.summary.push_str(&message);
-                if context.is_some() {
-                    self.summary_embedding = context;
+                if session_id.is_some() {
+                    self.session_id = session_id;
+                }
+                true
+            }
+            Msg::LoadMore => {
+                let mut request = ResultsRequest::new(&self.query);
+                match &self.results_cursor {
+                    Some(cursor) => request.cursor = Some(cursor.clone()),
+                    // The first page skips about as many as the search shows
+                    None => request.offset = Some(self.entries.len() + self.more_entries.len()),
                 }
+
+                let on_page = ctx.link().callback(Msg::MoreResults);
+                ResultsParams::new(request, on_page).load();
+
+                false
+            }
+            Msg::MoreResults(page) => {
+                for entry in page.results {
+                    upsert_entry(&mut self.more_entries, entry);
+                }
+                self.all_results_loaded = page.next_cursor.is_none();
+                self.results_cursor = page.next_cursor;
                 true
             }
         }
@@ -127,9 +179,14 @@ impl Component for Model {
         info!("entries.len(): {}", self.entries.len());
 
         let link = ctx.link();
+        let more_entries = self
+            .more_entries
+            .iter()
+            .filter(|entry| !self.entries.iter().any(|e| e.url == entry.url));
         let entries = self
             .entries
             .iter()
+            .chain(more_entries)
             .map(|entry| {
                 html! {
                     <div class="entry-card">
@@ -154,8 +211,9 @@ impl Component for Model {
                         <>
                         <h2>{"Summary"}</h2>
                         <div class="markdown-body">
-                            <Markdown src={ self.summary.clone() } />
+                            <Markdown src={ link_citations(&self.summary, &self.citations) } />
                         </div>
+                        { footnotes(&self.citations) }
                         </>
                             }
                         } else {
@@ -179,6 +237,15 @@ impl Component for Model {
                     <div class="entries-section">
                         <h2>{"Entries"}</h2>
                         { entries }
+                        {if !self.entries.is_empty() && !self.all_results_loaded {
+                            html! {
+                                <button class="load-more-button" onclick={link.callback(|_| Msg::LoadMore)}>
+                                    {"Load more"}
+                                </button>
+                            }
+                        } else {
+                            html! {}
+                        }}
                     </div>
                 </div>
                 <div class="search-bar">
@@ -198,6 +265,37 @@ impl Component for Model {
     }
 }
 
+/// Turns the `[n]` markers of cited sources into links to their footnotes.
+fn link_citations(summary: &str, citations: &[Citation]) -> String {
+    citations
+        .iter()
+        .fold(summary.to_string(), |summary, citation| {
+            summary.replace(
+                &format!("[{}]", citation.index),
+                &format!("[\\[{0}\\]](#citation-{0})", citation.index),
+            )
+        })
+}
+
+fn footnotes(citations: &[Citation]) -> Html {
+    if citations.is_empty() {
+        return html! {};
+    }
+
+    html! {
+        <ol class="citations">
+            { for citations.iter().map(|citation| html! {
+                <li id={format!("citation-{}", citation.index)} value={citation.index.to_string()}>
+                    <a href={citation.url.clone()} target="_blank" rel="noopener noreferrer">
+                        { &citation.title }
+                    </a>
+                    <p class="citation-snippet">{ &citation.snippet }</p>
+                </li>
+            }) }
+        </ol>
+    }
+}
+
 // Define the switch function for routing
 fn switch(routes: Route) -> Html {
     match routes {
@@ -4,56 +4,106 @@ use std::{
 };
 
 use futures::StreamExt;
-use log::{error, info};
-use searchllama_types::types::Entry;
+use log::{debug, error, info, warn};
+use searchllama_types::types::{
+    Citation, Entry, ResultsPage, ResultsRequest, SearchEvent, SearchRequest, Status,
+};
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
 
 pub struct SearchParams {
     pub query: String,
     pub entries: Vec<Entry>,
-    pub on_entries_update: Callback<(Vec<Entry>, String, Option<Vec<i32>>)>,
+    pub on_entries_update: Callback<(Vec<Entry>, String, Option<String>)>,
+    pub on_citation: Callback<Citation>,
 }
 
 impl SearchParams {
     pub fn new(
         query: String,
         entries: Vec<Entry>,
-        on_entries_update: Callback<(Vec<Entry>, String, Option<Vec<i32>>)>,
+        on_entries_update: Callback<(Vec<Entry>, String, Option<String>)>,
+        on_citation: Callback<Citation>,
     ) -> Self {
         Self {
             query,
             entries,
             on_entries_update,
+            on_citation,
         }
     }
 
     pub fn search(&self) {
         let query = self.query.clone();
         let on_entries_update = self.on_entries_update.clone();
+        let on_citation = self.on_citation.clone();
 
         spawn_local(async move {
-            let mut response_stream = searchllama_types::Searchllama::default()
-                .search(&query.to_string())
-                .await;
+            let response_stream = match searchllama_types::Searchllama::default()
+                .search(&SearchRequest::new(&query))
+                .await
+            {
+                Ok(response_stream) => response_stream,
+                Err(e) => {
+                    error!("Search failed: {}", e);
+                    return;
+                }
+            };
+            let mut response_stream = Box::pin(response_stream);
 
             let mut entries: HashMap<String, Entry> = HashMap::new();
-            while let Some(entry) = response_stream.next().await {
-                if let Ok(response) = entry {
-                    entries.extend(
-                        response
-                            .results
-                            .into_iter()
-                            .map(|res| (res.url.clone(), res)),
-                    );
-                    let mut entries_vec = entries.values().cloned().collect::<Vec<Entry>>();
-                    entries_vec.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse());
-                    on_entries_update.emit((
-                        entries_vec,
-                        response.summary,
-                        response.summary_context,
-                    ));
+            while let Some(event) = response_stream.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!("Error: {}", e);
+                        continue;
+                    }
+                };
+
+                let mut summary = String::new();
+                let mut session_id = None;
+                match event {
+                    SearchEvent::Session { session_id: id } => {
+                        session_id = Some(id);
+                    }
+                    SearchEvent::CachedResults { results } => {
+                        entries.extend(results.into_iter().map(|res| (res.url.clone(), res)));
+                    }
+                    SearchEvent::ResultUpsert { entry } => {
+                        entries.insert(entry.url.clone(), entry);
+                    }
+                    SearchEvent::AnswerToken { text } => {
+                        summary = text;
+                    }
+                    SearchEvent::StageStatus { stage, state } => {
+                        debug!("{:?}: {:?}", stage, state);
+                        continue;
+                    }
+                    SearchEvent::Citation(citation) => {
+                        on_citation.emit(citation);
+                        continue;
+                    }
+                    SearchEvent::Error(e) => {
+                        warn!(
+                            "{:?} failed: {} {}",
+                            e.stage,
+                            e.message,
+                            e.subject.clone().unwrap_or_default()
+                        );
+                        continue;
+                    }
+                    SearchEvent::Done { status } => {
+                        if status == Status::Failed {
+                            error!("Search failed");
+                        }
+                        break;
+                    }
                 }
+
+                let mut entries_vec = entries.values().cloned().collect::<Vec<Entry>>();
+                entries_vec.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse());
+                on_entries_update.emit((entries_vec, summary, session_id));
             }
 
             info!("Done searching");
@@ -63,38 +113,47 @@ impl SearchParams {
 
 pub struct ChatParams {
     pub prompt: String,
-    pub context: Option<Vec<i32>>,
-    pub on_chat_update: Callback<(String, Option<Vec<i32>>)>,
+    pub session_id: Option<String>,
+    pub on_chat_update: Callback<(String, Option<String>)>,
 }
 
 impl ChatParams {
     pub fn new(
         prompt: String,
-        context: Option<Vec<i32>>,
-        on_chat_update: Callback<(String, Option<Vec<i32>>)>,
+        session_id: Option<String>,
+        on_chat_update: Callback<(String, Option<String>)>,
     ) -> Self {
         Self {
             prompt,
-            context,
+            session_id,
             on_chat_update,
         }
     }
     pub fn send_chat(&self) {
         let prompt = self.prompt.clone();
-        let context = self.context.clone();
+        let session_id = self.session_id.clone();
         let on_chat_update = self.on_chat_update.clone();
 
         spawn_local(async move {
-            let mut response_stream = searchllama_types::Searchllama::default()
-                .chat(&prompt, context)
-                .await;
+            let response_stream = match searchllama_types::Searchllama::default()
+                .chat(&prompt, session_id.as_deref())
+                .await
+            {
+                Ok(response_stream) => response_stream,
+                Err(e) => {
+                    error!("Chat failed: {}", e);
+                    return;
+                }
+            };
+            let mut response_stream = Box::pin(response_stream);
 
             while let Some(response) = response_stream.next().await {
                 match response {
-                    Ok(responses) => {
-                        for chunk in responses {
-                            on_chat_update.emit((chunk.response, chunk.context));
+                    Ok(chunk) => {
+                        if let Some(e) = &chunk.error {
+                            error!("Chat failed: {}", e.message);
                         }
+                        on_chat_update.emit((chunk.response, chunk.session_id));
                     }
                     Err(e) => error!("Error: {}", e),
                 }
@@ -102,3 +161,30 @@ impl ChatParams {
         });
     }
 }
+
+/// Loads a page of the server's index for "load more".
+pub struct ResultsParams {
+    pub request: ResultsRequest,
+    pub on_page: Callback<ResultsPage>,
+}
+
+impl ResultsParams {
+    pub fn new(request: ResultsRequest, on_page: Callback<ResultsPage>) -> Self {
+        Self { request, on_page }
+    }
+
+    pub fn load(&self) {
+        let request = self.request.clone();
+        let on_page = self.on_page.clone();
+
+        spawn_local(async move {
+            match searchllama_types::Searchllama::default()
+                .results(&request)
+                .await
+            {
+                Ok(page) => on_page.emit(page),
+                Err(e) => error!("Failed to load results: {}", e),
+            }
+        });
+    }
+}
@@ -0,0 +1,46 @@
+-- The schema as it was before migrations were versioned. Every statement is
+-- guarded, as databases from before then already have some of the tables.
+
+CREATE TABLE IF NOT EXISTS indices (
+    url TEXT NOT NULL UNIQUE,
+    title TEXT,
+    title_embedding BLOB,
+    body_embedding_count INTEGER,
+    body_embeddings BLOB,
+    summary TEXT,
+    updated_at INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    query TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS session_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS session_snippets (
+    session_id TEXT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    idx INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    title TEXT NOT NULL,
+    text TEXT NOT NULL,
+    PRIMARY KEY (session_id, idx)
+);
+
+-- Searches as recorded in the history. `request` is the search with its
+-- options and `results` the final ranking, both as JSON.
+CREATE TABLE IF NOT EXISTS searches (
+    id TEXT PRIMARY KEY,
+    query TEXT NOT NULL,
+    request TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    session_id TEXT REFERENCES sessions(id) ON DELETE SET NULL,
+    results TEXT NOT NULL,
+    answer TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS searches_created_at ON searches (created_at);
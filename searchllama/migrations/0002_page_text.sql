@@ -0,0 +1,11 @@
+-- Page text for lexical search. Rows share their rowid with the page in
+-- `indices`. The tokenizer keeps diacritics so that lexical::tokenize
+-- splits text the same way.
+CREATE VIRTUAL TABLE pages_fts USING fts5(
+    title,
+    text,
+    tokenize = 'unicode61 remove_diacritics 0'
+);
+
+-- Number of pages containing each term, for BM25 outside of SQLite
+CREATE VIRTUAL TABLE pages_fts_vocab USING fts5vocab(pages_fts, 'row');
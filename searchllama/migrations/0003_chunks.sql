@@ -0,0 +1,13 @@
+-- The chunks of page text that body embeddings were computed from, so that
+-- snippets of indexed pages come from the database instead of the page.
+-- `page_id` is the rowid of the page in `indices`; offsets count characters
+-- of the page text, the end exclusive.
+CREATE TABLE chunks (
+    page_id INTEGER NOT NULL,
+    ordinal INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    start_char INTEGER NOT NULL,
+    end_char INTEGER NOT NULL,
+    PRIMARY KEY (page_id, ordinal)
+);
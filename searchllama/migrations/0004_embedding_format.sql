@@ -0,0 +1,12 @@
+-- Which model produced the embeddings of a row, their dimension and how
+-- they are encoded. Rows from before have a NULL model, which matches any
+-- model of the same dimension, and the f64 format they were written in.
+ALTER TABLE indices ADD COLUMN embedding_model TEXT;
+ALTER TABLE indices ADD COLUMN embedding_dimension INTEGER;
+ALTER TABLE indices ADD COLUMN embedding_format TEXT NOT NULL DEFAULT 'f64';
+UPDATE indices SET embedding_dimension = length(title_embedding) / 8;
+
+ALTER TABLE chunks ADD COLUMN embedding_model TEXT;
+ALTER TABLE chunks ADD COLUMN embedding_dimension INTEGER;
+ALTER TABLE chunks ADD COLUMN embedding_format TEXT NOT NULL DEFAULT 'f64';
+UPDATE chunks SET embedding_dimension = length(embedding) / 8;
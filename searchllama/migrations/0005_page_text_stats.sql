@@ -0,0 +1,14 @@
+-- Totals of `pages_fts` for BM25, kept up to date by update_entries so that
+-- queries do not count the whole table. `tokens` counts the terms of the
+-- titles and texts of all pages together.
+CREATE TABLE page_text_stats (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    pages INTEGER NOT NULL,
+    tokens INTEGER NOT NULL
+);
+
+INSERT INTO page_text_stats (id, pages, tokens) VALUES (
+    0,
+    (SELECT COUNT(*) FROM pages_fts),
+    (SELECT COALESCE(SUM(cnt), 0) FROM pages_fts_vocab)
+);
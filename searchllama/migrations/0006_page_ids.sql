@@ -0,0 +1,23 @@
+-- Gives pages an explicit id. `pages_fts` rows and `chunks.page_id` refer
+-- to pages by it, and the implicit rowid they used before may be renumbered
+-- by VACUUM. Pages keep their rowid as their id.
+CREATE TABLE indices_new (
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE,
+    title TEXT,
+    title_embedding BLOB,
+    body_embedding_count INTEGER,
+    body_embeddings BLOB,
+    summary TEXT,
+    updated_at INTEGER,
+    embedding_model TEXT,
+    embedding_dimension INTEGER,
+    embedding_format TEXT NOT NULL DEFAULT 'f64'
+);
+
+INSERT INTO indices_new (id, url, title, title_embedding, body_embedding_count, body_embeddings, summary, updated_at, embedding_model, embedding_dimension, embedding_format)
+SELECT rowid, url, title, title_embedding, body_embedding_count, body_embeddings, summary, updated_at, embedding_model, embedding_dimension, embedding_format
+FROM indices;
+
+DROP TABLE indices;
+ALTER TABLE indices_new RENAME TO indices;
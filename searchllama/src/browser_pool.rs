@@ -0,0 +1,282 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use playwright::{
+    api::{Browser, BrowserContext},
+    Playwright,
+};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    config::PlaywrightConfig,
+    error::{Error, Result},
+};
+
+/// Time a health probe or a shutdown may take before the browser counts as
+/// unresponsive.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A process-wide pool of headless Chromium instances.
+///
+/// Browsers are launched on first use and shared by all requests. Pages are
+/// spread round-robin over the browsers and their contexts, and at most
+/// `max_pages` are open at once. A background task probes every running
+/// browser and restarts the ones that crashed or stopped responding.
+pub struct BrowserPool {
+    config: PlaywrightConfig,
+    slots: Vec<Mutex<Option<PooledBrowser>>>,
+    pages: Arc<Semaphore>,
+    next: RoundRobin,
+}
+
+/// Hands out the indices below a length in turn.
+#[derive(Default)]
+struct RoundRobin(AtomicUsize);
+
+impl RoundRobin {
+    fn next(&self, len: usize) -> usize {
+        self.0.fetch_add(1, Ordering::Relaxed) % len
+    }
+}
+
+/// A browser slot, as seen before using it or by a health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Empty,
+    Running,
+    /// The browser crashed or stopped responding.
+    Dead,
+}
+
+impl SlotState {
+    fn of(browser: Option<&PooledBrowser>, alive: bool) -> Self {
+        match browser {
+            None => Self::Empty,
+            Some(_) if alive => Self::Running,
+            Some(_) => Self::Dead,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotAction {
+    Keep,
+    Launch,
+    Restart,
+}
+
+/// What to do with a slot in `state`. Browsers are only launched into empty
+/// slots when a page needs them, not by health checks.
+fn slot_action(state: SlotState, needed: bool) -> SlotAction {
+    match state {
+        SlotState::Running => SlotAction::Keep,
+        SlotState::Dead => SlotAction::Restart,
+        SlotState::Empty if needed => SlotAction::Launch,
+        SlotState::Empty => SlotAction::Keep,
+    }
+}
+
+struct PooledBrowser {
+    // Keeps the Playwright driver alive while the browser is in use
+    _pw: Playwright,
+    browser: Browser,
+    contexts: Vec<Arc<BrowserContext>>,
+    next_context: RoundRobin,
+}
+
+impl PooledBrowser {
+    async fn launch(contexts: usize) -> Result<Self> {
+        let pw = Playwright::initialize()
+            .await
+            .map_err(|e| Error::Fetch(format!("Failed to initialize Playwright: {}", e)))?;
+        let browser = pw
+            .chromium()
+            .launcher()
+            .headless(true)
+            .launch()
+            .await
+            .map_err(|e| Error::Fetch(format!("Failed to launch browser: {}", e)))?;
+        let mut browser_contexts = Vec::with_capacity(contexts);
+        for _ in 0..contexts {
+            browser_contexts.push(Arc::new(
+                browser
+                    .context_builder()
+                    .build()
+                    .await
+                    .map_err(|e| Error::Fetch(format!("Failed to create context: {}", e)))?,
+            ));
+        }
+
+        Ok(Self {
+            _pw: pw,
+            browser,
+            contexts: browser_contexts,
+            next_context: RoundRobin::default(),
+        })
+    }
+
+    /// Opens and closes a page to check that the browser still responds.
+    async fn probe(&self) -> bool {
+        if !self.browser.exists() {
+            return false;
+        }
+        match tokio::time::timeout(PROBE_TIMEOUT, self.contexts[0].new_page()).await {
+            Ok(Ok(page)) => {
+                let _ = page.close(None).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn close(self) {
+        let close = async {
+            for context in &self.contexts {
+                let _ = context.close().await;
+            }
+            self.browser.close().await
+        };
+        match tokio::time::timeout(PROBE_TIMEOUT, close).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to close browser: {}", e),
+            Err(_) => warn!("Timed out closing browser"),
+        }
+    }
+}
+
+/// Permission to open one page in `context`. The page slot is released when
+/// the lease is dropped.
+pub struct PageLease {
+    pub context: Arc<BrowserContext>,
+    slot: usize,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl BrowserPool {
+    pub fn new(config: &PlaywrightConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config: config.clone(),
+            slots: (0..config.browsers).map(|_| Mutex::new(None)).collect(),
+            pages: Arc::new(Semaphore::new(config.max_pages)),
+            next: RoundRobin::default(),
+        })
+    }
+
+    pub fn navigation_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.navigation_timeout_secs)
+    }
+
+    /// Waits for a free page slot and picks the context to open the page in,
+    /// launching its browser if it is not running.
+    pub async fn acquire(&self) -> Result<PageLease> {
+        let permit = self
+            .pages
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| Error::Fetch(format!("Browser pool is closed: {}", e)))?;
+        let slot = self.next.next(self.slots.len());
+
+        let mut browser = self.slots[slot].lock().await;
+        let alive = browser.as_ref().is_some_and(|b| b.browser.exists());
+        match slot_action(SlotState::of(browser.as_ref(), alive), true) {
+            SlotAction::Keep => {}
+            SlotAction::Launch => info!("Launching browser {}", slot),
+            SlotAction::Restart => {
+                warn!("Browser {} is gone, restarting it", slot);
+                *browser = None;
+            }
+        }
+        let browser = match &mut *browser {
+            Some(browser) => browser,
+            None => browser.insert(PooledBrowser::launch(self.config.contexts_per_browser).await?),
+        };
+        let context = browser.contexts[browser.next_context.next(browser.contexts.len())].clone();
+
+        Ok(PageLease {
+            context,
+            slot,
+            _permit: permit,
+        })
+    }
+
+    /// Restarts the browser behind `lease` if it stopped responding. Called
+    /// after a page failed, as that may have been caused by a crash.
+    pub async fn check(&self, lease: &PageLease) {
+        self.check_slot(lease.slot).await;
+    }
+
+    async fn check_slot(&self, slot: usize) {
+        let mut browser = self.slots[slot].lock().await;
+        let alive = match browser.as_ref() {
+            Some(running) => running.probe().await,
+            None => false,
+        };
+        if slot_action(SlotState::of(browser.as_ref(), alive), false) != SlotAction::Restart {
+            return;
+        }
+
+        warn!("Browser {} is unresponsive, restarting it", slot);
+        if let Some(unresponsive) = browser.take() {
+            unresponsive.close().await;
+        }
+        match PooledBrowser::launch(self.config.contexts_per_browser).await {
+            Ok(restarted) => *browser = Some(restarted),
+            // Launched again on the next acquire
+            Err(e) => warn!("Failed to restart browser {}: {}", slot, e),
+        }
+    }
+
+    /// Periodically probes every running browser, restarting the ones that
+    /// stopped responding.
+    pub fn spawn_health_checks(self: &Arc<Self>) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(pool.config.health_check_interval_secs));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                for slot in 0..pool.slots.len() {
+                    pool.check_slot(slot).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_rotate_over_browsers_and_their_contexts() {
+        let slots = RoundRobin::default();
+        let contexts = [RoundRobin::default(), RoundRobin::default()];
+        let picks = (0..5)
+            .map(|_| {
+                let slot = slots.next(contexts.len());
+                (slot, contexts[slot].next(2))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(picks, vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn dead_browsers_are_restarted() {
+        for needed in [true, false] {
+            assert_eq!(slot_action(SlotState::Running, needed), SlotAction::Keep);
+            assert_eq!(slot_action(SlotState::Dead, needed), SlotAction::Restart);
+        }
+        // Health checks leave empty slots to the next page
+        assert_eq!(slot_action(SlotState::Empty, true), SlotAction::Launch);
+        assert_eq!(slot_action(SlotState::Empty, false), SlotAction::Keep);
+        assert_eq!(SlotState::of(None, true), SlotState::Empty);
+    }
+}
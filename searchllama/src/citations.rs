@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+
+/// Longest text kept between `[` and `]` while waiting for a marker to close.
+const MAX_MARKER_LEN: usize = 16;
+
+/// Renders a source for a prompt, numbered so the model can cite it as `[n]`.
+pub fn format_source(index: usize, title: &str, url: &str, text: &str) -> String {
+    format!("[{}] \"{}\" ({}):\n\"{}\"", index, title, url, text)
+}
+
+/// Finds `[n]` citation markers in an answer streamed token by token.
+///
+/// A marker can be split over several tokens, so text from an unclosed `[`
+/// is kept until the next token. Markers may name several sources, as in
+/// `[1, 3]` or `[2][4]`.
+#[derive(Debug, Default)]
+pub struct CitationParser {
+    pending: String,
+    cited: HashSet<usize>,
+    sources: usize,
+}
+
+impl CitationParser {
+    /// `sources` is the number of snippets in the prompt, numbered from 1.
+    pub fn new(sources: usize) -> Self {
+        Self {
+            sources,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds the next piece of the answer and returns the sources it cites
+    /// for the first time.
+    pub fn push(&mut self, text: &str) -> Vec<usize> {
+        self.pending.push_str(text);
+
+        let mut cited = Vec::new();
+        loop {
+            let Some(open) = self.pending.find('[') else {
+                self.pending.clear();
+                break;
+            };
+            self.pending.drain(..open);
+
+            let Some(close) = self.pending.find(']') else {
+                let inner = &self.pending[1..];
+                if inner.len() > MAX_MARKER_LEN || !is_marker_text(inner) {
+                    self.pending.drain(..1);
+                    continue;
+                }
+                break;
+            };
+            // Only the last `[` before the `]` can open a marker, as in `[a [1]`
+            if let Some(reopen) = self.pending[1..close].rfind('[') {
+                self.pending.drain(..=reopen);
+                continue;
+            }
+
+            let inner = &self.pending[1..close];
+            if !inner.trim().is_empty() && is_marker_text(inner) {
+                for index in inner.split(',').filter_map(|n| n.trim().parse().ok()) {
+                    if (1..=self.sources).contains(&index) && self.cited.insert(index) {
+                        cited.push(index);
+                    }
+                }
+            }
+            self.pending.drain(..=close);
+        }
+
+        cited
+    }
+}
+
+fn is_marker_text(text: &str) -> bool {
+    text.chars()
+        .all(|c| c.is_ascii_digit() || c == ',' || c == ' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_markers_in_one_token() {
+        let mut parser = CitationParser::new(3);
+        assert_eq!(
+            parser.push("Rust is safe [2] and fast [1, 3]."),
+            vec![2, 1, 3]
+        );
+    }
+
+    #[test]
+    fn finds_markers_split_over_tokens() {
+        let mut parser = CitationParser::new(12);
+        let tokens = ["It is", " [", "1", "2", "]", "[3", "].", " Done [", "4]"];
+        let cited = tokens
+            .iter()
+            .flat_map(|t| parser.push(t))
+            .collect::<Vec<usize>>();
+        assert_eq!(cited, vec![12, 3, 4]);
+    }
+
+    #[test]
+    fn reports_each_source_once() {
+        let mut parser = CitationParser::new(2);
+        assert_eq!(parser.push("a [1] b [1][2]"), vec![1, 2]);
+        assert!(parser.push(" c [2]").is_empty());
+    }
+
+    #[test]
+    fn ignores_unknown_sources_and_other_brackets() {
+        let mut parser = CitationParser::new(2);
+        assert!(parser
+            .push("[0] [7] [link](http://x) [] [a [long text] ")
+            .is_empty());
+        assert_eq!(parser.push("[1]"), vec![1]);
+    }
+
+    #[test]
+    fn finds_markers_after_a_literal_bracket() {
+        let mut parser = CitationParser::new(3);
+        assert_eq!(parser.push("see [a [1]"), vec![1]);
+        assert_eq!(parser.push(" and [b, "), Vec::<usize>::new());
+        assert_eq!(parser.push("[2] [3]"), vec![2, 3]);
+    }
+}
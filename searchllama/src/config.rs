@@ -0,0 +1,719 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, ValueEnum};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::providers::SafeSearch;
+
+const DEFAULT_CONFIG_PATH: &str = "searchllama.toml";
+
+/// Runtime configuration.
+///
+/// Values are layered: built-in defaults, then the TOML config file, then
+/// `SEARCHLLAMA_*` environment variables, then command-line flags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub llm: LlmConfig,
+    pub embedding: EmbeddingConfig,
+    pub ollama: OllamaConfig,
+    pub openai: OpenAiConfig,
+    pub mock: MockConfig,
+    pub web_search: WebSearchConfig,
+    pub duckduckgo: DuckDuckGoConfig,
+    pub searxng: SearXngConfig,
+    pub search_fixture: SearchFixtureConfig,
+    pub models: ModelConfig,
+    pub search: SearchConfig,
+    pub ranking: RankingConfig,
+    pub fetcher: FetcherConfig,
+    pub playwright: PlaywrightConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3030)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// SQLite file, or `:memory:` for a database that is gone on exit.
+    pub path: PathBuf,
+    /// Connections in the shared pool.
+    pub max_connections: u32,
+    /// How long a write waits for another one to finish before failing.
+    pub busy_timeout_secs: u64,
+    /// How embeddings of newly indexed pages are stored.
+    pub embedding_format: EmbeddingFormat,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("data.db"),
+            max_connections: 8,
+            busy_timeout_secs: 5,
+            embedding_format: EmbeddingFormat::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingFormat {
+    /// As computed, 8 bytes per value
+    #[default]
+    F64,
+    /// 4 bytes per value
+    F32,
+    /// Scalar-quantized to 1 byte per value, with rankings rescored from the
+    /// chunk embeddings, which are kept as `f32`
+    Int8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmBackend {
+    #[default]
+    Ollama,
+    /// Any server implementing the OpenAI chat completions API
+    #[serde(rename = "openai")]
+    #[value(name = "openai")]
+    OpenAi,
+    /// Replays the responses from `[mock]`
+    Mock,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    pub backend: LlmBackend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingBackend {
+    #[default]
+    Ollama,
+    /// Any server implementing the OpenAI embeddings API
+    #[serde(rename = "openai")]
+    #[value(name = "openai")]
+    OpenAi,
+    /// Deterministic feature hashing, for tests and offline runs
+    Hash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    pub backend: EmbeddingBackend,
+    /// Vector size of the hash embedder. Other backends report their own.
+    pub dimension: usize,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            backend: EmbeddingBackend::default(),
+            dimension: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OllamaConfig {
+    pub url: String,
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://127.0.0.1:11434".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiConfig {
+    /// Base URL including the version prefix, e.g. `http://127.0.0.1:8080/v1`.
+    pub url: String,
+    pub api_key: Option<String>,
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://127.0.0.1:8080/v1".to_string(),
+            api_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MockConfig {
+    pub responses: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchBackend {
+    /// Scrapes html.duckduckgo.com
+    #[default]
+    #[serde(rename = "duckduckgo")]
+    #[value(name = "duckduckgo")]
+    DuckDuckGo,
+    /// A SearXNG instance with the JSON format enabled
+    #[serde(rename = "searxng")]
+    #[value(name = "searxng")]
+    SearXng,
+    /// Canned results from `[search_fixture]`
+    Fixture,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSearchConfig {
+    pub backend: SearchBackend,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DuckDuckGoConfig {
+    /// Region code such as `us-en`, or `wt-wt` for no region
+    pub region: String,
+    pub safe_search: SafeSearch,
+}
+
+impl Default for DuckDuckGoConfig {
+    fn default() -> Self {
+        Self {
+            region: "wt-wt".to_string(),
+            safe_search: SafeSearch::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearXngConfig {
+    pub url: String,
+}
+
+impl Default for SearXngConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://127.0.0.1:8888".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchFixtureConfig {
+    /// JSON file mapping queries to result lists
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub embedding: String,
+    pub search: String,
+    pub judgement: String,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            embedding: "nomic-embed-text:latest".to_string(),
+            search: "gemma2:2b".to_string(),
+            judgement: "gemma2:2b".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    /// Maximum number of database entries sent in the first response.
+    pub max_entries: usize,
+    /// Web results fetched for the query when the request does not set
+    /// `max_results`.
+    pub max_results: usize,
+    /// Web results fetched for each related query.
+    pub related_max_results: usize,
+    /// Size in characters of the chunks a page is split into before embedding.
+    pub max_embedding_size: usize,
+    pub snippet_target_size: usize,
+    pub snippet_number: usize,
+    /// Mean snippet score above which an answer is generated.
+    pub min_confidence: f64,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 50,
+            max_results: 10,
+            related_max_results: 3,
+            max_embedding_size: 1024,
+            snippet_target_size: 512,
+            snippet_number: 10,
+            min_confidence: 0.72,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Fusion {
+    /// Reciprocal rank fusion of the similarity and BM25 ranks
+    Rrf,
+    /// Similarity plus BM25 scaled to the best match, weighted by
+    /// `lexical_weight`
+    #[default]
+    Weighted,
+}
+
+/// How the embedding similarity and the BM25 score of a result are blended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RankingConfig {
+    pub fusion: Fusion,
+    /// Share of the BM25 score in the weighted sum; 0 ranks by similarity only.
+    pub lexical_weight: f64,
+    /// Rank offset of reciprocal rank fusion; larger values flatten the
+    /// difference between the top ranks.
+    pub rrf_k: f64,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            fusion: Fusion::default(),
+            lexical_weight: 0.3,
+            rrf_k: 60.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    /// Plain HTTP first, Playwright for pages with too little static content
+    #[default]
+    Auto,
+    /// Plain HTTP only; Playwright is never started
+    Http,
+    /// Always render pages in Playwright
+    Playwright,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetcherConfig {
+    pub mode: FetchMode,
+    /// Timeout of a plain HTTP fetch in seconds.
+    pub timeout_secs: u64,
+    /// In `auto` mode, pages with less extracted text than this are rendered
+    /// in Playwright instead.
+    pub min_text_length: usize,
+    /// Domains (and their subdomains) always rendered in Playwright in `auto`
+    /// mode.
+    pub playwright_domains: Vec<String>,
+}
+
+impl Default for FetcherConfig {
+    fn default() -> Self {
+        Self {
+            mode: FetchMode::default(),
+            timeout_secs: 10,
+            min_text_length: 500,
+            playwright_domains: Vec::new(),
+        }
+    }
+}
+
+/// The shared pool of headless Chromium instances used by the fetcher.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaywrightConfig {
+    /// Number of browsers, each with its own Playwright driver.
+    pub browsers: usize,
+    pub contexts_per_browser: usize,
+    /// Pages open at the same time across all browsers.
+    pub max_pages: usize,
+    /// Page load timeout in seconds.
+    pub navigation_timeout_secs: u64,
+    /// Interval between checks that restart unresponsive browsers.
+    pub health_check_interval_secs: u64,
+}
+
+impl Default for PlaywrightConfig {
+    fn default() -> Self {
+        Self {
+            browsers: 1,
+            contexts_per_browser: 2,
+            max_pages: 8,
+            navigation_timeout_secs: 15,
+            health_check_interval_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the TOML config file
+    #[arg(long, env = "SEARCHLLAMA_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address the HTTP server binds to
+    #[arg(long, env = "SEARCHLLAMA_BIND")]
+    bind: Option<SocketAddr>,
+    /// Path to the SQLite database, or :memory:
+    #[arg(long, env = "SEARCHLLAMA_DATABASE")]
+    database: Option<PathBuf>,
+    /// How embeddings of newly indexed pages are stored
+    #[arg(long, env = "SEARCHLLAMA_EMBEDDING_FORMAT")]
+    embedding_format: Option<EmbeddingFormat>,
+    /// Backend used for text generation
+    #[arg(long, env = "SEARCHLLAMA_LLM_BACKEND")]
+    llm_backend: Option<LlmBackend>,
+    /// Backend used for text embeddings
+    #[arg(long, env = "SEARCHLLAMA_EMBEDDING_BACKEND")]
+    embedding_backend: Option<EmbeddingBackend>,
+    /// Base URL of the Ollama server
+    #[arg(long, env = "SEARCHLLAMA_OLLAMA_URL")]
+    ollama_url: Option<String>,
+    /// Base URL of the OpenAI-compatible server
+    #[arg(long, env = "SEARCHLLAMA_OPENAI_URL")]
+    openai_url: Option<String>,
+    #[arg(long, env = "SEARCHLLAMA_OPENAI_API_KEY", hide_env_values = true)]
+    openai_api_key: Option<String>,
+    /// Web search engine used to find pages
+    #[arg(long, env = "SEARCHLLAMA_WEB_SEARCH_BACKEND")]
+    web_search_backend: Option<SearchBackend>,
+    /// DuckDuckGo region code, e.g. us-en
+    #[arg(long, env = "SEARCHLLAMA_DUCKDUCKGO_REGION")]
+    duckduckgo_region: Option<String>,
+    /// DuckDuckGo safe search level: strict, moderate or off
+    #[arg(long, env = "SEARCHLLAMA_DUCKDUCKGO_SAFE_SEARCH")]
+    duckduckgo_safe_search: Option<SafeSearch>,
+    /// Base URL of the SearXNG instance
+    #[arg(long, env = "SEARCHLLAMA_SEARXNG_URL")]
+    searxng_url: Option<String>,
+    /// JSON file with canned search results
+    #[arg(long, env = "SEARCHLLAMA_SEARCH_FIXTURE")]
+    search_fixture: Option<PathBuf>,
+    #[arg(long, env = "SEARCHLLAMA_EMBEDDING_MODEL")]
+    embedding_model: Option<String>,
+    #[arg(long, env = "SEARCHLLAMA_SEARCH_MODEL")]
+    search_model: Option<String>,
+    #[arg(long, env = "SEARCHLLAMA_JUDGEMENT_MODEL")]
+    judgement_model: Option<String>,
+    #[arg(long, env = "SEARCHLLAMA_MAX_ENTRIES")]
+    max_entries: Option<usize>,
+    /// Default number of web results for a query
+    #[arg(long, env = "SEARCHLLAMA_MAX_RESULTS")]
+    max_results: Option<usize>,
+    /// Number of web results for each related query
+    #[arg(long, env = "SEARCHLLAMA_RELATED_MAX_RESULTS")]
+    related_max_results: Option<usize>,
+    #[arg(long, env = "SEARCHLLAMA_MAX_EMBEDDING_SIZE")]
+    max_embedding_size: Option<usize>,
+    #[arg(long, env = "SEARCHLLAMA_SNIPPET_TARGET_SIZE")]
+    snippet_target_size: Option<usize>,
+    #[arg(long, env = "SEARCHLLAMA_SNIPPET_NUMBER")]
+    snippet_number: Option<usize>,
+    #[arg(long, env = "SEARCHLLAMA_MIN_CONFIDENCE")]
+    min_confidence: Option<f64>,
+    /// How similarity and BM25 scores are blended
+    #[arg(long, env = "SEARCHLLAMA_FUSION")]
+    fusion: Option<Fusion>,
+    /// Share of the BM25 score in the weighted blend
+    #[arg(long, env = "SEARCHLLAMA_LEXICAL_WEIGHT")]
+    lexical_weight: Option<f64>,
+    /// How pages are fetched
+    #[arg(long, env = "SEARCHLLAMA_FETCH_MODE")]
+    fetch_mode: Option<FetchMode>,
+    /// Number of headless browsers in the shared pool
+    #[arg(long, env = "SEARCHLLAMA_PLAYWRIGHT_BROWSERS")]
+    playwright_browsers: Option<usize>,
+    /// Pages rendered at the same time across all browsers
+    #[arg(long, env = "SEARCHLLAMA_PLAYWRIGHT_MAX_PAGES")]
+    playwright_max_pages: Option<usize>,
+}
+
+impl Config {
+    /// Loads the configuration from the config file, the environment and the
+    /// command line, and validates the result.
+    pub fn load() -> Result<Self, String> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        info!("Loading config from {}", path.display());
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    fn apply_args(&mut self, args: Args) {
+        fn set<T>(target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+
+        set(&mut self.server.bind, args.bind);
+        set(&mut self.database.path, args.database);
+        set(&mut self.database.embedding_format, args.embedding_format);
+        set(&mut self.llm.backend, args.llm_backend);
+        set(&mut self.embedding.backend, args.embedding_backend);
+        set(&mut self.ollama.url, args.ollama_url);
+        set(&mut self.openai.url, args.openai_url);
+        if args.openai_api_key.is_some() {
+            self.openai.api_key = args.openai_api_key;
+        }
+        set(&mut self.web_search.backend, args.web_search_backend);
+        set(&mut self.duckduckgo.region, args.duckduckgo_region);
+        set(
+            &mut self.duckduckgo.safe_search,
+            args.duckduckgo_safe_search,
+        );
+        set(&mut self.searxng.url, args.searxng_url);
+        set(&mut self.search_fixture.path, args.search_fixture);
+        set(&mut self.models.embedding, args.embedding_model);
+        set(&mut self.models.search, args.search_model);
+        set(&mut self.models.judgement, args.judgement_model);
+        set(&mut self.search.max_entries, args.max_entries);
+        set(&mut self.search.max_results, args.max_results);
+        set(
+            &mut self.search.related_max_results,
+            args.related_max_results,
+        );
+        set(&mut self.search.max_embedding_size, args.max_embedding_size);
+        set(
+            &mut self.search.snippet_target_size,
+            args.snippet_target_size,
+        );
+        set(&mut self.search.snippet_number, args.snippet_number);
+        set(&mut self.search.min_confidence, args.min_confidence);
+        set(&mut self.ranking.fusion, args.fusion);
+        set(&mut self.ranking.lexical_weight, args.lexical_weight);
+        set(&mut self.fetcher.mode, args.fetch_mode);
+        set(&mut self.playwright.browsers, args.playwright_browsers);
+        set(&mut self.playwright.max_pages, args.playwright_max_pages);
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if reqwest::Url::parse(&self.ollama.url).is_err() {
+            return Err(format!(
+                "ollama.url is not a valid URL: {}",
+                self.ollama.url
+            ));
+        }
+        let uses_openai = self.llm.backend == LlmBackend::OpenAi
+            || self.embedding.backend == EmbeddingBackend::OpenAi;
+        if uses_openai && reqwest::Url::parse(&self.openai.url).is_err() {
+            return Err(format!(
+                "openai.url is not a valid URL: {}",
+                self.openai.url
+            ));
+        }
+        if self.duckduckgo.region.trim().is_empty() {
+            return Err("duckduckgo.region must not be empty, use wt-wt for no region".to_string());
+        }
+        if self.web_search.backend == SearchBackend::SearXng
+            && reqwest::Url::parse(&self.searxng.url).is_err()
+        {
+            return Err(format!(
+                "searxng.url is not a valid URL: {}",
+                self.searxng.url
+            ));
+        }
+        if self.web_search.backend == SearchBackend::Fixture && !self.search_fixture.path.is_file()
+        {
+            return Err(format!(
+                "search_fixture.path does not exist: {}",
+                self.search_fixture.path.display()
+            ));
+        }
+        if self.llm.backend == LlmBackend::Mock && self.mock.responses.is_empty() {
+            return Err("mock.responses must not be empty when llm.backend is mock".to_string());
+        }
+        for (name, model) in [
+            ("models.embedding", &self.models.embedding),
+            ("models.search", &self.models.search),
+            ("models.judgement", &self.models.judgement),
+        ] {
+            if model.trim().is_empty() {
+                return Err(format!("{} must not be empty", name));
+            }
+        }
+        if self.embedding.backend == EmbeddingBackend::Hash && self.embedding.dimension == 0 {
+            return Err("embedding.dimension must be greater than 0".to_string());
+        }
+        if self.database.path.as_os_str().is_empty() {
+            return Err("database.path must not be empty".to_string());
+        }
+        if self.database.max_connections == 0 {
+            return Err("database.max_connections must be greater than 0".to_string());
+        }
+
+        let search = &self.search;
+        if search.max_entries == 0 {
+            return Err("search.max_entries must be greater than 0".to_string());
+        }
+        if search.max_results == 0 {
+            return Err("search.max_results must be greater than 0".to_string());
+        }
+        if search.snippet_number == 0 {
+            return Err("search.snippet_number must be greater than 0".to_string());
+        }
+        if search.snippet_target_size == 0 || search.max_embedding_size == 0 {
+            return Err("search chunk sizes must be greater than 0".to_string());
+        }
+        if search.snippet_target_size > search.max_embedding_size {
+            return Err(format!(
+                "search.snippet_target_size ({}) must not exceed search.max_embedding_size ({})",
+                search.snippet_target_size, search.max_embedding_size
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.ranking.lexical_weight) {
+            return Err(format!(
+                "ranking.lexical_weight must be between 0 and 1, got {}",
+                self.ranking.lexical_weight
+            ));
+        }
+        if self.ranking.rrf_k.is_nan() || self.ranking.rrf_k < 0.0 {
+            return Err("ranking.rrf_k must not be negative".to_string());
+        }
+        if self.fetcher.timeout_secs == 0 {
+            return Err("fetcher.timeout_secs must be greater than 0".to_string());
+        }
+        let playwright = &self.playwright;
+        for (name, value) in [
+            ("playwright.browsers", playwright.browsers as u64),
+            (
+                "playwright.contexts_per_browser",
+                playwright.contexts_per_browser as u64,
+            ),
+            ("playwright.max_pages", playwright.max_pages as u64),
+            (
+                "playwright.navigation_timeout_secs",
+                playwright.navigation_timeout_secs,
+            ),
+            (
+                "playwright.health_check_interval_secs",
+                playwright.health_check_interval_secs,
+            ),
+        ] {
+            if value == 0 {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        if !(-1.0..=1.0).contains(&search.min_confidence) {
+            return Err(format!(
+                "search.min_confidence must be between -1 and 1, got {}",
+                search.min_confidence
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_environment_and_the_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [models]
+            search = "toml-search"
+            judgement = "toml-judgement"
+
+            [search]
+            max_entries = 5
+            "#,
+        )
+        .unwrap();
+        // Only read by this test, as the environment is shared by all of them
+        std::env::set_var("SEARCHLLAMA_SEARCH_MODEL", "env-search");
+        std::env::set_var("SEARCHLLAMA_JUDGEMENT_MODEL", "env-judgement");
+        let args = Args::try_parse_from([
+            "searchllama",
+            "--judgement-model",
+            "cli-judgement",
+            "--fusion",
+            "rrf",
+        ])
+        .unwrap();
+        config.apply_args(args);
+
+        assert_eq!(config.search.max_entries, 5);
+        assert_eq!(config.models.search, "env-search");
+        assert_eq!(config.models.judgement, "cli-judgement");
+        assert_eq!(config.ranking.fusion, Fusion::Rrf);
+        assert_eq!(config.models.embedding, ModelConfig::default().embedding);
+    }
+
+    #[test]
+    fn validate_rejects_inconsistent_settings() {
+        assert_eq!(Config::default().validate(), Ok(()));
+        assert!(toml::from_str::<Config>("[search]\nmax_entrys = 5").is_err());
+
+        let invalid = |change: fn(&mut Config)| {
+            let mut config = Config::default();
+            change(&mut config);
+            config.validate().unwrap_err()
+        };
+        assert!(
+            invalid(|c| c.search.snippet_target_size = c.search.max_embedding_size + 1)
+                .contains("must not exceed")
+        );
+        assert!(invalid(|c| c.ranking.lexical_weight = 1.5).contains("lexical_weight"));
+        assert!(invalid(|c| c.search.min_confidence = f64::NAN).contains("min_confidence"));
+        assert!(invalid(|c| c.duckduckgo.region = " ".to_string()).contains("wt-wt"));
+        assert!(invalid(|c| c.llm.backend = LlmBackend::Mock).contains("mock.responses"));
+        assert!(invalid(|c| {
+            c.web_search.backend = SearchBackend::SearXng;
+            c.searxng.url = "not a url".to_string();
+        })
+        .contains("searxng.url"));
+    }
+}
@@ -1,132 +1,1474 @@
-use std::sync::Arc;
-
-use bytemuck::{cast_slice, try_cast_slice};
 use futures::TryStreamExt;
-use log::info;
-use playwright::api::BrowserContext;
-use pollster::FutureExt;
+use log::{info, warn};
+use searchllama_types::types::{Entry, HistoryEntry};
+use sqlx::sqlite::SqliteRow;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
 use sqlx::Row;
-use sqlx::{migrate::MigrateDatabase, Executor, Sqlite, SqlitePool};
+use sqlx::{Executor, SqlitePool};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::config::{DatabaseConfig, EmbeddingFormat, RankingConfig};
+use crate::error::{Error, Result};
+use crate::lexical::{self, Bm25};
+use crate::llm::{Message, Role};
+use crate::ranking::{self, Scores};
+use crate::search::{self, ResultFilter};
+use crate::vector_codec::VectorEncoding;
+use crate::vector_index::{Stamp, VectorIndex};
 
-use crate::embedding::{self, vec_cos_sim};
-use crate::search;
+/// Schema migrations, in order. A database at schema version `n` has the
+/// first `n` applied; new ones go at the end and are never edited once
+/// released.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_initial.sql"),
+    include_str!("../migrations/0002_page_text.sql"),
+    include_str!("../migrations/0003_chunks.sql"),
+    include_str!("../migrations/0004_embedding_format.sql"),
+    include_str!("../migrations/0005_page_text_stats.sql"),
+    include_str!("../migrations/0006_page_ids.sql"),
+];
 
-struct DBWrapper {
-    pool: sqlx::Pool<Sqlite>,
+/// `database.path` value for a database that lives in memory only.
+pub const IN_MEMORY: &str = ":memory:";
+
+/// Opens the database shared by all requests and brings its schema up to
+/// date, creating it if needed.
+pub async fn connect(config: &DatabaseConfig) -> Result<SqlitePool> {
+    let db = open(config).await?;
+    migrate(&db).await?;
+    Ok(db)
 }
-impl Drop for DBWrapper {
-    fn drop(&mut self) {
-        self.pool.close().block_on();
+
+async fn open(config: &DatabaseConfig) -> Result<SqlitePool> {
+    let busy_timeout = Duration::from_secs(config.busy_timeout_secs);
+    if config.path == Path::new(IN_MEMORY) {
+        // Every connection would get an empty database of its own, so the
+        // pool keeps exactly one open
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?.busy_timeout(busy_timeout);
+        return Ok(SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?);
+    }
+
+    if !tokio::fs::try_exists(&config.path)
+        .await
+        .map_err(sqlx::Error::Io)?
+    {
+        info!("Creating database {}", config.path.display());
     }
+    // WAL lets the crawl write while searches read
+    let options = SqliteConnectOptions::new()
+        .filename(&config.path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(busy_timeout);
+    Ok(SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .connect_with(options)
+        .await?)
 }
 
-async fn get_db_pool() -> DBWrapper {
-    if !tokio::fs::try_exists("data.db").await.unwrap() {
-        info!("Creating database...");
-        Sqlite::create_database("sqlite://data.db").await.unwrap();
+/// The schema version of the database: the number of migrations applied.
+async fn schema_version(db: &SqlitePool) -> Result<usize> {
+    db.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+        .await?;
+    let version: Option<i64> = sqlx::query_scalar("SELECT version FROM schema_version")
+        .fetch_optional(db)
+        .await?;
+    Ok(version.unwrap_or(0) as usize)
+}
 
-        let pool = SqlitePool::connect("sqlite://data.db").await.unwrap();
+/// Applies the migrations the database does not have yet.
+///
+/// Fails without touching the database if it has a newer schema than this
+/// build knows, as written by a later version of searchllama.
+async fn migrate(db: &SqlitePool) -> Result<()> {
+    let version = schema_version(db).await?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::Schema(format!(
+            "the database has schema version {}, but this build only knows up to version {}",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
 
-        pool.execute(
-            "CREATE TABLE IF NOT EXISTS indices (
-                url TEXT NOT NULL UNIQUE,
-                title TEXT,
-                title_embedding BLOB,
-                body_embedding_count INTEGER,
-                body_embeddings BLOB,
-                summary TEXT
+    if version == 0 {
+        // Databases from before entries were timestamped
+        let unstamped = sqlx::query(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'indices'
+             AND NOT EXISTS (SELECT 1 FROM pragma_table_info('indices') WHERE name = 'updated_at')",
         )
-        ",
-        )
-        .await
-        .unwrap();
+        .fetch_optional(db)
+        .await?
+        .is_some();
+        if unstamped {
+            db.execute("ALTER TABLE indices ADD COLUMN updated_at INTEGER")
+                .await?;
+        }
+    }
 
-        pool.close().await;
-        info!("closed: {}", pool.is_closed());
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let target = applied + 1;
+        info!("Migrating database to schema version {}", target);
+        let mut transaction = db.begin().await?;
+        transaction.execute(*migration).await?;
+        transaction.execute("DELETE FROM schema_version").await?;
+        sqlx::query("INSERT INTO schema_version (version) VALUES (?)")
+            .bind(target as i64)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
     }
-    DBWrapper {
-        pool: SqlitePool::connect("sqlite://data.db").await.unwrap(),
+
+    Ok(())
+}
+
+/// A crawled page with its embeddings, ready for the index.
+#[derive(Debug, Clone)]
+pub struct IndexedPage {
+    pub url: String,
+    pub title: String,
+    pub summary: String,
+    pub title_embedding: Vec<f64>,
+    pub body_embeddings: Vec<Vec<f64>>,
+    /// Text of the page in the chunks `body_embeddings` were computed from.
+    pub chunks: Vec<String>,
+}
+
+/// Adds or replaces the pages in the index, all in one transaction, and
+/// then in the vector index. Embeddings are stored as `encoding` says.
+pub async fn update_entries(
+    db: &SqlitePool,
+    vectors: &Arc<VectorIndex>,
+    encoding: &VectorEncoding,
+    pages: &[IndexedPage],
+) -> Result<()> {
+    let updated_at = chrono::Utc::now().timestamp();
+    let chunk_format = encoding.format.for_chunks();
+    let mut pages_added: i64 = 0;
+    let mut tokens_added: i64 = 0;
+    let mut transaction = db.begin().await?;
+    for page in pages {
+        let dimension = page.title_embedding.len() as i64;
+        let title_bytes =
+            VectorEncoding::encode(encoding.format, std::slice::from_ref(&page.title_embedding));
+        let body_bytes = VectorEncoding::encode(encoding.format, &page.body_embeddings);
+
+        let id: i64 = sqlx::query("INSERT INTO indices (url, title, title_embedding, body_embedding_count, body_embeddings, summary, updated_at, embedding_model, embedding_dimension, embedding_format) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT(url) DO UPDATE SET title=excluded.title, title_embedding=excluded.title_embedding, body_embedding_count=excluded.body_embedding_count, body_embeddings=excluded.body_embeddings, summary=excluded.summary, updated_at=excluded.updated_at, embedding_model=excluded.embedding_model, embedding_dimension=excluded.embedding_dimension, embedding_format=excluded.embedding_format RETURNING id")
+            .bind(&page.url)
+            .bind(&page.title)
+            .bind(title_bytes)
+            .bind(page.body_embeddings.len() as i64)
+            .bind(body_bytes)
+            .bind(&page.summary)
+            .bind(updated_at)
+            .bind(&encoding.model)
+            .bind(dimension)
+            .bind(encoding.format.name())
+            .fetch_one(&mut *transaction)
+            .await?
+            .try_get("id")?;
+
+        if let Some(old) = sqlx::query("SELECT title, text FROM pages_fts WHERE rowid = ?")
+            .bind(id)
+            .fetch_optional(&mut *transaction)
+            .await?
+        {
+            let title: Option<String> = old.try_get("title")?;
+            let text: Option<String> = old.try_get("text")?;
+            pages_added -= 1;
+            tokens_added -= token_count(title.as_deref(), text.as_deref());
+            sqlx::query("DELETE FROM pages_fts WHERE rowid = ?")
+                .bind(id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        let text = page.chunks.concat();
+        sqlx::query("INSERT INTO pages_fts (rowid, title, text) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(&page.title)
+            .bind(&text)
+            .execute(&mut *transaction)
+            .await?;
+        pages_added += 1;
+        tokens_added += token_count(Some(&page.title), Some(&text));
+
+        sqlx::query("DELETE FROM chunks WHERE page_id = ?")
+            .bind(id)
+            .execute(&mut *transaction)
+            .await?;
+        let mut start = 0;
+        for (ordinal, (text, embedding)) in
+            page.chunks.iter().zip(&page.body_embeddings).enumerate()
+        {
+            let end = start + text.chars().count() as i64;
+            sqlx::query("INSERT INTO chunks (page_id, ordinal, text, embedding, start_char, end_char, embedding_model, embedding_dimension, embedding_format) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+                .bind(id)
+                .bind(ordinal as i64)
+                .bind(text)
+                .bind(VectorEncoding::encode(chunk_format, std::slice::from_ref(embedding)))
+                .bind(start)
+                .bind(end)
+                .bind(&encoding.model)
+                .bind(embedding.len() as i64)
+                .bind(chunk_format.name())
+                .execute(&mut *transaction)
+                .await?;
+            start = end;
+        }
     }
+    sqlx::query("UPDATE page_text_stats SET pages = pages + ?, tokens = tokens + ?")
+        .bind(pages_added)
+        .bind(tokens_added)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    // Linking the vectors into the graph takes a while
+    let vectors = vectors.clone();
+    let pages = pages.to_vec();
+    tokio::task::spawn_blocking(move || {
+        for page in pages {
+            vectors.add_page(
+                &page.url,
+                &page.title_embedding,
+                &page.body_embeddings,
+                updated_at,
+            );
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Number of terms of a page in `pages_fts`.
+fn token_count(title: Option<&str>, text: Option<&str>) -> i64 {
+    let title = lexical::tokenize(title.unwrap_or_default()).count();
+    let text = lexical::tokenize(text.unwrap_or_default()).count();
+    (title + text) as i64
+}
+
+/// A chunk of an indexed page, with the offsets of its characters in the
+/// page text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub text: String,
+    pub embedding: Vec<f64>,
+    pub start_char: i64,
+    pub end_char: i64,
 }
 
-pub async fn update_entry(
+/// The vectors in `column` of a row with the `embedding_*` columns, or `None`
+/// if they cannot be decoded.
+fn stored_vectors(row: &SqliteRow, column: &str) -> Result<Option<Vec<Vec<f64>>>> {
+    let bytes: Option<Vec<u8>> = row.try_get(column)?;
+    let dimension: Option<i64> = row.try_get("embedding_dimension")?;
+    let format: String = row.try_get("embedding_format")?;
+    Ok(
+        match (bytes, dimension, EmbeddingFormat::from_name(&format)) {
+            (Some(bytes), Some(dimension), Some(format)) if dimension > 0 => {
+                format.decode(&bytes, dimension as usize)
+            }
+            _ => None,
+        },
+    )
+}
+
+/// The chunks stored for `url`, in order. Empty if the page is not indexed,
+/// was indexed before chunks were stored or with another model.
+pub async fn page_chunks(
+    db: &SqlitePool,
+    encoding: &VectorEncoding,
     url: &str,
-    title: &str,
-    summary: &str,
-    title_embedding: Vec<f64>,
-    body_embeddings: Vec<Vec<f64>>,
-) {
-    let wrapper = get_db_pool().await;
+) -> Result<Vec<Chunk>> {
+    let rows = sqlx::query("SELECT chunks.* FROM chunks JOIN indices ON indices.id = chunks.page_id WHERE indices.url = ? AND (chunks.embedding_model IS NULL OR chunks.embedding_model = ?) ORDER BY chunks.ordinal")
+        .bind(url)
+        .bind(&encoding.model)
+        .fetch_all(db)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            let mut embedding = stored_vectors(row, "embedding")?
+                .filter(|vectors| vectors.len() == 1)
+                .ok_or_else(|| {
+                    Error::Embedding(format!("Invalid embedding of a chunk of {}", url))
+                })?;
+            Ok(Chunk {
+                text: row.try_get("text")?,
+                embedding: embedding.remove(0),
+                start_char: row.try_get("start_char")?,
+                end_char: row.try_get("end_char")?,
+            })
+        })
+        .collect()
+}
+
+/// A row of `indices`. `embeddings` is `None` when they cannot be decoded.
+struct StoredPage {
+    url: String,
+    title: String,
+    summary: String,
+    updated_at: i64,
+    /// Model of the embeddings, `None` for rows from before it was recorded.
+    model: Option<String>,
+    dimension: Option<i64>,
+    quantized: bool,
+    embeddings: Option<(Vec<f64>, Vec<Vec<f64>>)>,
+}
+
+fn stored_page(row: &SqliteRow) -> Result<StoredPage> {
+    let body_embedding_count: Option<i64> = row.try_get("body_embedding_count")?;
+    let embeddings = match (
+        stored_vectors(row, "title_embedding")?,
+        stored_vectors(row, "body_embeddings")?,
+    ) {
+        (Some(mut title_embedding), Some(body_embeddings))
+            if title_embedding.len() == 1
+                && !body_embeddings.is_empty()
+                && body_embedding_count == Some(body_embeddings.len() as i64) =>
+        {
+            Some((title_embedding.remove(0), body_embeddings))
+        }
+        _ => None,
+    };
+    let format: String = row.try_get("embedding_format")?;
+
+    Ok(StoredPage {
+        url: row.try_get("url")?,
+        title: row.try_get("title")?,
+        summary: row.try_get("summary")?,
+        updated_at: row.try_get::<Option<i64>, _>("updated_at")?.unwrap_or(0),
+        model: row.try_get("embedding_model")?,
+        dimension: row.try_get("embedding_dimension")?,
+        quantized: EmbeddingFormat::from_name(&format) == Some(EmbeddingFormat::Int8),
+        embeddings,
+    })
+}
+
+/// Pages scored for a query, and how many were left out for their
+/// embeddings.
+#[derive(Debug, Default)]
+struct Scored {
+    pages: Vec<RankedPage>,
+    /// Title embeddings and number of chunks of the pages scored from
+    /// quantized vectors, by URL.
+    quantized: HashMap<String, (Vec<f64>, usize)>,
+    /// Pages embedded with another model, or of another dimension.
+    incompatible: usize,
+    /// Pages whose embeddings cannot be decoded.
+    undecodable: usize,
+}
 
-    let title_bytes = cast_slice(&title_embedding);
+/// Scores a row for the query into `scored`, unless the filter rejects it
+/// or its embeddings cannot be compared with the query.
+fn rank_row(
+    row: &SqliteRow,
+    encoding: &VectorEncoding,
+    query_embedding: &[f64],
+    bm25: &Bm25,
+    filter: &ResultFilter,
+    scored: &mut Scored,
+) -> Result<()> {
+    let page = stored_page(row)?;
+    if !filter.allows_url(&page.url)
+        || filter
+            .indexed_after
+            .is_some_and(|indexed_after| page.updated_at < indexed_after)
+    {
+        return Ok(());
+    }
+    if !encoding.accepts(page.model.as_deref())
+        || page.dimension != Some(query_embedding.len() as i64)
+    {
+        scored.incompatible += 1;
+        return Ok(());
+    }
+    let Some((title_embedding, body_embeddings)) = page.embeddings else {
+        scored.undecodable += 1;
+        return Ok(());
+    };
+
+    let similarity =
+        search::calculate_entry_similarity(query_embedding, &title_embedding, &body_embeddings);
+
+    // Remove entry if similarity weird
+    if !(-10.0..=10.0).contains(&similarity) {
+        return Ok(());
+    }
+
+    let text: Option<String> = row.try_get("text")?;
+    let lexical = bm25.score(&page.title, text.as_deref().unwrap_or_default());
+
+    if page.quantized {
+        scored
+            .quantized
+            .insert(page.url.clone(), (title_embedding, body_embeddings.len()));
+    }
+    scored.pages.push(RankedPage {
+        entry: Entry {
+            score: similarity,
+            url: page.url,
+            title: page.title,
+            description: page.summary,
+        },
+        scores: Scores {
+            similarity,
+            lexical,
+        },
+    });
+    Ok(())
+}
 
-    let body_count = body_embeddings.len() as i64;
+/// Pages scored from quantized vectors that are rescored for every result
+/// asked for, the most similar first.
+const RESCORED_PER_RESULT: usize = 2;
 
-    let mut body_bytes: Vec<u8> = Vec::new();
-    body_embeddings.into_iter().for_each(|v| {
-        let v_bytes: &[u8] = cast_slice(&v);
-        body_bytes.extend(v_bytes);
+/// Replaces the similarity of the pages scored from quantized vectors by
+/// the one of their chunk embeddings, which are not quantized.
+async fn rescore(
+    db: &SqlitePool,
+    query_embedding: &[f64],
+    scored: &mut Scored,
+    limit: usize,
+) -> Result<()> {
+    let mut rescored: Vec<usize> = (0..scored.pages.len())
+        .filter(|&idx| scored.quantized.contains_key(&scored.pages[idx].entry.url))
+        .collect();
+    rescored.sort_by(|&a, &b| {
+        scored.pages[b]
+            .scores
+            .similarity
+            .total_cmp(&scored.pages[a].scores.similarity)
     });
+    rescored.truncate(limit.saturating_mul(RESCORED_PER_RESULT));
+
+    for batch in rescored.chunks(URLS_PER_QUERY) {
+        let sql = format!(
+            "SELECT indices.url AS url, chunks.embedding, chunks.embedding_dimension, chunks.embedding_format
+             FROM chunks JOIN indices ON indices.id = chunks.page_id
+             WHERE indices.url IN ({}) ORDER BY chunks.page_id, chunks.ordinal",
+            vec!["?"; batch.len()].join(", ")
+        );
+        let mut query = sqlx::query(&sql);
+        for &idx in batch {
+            query = query.bind(&scored.pages[idx].entry.url);
+        }
+        let mut chunks: HashMap<String, Vec<Vec<f64>>> = HashMap::new();
+        for row in query.fetch_all(db).await? {
+            if let Some(embedding) = stored_vectors(&row, "embedding")? {
+                chunks
+                    .entry(row.try_get("url")?)
+                    .or_default()
+                    .extend(embedding);
+            }
+        }
 
-    wrapper.pool.execute(
-        sqlx::query("INSERT INTO indices (url, title, title_embedding, body_embedding_count, body_embeddings, summary) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT(url) DO UPDATE SET title=excluded.title, title_embedding=excluded.title_embedding, body_embedding_count=excluded.body_embedding_count, body_embeddings=excluded.body_embeddings, summary=excluded.summary")
-            .bind(url)
-            .bind(title)
-            .bind(&title_bytes)
-            .bind(body_count)
-            .bind(&body_bytes)
-            .bind(summary)
-    ).await.unwrap();
-}
-
-pub async fn query_db(query_embedding: &[f64]) -> Vec<(String, String, String, f64)> {
-    let wrapper = get_db_pool().await;
-    let pool = &wrapper.pool;
-
-    // Fetch all rows from the users table
-    let mut rows = sqlx::query("SELECT * FROM indices").fetch(pool);
-
-    let mut entries_with_sim = Vec::new();
-    while let Some(row) = rows.try_next().await.unwrap() {
-        // map the row into a user-defined domain type
-        let url: &str = row.try_get("url").unwrap();
-        let title: &str = row.try_get("title").unwrap();
-        let title_embedding: Vec<u8> = row.try_get("title_embedding").unwrap();
-        let body_embedding_count: i64 = row.try_get("body_embedding_count").unwrap();
-        let body_embeddings: Vec<u8> = row.try_get("body_embeddings").unwrap();
-        let summary: &str = row.try_get("summary").unwrap();
-
-        let title_embedding: &[f64] = match try_cast_slice(&title_embedding) {
-            Ok(v) => v,
-            Err(_) => continue,
+        for &idx in batch {
+            let page = &mut scored.pages[idx];
+            let (title_embedding, count) = &scored.quantized[&page.entry.url];
+            // Pages missing chunks keep their approximate similarity
+            if let Some(body_embeddings) = chunks
+                .get(&page.entry.url)
+                .filter(|body_embeddings| body_embeddings.len() == *count)
+            {
+                page.scores.similarity = search::calculate_entry_similarity(
+                    query_embedding,
+                    title_embedding,
+                    body_embeddings,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// BM25 of `query` against the text of the pages in the index.
+pub async fn lexical_scorer(db: &SqlitePool, query: &str) -> Result<Bm25> {
+    let terms = lexical::query_terms(query);
+    let mut documents = HashMap::new();
+    if !terms.is_empty() {
+        let sql = format!(
+            "SELECT term, doc FROM pages_fts_vocab WHERE term IN ({})",
+            vec!["?"; terms.len()].join(", ")
+        );
+        let mut query = sqlx::query(&sql);
+        for term in &terms {
+            query = query.bind(term);
+        }
+        for row in query.fetch_all(db).await? {
+            documents.insert(row.try_get("term")?, row.try_get("doc")?);
+        }
+    }
+    let stats = sqlx::query("SELECT pages, tokens FROM page_text_stats")
+        .fetch_one(db)
+        .await?;
+    let pages: i64 = stats.try_get("pages")?;
+    let tokens: i64 = stats.try_get("tokens")?;
+
+    Ok(Bm25::new(terms, &documents, pages, tokens))
+}
+
+/// Candidates taken from the vector index for every result asked for. The
+/// pages with the nearest single vector are not always the ones scoring
+/// highest, and some are rejected by the filter.
+const CANDIDATES_PER_RESULT: usize = 4;
+/// URLs bound to a single query; SQLite limits the number of parameters.
+const URLS_PER_QUERY: usize = 500;
+
+/// Columns of a page with its text.
+const PAGE_COLUMNS: &str =
+    "indices.*, pages_fts.text AS text FROM indices LEFT JOIN pages_fts ON pages_fts.rowid = indices.id";
+
+/// A page of the index with the score it was ranked by.
+#[derive(Debug, Clone)]
+pub struct RankedPage {
+    /// `score` is the blend of `scores`.
+    pub entry: Entry,
+    pub scores: Scores,
+}
+
+/// The `limit` pages of the index ranked highest for the query, by the
+/// similarity of their embeddings blended with the BM25 score of their text
+/// from `lexical_scorer`.
+///
+/// Candidates come from the vector index and the text index and are scored
+/// exactly. The table is scanned instead when the vector index cannot answer
+/// or too few candidates pass the filter. Pages embedded with another model
+/// than `encoding`'s are left out, and the most similar of those stored
+/// quantized are rescored.
+#[allow(clippy::too_many_arguments)]
+pub async fn query_db(
+    db: &SqlitePool,
+    vectors: &VectorIndex,
+    encoding: &VectorEncoding,
+    ranking: &RankingConfig,
+    bm25: &Bm25,
+    query_embedding: &[f64],
+    filter: &ResultFilter,
+    limit: usize,
+) -> Result<Vec<RankedPage>> {
+    let mut scored = Scored::default();
+
+    let wanted = limit.saturating_mul(CANDIDATES_PER_RESULT);
+    if let Some(mut candidates) = vectors.candidates(query_embedding, wanted) {
+        let exhaustive = candidates.len() >= vectors.len();
+        if let Some(match_query) = bm25.match_query() {
+            let matches: Vec<String> = sqlx::query_scalar(
+                "SELECT indices.url FROM pages_fts JOIN indices ON indices.id = pages_fts.rowid
+                 WHERE pages_fts MATCH ? ORDER BY rank LIMIT ?",
+            )
+            .bind(match_query)
+            .bind(wanted as i64)
+            .fetch_all(db)
+            .await?;
+            let known: HashSet<String> = candidates.iter().cloned().collect();
+            candidates.extend(matches.into_iter().filter(|url| !known.contains(url)));
+        }
+
+        for urls in candidates.chunks(URLS_PER_QUERY) {
+            let sql = format!(
+                "SELECT {} WHERE indices.url IN ({})",
+                PAGE_COLUMNS,
+                vec!["?"; urls.len()].join(", ")
+            );
+            let mut query = sqlx::query(&sql);
+            for url in urls {
+                query = query.bind(url);
+            }
+            for row in query.fetch_all(db).await? {
+                rank_row(&row, encoding, query_embedding, bm25, filter, &mut scored)?;
+            }
+        }
+        if scored.pages.len() < limit && !exhaustive {
+            scored = Scored::default();
+        }
+    }
+
+    if scored.pages.is_empty() {
+        // Fetch all rows indexed recently enough
+        let sql = format!(
+            "SELECT {} WHERE ?1 IS NULL OR indices.updated_at >= ?1",
+            PAGE_COLUMNS
+        );
+        let mut rows = sqlx::query(&sql).bind(filter.indexed_after).fetch(db);
+        while let Some(row) = rows.try_next().await? {
+            rank_row(&row, encoding, query_embedding, bm25, filter, &mut scored)?;
+        }
+    }
+
+    if scored.incompatible > 0 {
+        warn!(
+            "Left out {} pages embedded with another model than {} until they are crawled again",
+            scored.incompatible, encoding.model
+        );
+    }
+    if scored.undecodable > 0 {
+        warn!(
+            "Left out {} pages whose embeddings cannot be decoded",
+            scored.undecodable
+        );
+    }
+    rescore(db, query_embedding, &mut scored, limit).await?;
+
+    let mut ranked = scored.pages;
+    let scores: Vec<Scores> = ranked.iter().map(|page| page.scores).collect();
+    let keys: Vec<&str> = ranked.iter().map(|page| page.entry.url.as_str()).collect();
+    let fused = ranking::fuse(ranking, &scores, &keys);
+    for (page, score) in ranked.iter_mut().zip(fused) {
+        page.entry.score = score;
+    }
+
+    // Ties are broken by URL so that pages of the ranking are stable
+    ranked.sort_by(|a, b| {
+        b.entry
+            .score
+            .total_cmp(&a.entry.score)
+            .then_with(|| a.entry.url.cmp(&b.entry.url))
+    });
+    ranked.truncate(limit);
+
+    Ok(ranked)
+}
+
+/// Loads the vector index saved next to the database, rebuilding it from
+/// `indices` if it is missing or out of date. Only pages whose embeddings
+/// `encoding` accepts are in it.
+pub async fn open_vector_index(
+    db: &SqlitePool,
+    config: &DatabaseConfig,
+    encoding: &VectorEncoding,
+) -> Result<VectorIndex> {
+    let path = (config.path != Path::new(IN_MEMORY)).then(|| VectorIndex::path_for(&config.path));
+    let row = sqlx::query(
+        "SELECT COUNT(*) AS pages, COALESCE(MAX(updated_at), 0) AS updated_at FROM indices
+         WHERE embedding_model IS NULL OR embedding_model = ?",
+    )
+    .bind(&encoding.model)
+    .fetch_one(db)
+    .await?;
+    let stamp = Stamp {
+        pages: row.try_get("pages")?,
+        updated_at: row.try_get("updated_at")?,
+    };
+
+    if let Some(index) = path.as_deref().and_then(VectorIndex::load) {
+        if index.stamp() == stamp {
+            info!("Loaded the vector index of {} pages", stamp.pages);
+            return Ok(index);
+        }
+        info!("The vector index is out of date");
+    }
+
+    info!("Building the vector index of {} pages...", stamp.pages);
+    let index = VectorIndex::new(path);
+    let mut rows =
+        sqlx::query("SELECT * FROM indices WHERE embedding_model IS NULL OR embedding_model = ?")
+            .bind(&encoding.model)
+            .fetch(db);
+    while let Some(row) = rows.try_next().await? {
+        let page = stored_page(&row)?;
+        // Pages without embeddings are added too, so that the stamps match
+        let (title_embedding, body_embeddings) = page.embeddings.unwrap_or_default();
+        index.add_page(
+            &page.url,
+            &title_embedding,
+            &body_embeddings,
+            page.updated_at,
+        );
+    }
+    if let Err(e) = index.save() {
+        warn!("Failed to save the vector index: {}", e);
+    }
+
+    Ok(index)
+}
+
+/// A snippet an answer in a session was based on, numbered from 1.
+#[derive(Debug, Clone)]
+pub struct SessionSnippet {
+    pub url: String,
+    pub title: String,
+    pub text: String,
+}
+
+/// A conversation started by a search or a chat request.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub query: String,
+    pub messages: Vec<Message>,
+    pub snippets: Vec<SessionSnippet>,
+}
+
+/// Creates an empty session and returns its id.
+pub async fn create_session(db: &SqlitePool, query: &str) -> Result<String> {
+    let id = uuid::Uuid::new_v4().to_string();
+
+    sqlx::query("INSERT INTO sessions (id, query, created_at) VALUES (?, ?, ?)")
+        .bind(&id)
+        .bind(query)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(db)
+        .await?;
+
+    Ok(id)
+}
+
+pub async fn load_session(db: &SqlitePool, id: &str) -> Result<Option<Session>> {
+    let Some(row) = sqlx::query("SELECT query FROM sessions WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let messages =
+        sqlx::query("SELECT role, content FROM session_messages WHERE session_id = ? ORDER BY id")
+            .bind(id)
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let role: String = row.try_get("role")?;
+                let content: String = row.try_get("content")?;
+                // Rows with an unknown role are skipped
+                Ok(Role::parse(&role).map(|role| Message { role, content }))
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<Message>>>()?;
+
+    let snippets = sqlx::query(
+        "SELECT url, title, text FROM session_snippets WHERE session_id = ? ORDER BY idx",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(SessionSnippet {
+            url: row.try_get("url")?,
+            title: row.try_get("title")?,
+            text: row.try_get("text")?,
+        })
+    })
+    .collect::<Result<Vec<SessionSnippet>>>()?;
+
+    Ok(Some(Session {
+        id: id.to_string(),
+        query: row.try_get("query")?,
+        messages,
+        snippets,
+    }))
+}
+
+pub async fn append_messages(
+    db: &SqlitePool,
+    session_id: &str,
+    messages: &[Message],
+) -> Result<()> {
+    let mut transaction = db.begin().await?;
+    for message in messages {
+        sqlx::query(
+            "INSERT INTO session_messages (session_id, role, content, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(session_id)
+        .bind(message.role.as_str())
+        .bind(&message.content)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Replaces the snippets of a session.
+pub async fn save_snippets(
+    db: &SqlitePool,
+    session_id: &str,
+    snippets: &[SessionSnippet],
+) -> Result<()> {
+    let mut transaction = db.begin().await?;
+    sqlx::query("DELETE FROM session_snippets WHERE session_id = ?")
+        .bind(session_id)
+        .execute(&mut *transaction)
+        .await?;
+    for (idx, snippet) in snippets.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO session_snippets (session_id, idx, url, title, text) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(session_id)
+        .bind(idx as i64 + 1)
+        .bind(&snippet.url)
+        .bind(&snippet.title)
+        .bind(&snippet.text)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+fn json_error(e: serde_json::Error) -> sqlx::Error {
+    sqlx::Error::Decode(Box::new(e))
+}
+
+fn history_entry(row: &SqliteRow) -> Result<HistoryEntry> {
+    let request: String = row.try_get("request")?;
+    let results: String = row.try_get("results")?;
+    Ok(HistoryEntry {
+        id: row.try_get("id")?,
+        request: serde_json::from_str(&request).map_err(json_error)?,
+        created_at: row.try_get("created_at")?,
+        session_id: row.try_get("session_id")?,
+        results: serde_json::from_str(&results).map_err(json_error)?,
+        answer: row.try_get("answer")?,
+    })
+}
+
+pub async fn save_search(db: &SqlitePool, entry: &HistoryEntry) -> Result<()> {
+    let request = serde_json::to_string(&entry.request).map_err(json_error)?;
+    let results = serde_json::to_string(&entry.results).map_err(json_error)?;
+
+    sqlx::query(
+        "INSERT INTO searches (id, query, request, created_at, session_id, results, answer) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&entry.id)
+    .bind(&entry.request.query)
+    .bind(request)
+    .bind(entry.created_at)
+    .bind(&entry.session_id)
+    .bind(results)
+    .bind(&entry.answer)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Recorded searches, newest first.
+pub async fn list_searches(
+    db: &SqlitePool,
+    limit: usize,
+    offset: usize,
+) -> Result<Vec<HistoryEntry>> {
+    sqlx::query("SELECT * FROM searches ORDER BY created_at DESC, rowid DESC LIMIT ? OFFSET ?")
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(db)
+        .await?
+        .iter()
+        .map(history_entry)
+        .collect()
+}
+
+pub async fn load_search(db: &SqlitePool, id: &str) -> Result<Option<HistoryEntry>> {
+    sqlx::query("SELECT * FROM searches WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+        .as_ref()
+        .map(history_entry)
+        .transpose()
+}
+
+/// Deletes a recorded search and its chat session. Returns whether it existed.
+pub async fn delete_search(db: &SqlitePool, id: &str) -> Result<bool> {
+    let mut transaction = db.begin().await?;
+
+    let Some(row) = sqlx::query("DELETE FROM searches WHERE id = ? RETURNING session_id")
+        .bind(id)
+        .fetch_optional(&mut *transaction)
+        .await?
+    else {
+        return Ok(false);
+    };
+    let session_id: Option<String> = row.try_get("session_id")?;
+    if let Some(session_id) = session_id {
+        sqlx::query("DELETE FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Fusion;
+    use searchllama_types::types::SearchRequest;
+
+    impl DatabaseConfig {
+        fn default_in_memory() -> Self {
+            Self {
+                path: IN_MEMORY.into(),
+                ..Default::default()
+            }
+        }
+    }
+
+    async fn in_memory() -> SqlitePool {
+        open(&DatabaseConfig::default_in_memory()).await.unwrap()
+    }
+
+    fn encoding(format: EmbeddingFormat) -> VectorEncoding {
+        VectorEncoding {
+            model: "test".to_string(),
+            format,
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_databases_from_before_versioning() {
+        let db = in_memory().await;
+        db.execute("CREATE TABLE indices (url TEXT NOT NULL UNIQUE, title TEXT, title_embedding BLOB, body_embedding_count INTEGER, body_embeddings BLOB, summary TEXT)")
+            .await
+            .unwrap();
+        // A page written before the embedding columns, as raw f64 bytes
+        let legacy = |values: &[f64]| -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect()
+        };
+        sqlx::query("INSERT INTO indices VALUES ('https://example.org', 'Legacy', ?, 1, ?, '')")
+            .bind(legacy(&[0.0, 1.0]))
+            .bind(legacy(&[0.0, 1.0]))
+            .execute(&db)
+            .await
+            .unwrap();
+
+        migrate(&db).await.unwrap();
+        // Running again is a no-op
+        migrate(&db).await.unwrap();
+        assert_eq!(schema_version(&db).await.unwrap(), MIGRATIONS.len());
+
+        let page = IndexedPage {
+            url: "https://example.com".to_string(),
+            title: "Example".to_string(),
+            summary: String::new(),
+            title_embedding: vec![1.0, 0.0],
+            body_embeddings: vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            chunks: vec!["An example ".to_string(), "page".to_string()],
+        };
+        let vectors = Arc::new(
+            open_vector_index(
+                &db,
+                &DatabaseConfig::default_in_memory(),
+                &encoding(EmbeddingFormat::F64),
+            )
+            .await
+            .unwrap(),
+        );
+        assert_eq!(vectors.len(), 1);
+        update_entries(
+            &db,
+            &vectors,
+            &encoding(EmbeddingFormat::F64),
+            &[page.clone(), page],
+        )
+        .await
+        .unwrap();
+        let bm25 = lexical_scorer(&db, "example").await.unwrap();
+        let ranked = query_db(
+            &db,
+            &vectors,
+            &encoding(EmbeddingFormat::F64),
+            &RankingConfig::default(),
+            &bm25,
+            &[1.0, 0.0],
+            &ResultFilter::default(),
+            10,
+        )
+        .await
+        .unwrap();
+        // The legacy page is still ranked, with no model to tell it apart
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].entry.url, "https://example.com");
+        assert!(ranked[0].scores.lexical > 0.0);
+        assert_eq!(ranked[1].scores.similarity, 0.0);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_replaced_with_the_page() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let vectors = Arc::new(VectorIndex::new(None));
+        let mut page = IndexedPage {
+            url: "https://example.com".to_string(),
+            title: "Example".to_string(),
+            summary: String::new(),
+            title_embedding: vec![1.0, 0.0],
+            body_embeddings: vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8]],
+            chunks: vec![
+                "Ünïcode ".to_string(),
+                "text in ".to_string(),
+                "three".to_string(),
+            ],
+        };
+        update_entries(
+            &db,
+            &vectors,
+            &encoding(EmbeddingFormat::F64),
+            std::slice::from_ref(&page),
+        )
+        .await
+        .unwrap();
+        // Offsets count characters, not bytes
+        let chunks = page_chunks(&db, &encoding(EmbeddingFormat::F64), &page.url)
+            .await
+            .unwrap();
+        assert_eq!((chunks[2].start_char, chunks[2].end_char), (16, 21));
+
+        page.body_embeddings.truncate(2);
+        page.chunks = vec!["Shorter ".to_string(), "text".to_string()];
+        update_entries(
+            &db,
+            &vectors,
+            &encoding(EmbeddingFormat::F64),
+            std::slice::from_ref(&page),
+        )
+        .await
+        .unwrap();
+
+        let chunks = page_chunks(&db, &encoding(EmbeddingFormat::F64), &page.url)
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                Chunk {
+                    text: "Shorter ".to_string(),
+                    embedding: vec![1.0, 0.0],
+                    start_char: 0,
+                    end_char: 8,
+                },
+                Chunk {
+                    text: "text".to_string(),
+                    embedding: vec![0.0, 1.0],
+                    start_char: 8,
+                    end_char: 12,
+                },
+            ]
+        );
+        assert!(
+            page_chunks(&db, &encoding(EmbeddingFormat::F64), "https://example.org")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    fn random_vectors(count: usize, dimension: usize, state: &mut u64) -> Vec<Vec<f64>> {
+        (0..count)
+            .map(|_| {
+                (0..dimension)
+                    .map(|_| {
+                        *state = state
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407);
+                        (*state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn vector_index_ranks_like_a_full_scan() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let vectors = Arc::new(VectorIndex::new(None));
+        let mut state = 7;
+        let pages: Vec<IndexedPage> = (0..400)
+            .map(|i| IndexedPage {
+                url: format!("https://example.com/{}", i),
+                title: String::new(),
+                summary: String::new(),
+                title_embedding: random_vectors(1, 24, &mut state).remove(0),
+                body_embeddings: random_vectors(1 + i % 4, 24, &mut state),
+                chunks: Vec::new(),
+            })
+            .collect();
+        update_entries(&db, &vectors, &encoding(EmbeddingFormat::F64), &pages)
+            .await
+            .unwrap();
+
+        // The index is rebuilt from the table the same way
+        let rebuilt = open_vector_index(
+            &db,
+            &DatabaseConfig::default_in_memory(),
+            &encoding(EmbeddingFormat::F64),
+        )
+        .await
+        .unwrap();
+        assert_eq!(rebuilt.stamp(), vectors.stamp());
+
+        let filter = ResultFilter::default();
+        let ranking = RankingConfig::default();
+        let bm25 = Bm25::default();
+        for query in random_vectors(10, 24, &mut state) {
+            let mut exact: Vec<f64> = pages
+                .iter()
+                .map(|page| {
+                    search::calculate_entry_similarity(
+                        &query,
+                        &page.title_embedding,
+                        &page.body_embeddings,
+                    )
+                })
+                .collect();
+            exact.sort_by(|a, b| b.total_cmp(a));
+
+            let ranked = query_db(
+                &db,
+                &vectors,
+                &encoding(EmbeddingFormat::F64),
+                &ranking,
+                &bm25,
+                &query,
+                &filter,
+                10,
+            )
+            .await
+            .unwrap();
+            assert_eq!(ranked.len(), 10);
+            for (page, exact) in ranked.iter().zip(&exact) {
+                let similarity = page.scores.similarity;
+                assert!(
+                    (similarity - exact).abs() < 0.01,
+                    "{} vs {}",
+                    similarity,
+                    exact
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_newer_databases() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        sqlx::query("UPDATE schema_version SET version = ?")
+            .bind(MIGRATIONS.len() as i64 + 1)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert!(matches!(migrate(&db).await, Err(Error::Schema(_))));
+    }
+
+    #[tokio::test]
+    async fn lexical_matches_are_found_and_ranked_first() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let vectors = Arc::new(VectorIndex::new(None));
+        let mut state = 11;
+        let pages: Vec<IndexedPage> = (0..200)
+            .map(|i| IndexedPage {
+                url: format!("https://example.com/{}", i),
+                title: format!("Page {}", i),
+                summary: String::new(),
+                title_embedding: random_vectors(1, 16, &mut state).remove(0),
+                body_embeddings: random_vectors(2, 16, &mut state),
+                chunks: vec![match i {
+                    123 => "error[E0502]: cannot borrow as mutable".to_string(),
+                    _ => "cannot borrow as mutable".to_string(),
+                }],
+            })
+            .collect();
+        update_entries(&db, &vectors, &encoding(EmbeddingFormat::F64), &pages)
+            .await
+            .unwrap();
+        // Replaced pages are counted once, with their new text
+        update_entries(&db, &vectors, &encoding(EmbeddingFormat::F64), &pages[..10])
+            .await
+            .unwrap();
+        let stats = sqlx::query("SELECT pages, tokens FROM page_text_stats")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let tokens: i64 = sqlx::query_scalar("SELECT SUM(cnt) FROM pages_fts_vocab")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(stats.get::<i64, _>("pages"), 200);
+        assert_eq!(stats.get::<i64, _>("tokens"), tokens);
+
+        let ranking = RankingConfig {
+            fusion: Fusion::Rrf,
+            ..Default::default()
         };
-        let body_embedding: Vec<f64> = match try_cast_slice(&body_embeddings) {
-            Ok(v) => v.to_vec(),
-            Err(_) => continue,
+        let bm25 = lexical_scorer(&db, "E0502").await.unwrap();
+        let query = random_vectors(1, 16, &mut state).remove(0);
+        let ranked = query_db(
+            &db,
+            &vectors,
+            &encoding(EmbeddingFormat::F64),
+            &ranking,
+            &bm25,
+            &query,
+            &ResultFilter::default(),
+            3,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ranked[0].entry.url, "https://example.com/123");
+    }
+
+    #[tokio::test]
+    async fn pages_of_other_models_are_left_out() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let page = IndexedPage {
+            url: "https://example.com".to_string(),
+            title: "Example".to_string(),
+            summary: String::new(),
+            title_embedding: vec![1.0, 0.0],
+            body_embeddings: vec![vec![1.0, 0.0]],
+            chunks: vec!["An example page".to_string()],
         };
-        // Split body_embeddings in 'body_embedding_count' parts
-        let body_embeddings: Vec<Vec<f64>> = body_embedding
-            .chunks(body_embedding.len() / body_embedding_count as usize)
-            .map(|chunk| chunk.to_vec())
+        let vectors = Arc::new(VectorIndex::new(None));
+        update_entries(&db, &vectors, &encoding(EmbeddingFormat::F64), &[page])
+            .await
+            .unwrap();
+
+        let other = VectorEncoding {
+            model: "other".to_string(),
+            format: EmbeddingFormat::F64,
+        };
+        let ranked = query_db(
+            &db,
+            &vectors,
+            &other,
+            &RankingConfig::default(),
+            &Bm25::default(),
+            &[1.0, 0.0],
+            &ResultFilter::default(),
+            10,
+        )
+        .await
+        .unwrap();
+        assert!(ranked.is_empty());
+        assert!(page_chunks(&db, &other, "https://example.com")
+            .await
+            .unwrap()
+            .is_empty());
+        let rebuilt = open_vector_index(&db, &DatabaseConfig::default_in_memory(), &other)
+            .await
+            .unwrap();
+        assert_eq!(rebuilt.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn quantized_rankings_are_rescored() {
+        let mut state = 5;
+        let pages: Vec<IndexedPage> = (0..100)
+            .map(|i| IndexedPage {
+                url: format!("https://example.com/{}", i),
+                title: String::new(),
+                summary: String::new(),
+                title_embedding: random_vectors(1, 32, &mut state).remove(0),
+                body_embeddings: random_vectors(3, 32, &mut state),
+                chunks: vec!["a ".to_string(), "b ".to_string(), "c".to_string()],
+            })
             .collect();
+        let query = random_vectors(1, 32, &mut state).remove(0);
 
-        let similarity =
-            search::calculate_entry_similarity(&query_embedding, title_embedding, &body_embeddings);
+        let mut rankings = Vec::new();
+        for format in [EmbeddingFormat::F64, EmbeddingFormat::Int8] {
+            let db = in_memory().await;
+            migrate(&db).await.unwrap();
+            let vectors = Arc::new(VectorIndex::new(None));
+            update_entries(&db, &vectors, &encoding(format), &pages)
+                .await
+                .unwrap();
+            let bytes: i64 = sqlx::query_scalar("SELECT SUM(length(body_embeddings)) FROM indices")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+            assert_eq!(
+                bytes,
+                match format {
+                    EmbeddingFormat::Int8 => 300 * (4 + 32),
+                    _ => 300 * 32 * 8,
+                }
+            );
+            rankings.push(
+                query_db(
+                    &db,
+                    &vectors,
+                    &encoding(format),
+                    &RankingConfig::default(),
+                    &Bm25::default(),
+                    &query,
+                    &ResultFilter::default(),
+                    10,
+                )
+                .await
+                .unwrap(),
+            );
+        }
 
-        // Remove entry if similarity weird
-        if similarity < -10.0 || similarity > 10.0 {
-            continue;
+        // The top results are scored from the f32 chunks, bar the title
+        for (exact, quantized) in rankings[0].iter().zip(&rankings[1]) {
+            assert_eq!(exact.entry.url, quantized.entry.url);
+            assert!((exact.scores.similarity - quantized.scores.similarity).abs() < 0.01);
         }
+    }
 
-        entries_with_sim.push((
-            url.to_owned(),
-            title.to_owned(),
-            summary.to_owned(),
-            similarity,
-        ));
+    #[tokio::test]
+    async fn page_text_and_chunks_survive_vacuum() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let vectors = Arc::new(VectorIndex::new(None));
+        let pages: Vec<IndexedPage> = (0..3)
+            .map(|i| IndexedPage {
+                url: format!("https://example.com/{}", i),
+                title: format!("Page {}", i),
+                summary: String::new(),
+                title_embedding: vec![1.0, 0.0],
+                body_embeddings: vec![vec![1.0, 0.0]],
+                chunks: vec![format!("text of page {}", i)],
+            })
+            .collect();
+        update_entries(&db, &vectors, &encoding(EmbeddingFormat::F64), &pages)
+            .await
+            .unwrap();
+
+        // VACUUM may renumber implicit rowids, but not explicit ids
+        sqlx::query("DELETE FROM indices WHERE url = 'https://example.com/0'")
+            .execute(&db)
+            .await
+            .unwrap();
+        db.execute("VACUUM").await.unwrap();
+
+        let chunks = page_chunks(
+            &db,
+            &encoding(EmbeddingFormat::F64),
+            "https://example.com/2",
+        )
+        .await
+        .unwrap();
+        assert_eq!(chunks[0].text, "text of page 2");
+        let text: String = sqlx::query_scalar(&format!(
+            "SELECT text FROM (SELECT {}) WHERE url = 'https://example.com/2'",
+            PAGE_COLUMNS
+        ))
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(text, "text of page 2");
     }
 
-    entries_with_sim.sort_by(|a, b| b.3.partial_cmp(&a.3).unwrap());
+    #[tokio::test]
+    async fn sessions_round_trip() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let id = create_session(&db, "rust borrow checker").await.unwrap();
+        assert!(load_session(&db, "unknown").await.unwrap().is_none());
 
-    entries_with_sim
+        let snippet = |url: &str| SessionSnippet {
+            url: url.to_string(),
+            title: url.to_uppercase(),
+            text: format!("text of {}", url),
+        };
+        save_snippets(&db, &id, &[snippet("a"), snippet("b")])
+            .await
+            .unwrap();
+        save_snippets(&db, &id, &[snippet("c")]).await.unwrap();
+        for turn in [["What is it?", "A checker."], ["Why?", "Safety."]] {
+            let turn = [
+                Message::new(Role::User, turn[0]),
+                Message::new(Role::Assistant, turn[1]),
+            ];
+            append_messages(&db, &id, &turn).await.unwrap();
+        }
+
+        let session = load_session(&db, &id).await.unwrap().unwrap();
+        assert_eq!(session.query, "rust borrow checker");
+        let messages = session
+            .messages
+            .iter()
+            .map(|m| (m.role, m.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (Role::User, "What is it?"),
+                (Role::Assistant, "A checker."),
+                (Role::User, "Why?"),
+                (Role::Assistant, "Safety."),
+            ]
+        );
+        // Saving snippets again replaces them
+        assert_eq!(session.snippets.len(), 1);
+        assert_eq!(session.snippets[0].url, "c");
+        assert_eq!(session.snippets[0].text, "text of c");
+    }
+
+    #[tokio::test]
+    async fn searches_are_listed_newest_first_and_deleted_with_their_session() {
+        let db = in_memory().await;
+        migrate(&db).await.unwrap();
+        let session_id = create_session(&db, "second").await.unwrap();
+        let search = |query: &str, created_at: i64, session_id: Option<String>| HistoryEntry {
+            id: format!("id-{}", query),
+            request: SearchRequest::new(query),
+            created_at,
+            session_id,
+            results: Vec::new(),
+            answer: format!("answer to {}", query),
+        };
+        save_search(&db, &search("first", 1, None)).await.unwrap();
+        save_search(&db, &search("second", 2, Some(session_id.clone())))
+            .await
+            .unwrap();
+        save_search(&db, &search("third", 3, None)).await.unwrap();
+
+        let queries = |entries: Vec<HistoryEntry>| {
+            entries
+                .into_iter()
+                .map(|e| e.request.query)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            queries(list_searches(&db, 10, 0).await.unwrap()),
+            vec!["third", "second", "first"]
+        );
+        assert_eq!(
+            queries(list_searches(&db, 1, 1).await.unwrap()),
+            vec!["second"]
+        );
+        let entry = load_search(&db, "id-second").await.unwrap().unwrap();
+        assert_eq!(entry.answer, "answer to second");
+        assert_eq!(entry.session_id.as_deref(), Some(session_id.as_str()));
+
+        assert!(delete_search(&db, "id-second").await.unwrap());
+        assert!(!delete_search(&db, "id-second").await.unwrap());
+        assert!(load_search(&db, "id-second").await.unwrap().is_none());
+        assert!(load_session(&db, &session_id).await.unwrap().is_none());
+        assert_eq!(
+            queries(list_searches(&db, 10, 0).await.unwrap()),
+            vec!["third", "first"]
+        );
+    }
 }
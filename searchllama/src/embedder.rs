@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use ollama_rs::Ollama;
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+use crate::{
+    config::{Config, EmbeddingBackend},
+    error::{Error, Result},
+};
+
+/// A model that turns text into fixed-size vectors.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Identifies the model. Vectors from different ids must not be compared.
+    fn model_id(&self) -> &str;
+
+    async fn dimension(&self) -> Result<usize>;
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f64>>>;
+
+    async fn embed(&self, text: &str) -> Result<Vec<f64>> {
+        self.embed_batch(&[text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| Error::Embedding("Embedder returned no embedding".to_string()))
+    }
+}
+
+pub fn from_config(config: &Config) -> Result<Arc<dyn Embedder>> {
+    let model = config.models.embedding.clone();
+    Ok(match config.embedding.backend {
+        EmbeddingBackend::Ollama => Arc::new(OllamaEmbedder::new(&config.ollama.url, model)?),
+        EmbeddingBackend::OpenAi => Arc::new(OpenAiEmbedder::new(
+            &config.openai.url,
+            config.openai.api_key.clone(),
+            model,
+        )),
+        EmbeddingBackend::Hash => Arc::new(HashEmbedder::new(config.embedding.dimension)),
+    })
+}
+
+/// Lazily determines the dimension of a remote model by embedding a probe.
+async fn probe_dimension(embedder: &dyn Embedder, cell: &OnceCell<usize>) -> Result<usize> {
+    cell.get_or_try_init(|| async { Ok(embedder.embed("dimension probe").await?.len()) })
+        .await
+        .copied()
+}
+
+pub struct OllamaEmbedder {
+    ollama: Ollama,
+    model: String,
+    dimension: OnceCell<usize>,
+}
+
+impl OllamaEmbedder {
+    pub fn new(url: &str, model: String) -> Result<Self> {
+        Ok(Self {
+            ollama: Ollama::try_new(url)
+                .map_err(|e| Error::Embedding(format!("Invalid Ollama URL: {}", e)))?,
+            model,
+            dimension: OnceCell::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    async fn dimension(&self) -> Result<usize> {
+        probe_dimension(self, &self.dimension).await
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f64>>> {
+        // The embeddings endpoint takes one prompt per request.
+        try_join_all(texts.iter().map(|text| async {
+            self.ollama
+                .generate_embeddings(self.model.clone(), text.clone(), None)
+                .await
+                .map(|res| res.embeddings)
+                .map_err(|e| Error::Embedding(format!("Failed to generate embedding: {}", e)))
+        }))
+        .await
+    }
+}
+
+/// Any server implementing the OpenAI `/v1/embeddings` API.
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    dimension: OnceCell<usize>,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(base_url: &str, api_key: Option<String>, model: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+            dimension: OnceCell::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbeddingResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    index: usize,
+    embedding: Vec<f64>,
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    async fn dimension(&self) -> Result<usize> {
+        probe_dimension(self, &self.dimension).await
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f64>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut builder = self
+            .client
+            .post(format!("{}/embeddings", self.base_url))
+            .json(&serde_json::json!({
+                "model": self.model,
+                "input": texts,
+            }));
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| Error::Embedding(format!("Failed to send request: {}", e)))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(Error::Embedding(format!(
+                "Embedding request failed ({}): {}",
+                status, text
+            )));
+        }
+
+        let mut data = response
+            .json::<OpenAiEmbeddingResponse>()
+            .await
+            .map_err(|e| Error::Embedding(format!("Failed to decode embeddings: {}", e)))?
+            .data;
+        if data.len() != texts.len() {
+            return Err(Error::Embedding(format!(
+                "Expected {} embeddings, got {}",
+                texts.len(),
+                data.len()
+            )));
+        }
+        data.sort_by_key(|e| e.index);
+
+        Ok(data.into_iter().map(|e| e.embedding).collect())
+    }
+}
+
+/// Deterministic feature-hashing embedder for tests and offline runs.
+///
+/// Each lowercase word is hashed into one of `dimension` signed buckets and
+/// the result is L2-normalised, so texts sharing words have a positive
+/// cosine similarity.
+pub struct HashEmbedder {
+    model_id: String,
+    dimension: usize,
+}
+
+impl HashEmbedder {
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            model_id: format!("hash-{}", dimension),
+            dimension,
+        }
+    }
+
+    fn embed_text(&self, text: &str) -> Vec<f64> {
+        let mut vector = vec![0.0; self.dimension];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+        {
+            // FNV-1a, stable across platforms and Rust versions
+            let hash = word
+                .to_lowercase()
+                .bytes()
+                .fold(0xcbf29ce484222325u64, |h, b| {
+                    (h ^ b as u64).wrapping_mul(0x100000001b3)
+                });
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimension as u64) as usize] += sign;
+        }
+
+        let magnitude = vector.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if magnitude > 0.0 {
+            vector.iter_mut().for_each(|v| *v /= magnitude);
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl Embedder for HashEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    async fn dimension(&self) -> Result<usize> {
+        Ok(self.dimension)
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f64>>> {
+        Ok(texts.iter().map(|text| self.embed_text(text)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cosine(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+
+    #[tokio::test]
+    async fn hash_embeddings_are_deterministic_and_normalized() {
+        let embedder = HashEmbedder::new(64);
+        assert_eq!(embedder.model_id(), "hash-64");
+        assert_eq!(embedder.dimension().await.unwrap(), 64);
+
+        let texts = [
+            "Rust borrow checker".to_string(),
+            "the BORROW checker of rust".to_string(),
+            "baking bread at home".to_string(),
+            String::new(),
+        ];
+        let vectors = embedder.embed_batch(&texts).await.unwrap();
+        assert_eq!(
+            vectors,
+            HashEmbedder::new(64).embed_batch(&texts).await.unwrap()
+        );
+        assert!(vectors.iter().all(|vector| vector.len() == 64));
+        assert!((cosine(&vectors[0], &vectors[0]) - 1.0).abs() < 1e-9);
+
+        // Shared words, whatever their case, make texts similar
+        assert!(cosine(&vectors[0], &vectors[1]) > cosine(&vectors[0], &vectors[2]));
+        assert_eq!(vectors[3], vec![0.0; 64]);
+        assert_eq!(
+            embedder.embed("Rust borrow checker").await.unwrap(),
+            vectors[0]
+        );
+    }
+}
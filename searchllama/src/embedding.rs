@@ -1,36 +1,70 @@
-use std::sync::Arc;
-
-use cached::proc_macro::{cached, io_cached};
-use cached::DiskCache;
+use cached::proc_macro::io_cached;
+use cached::{DiskCache, IOCached};
 use lazy_static::lazy_static;
-use log::{info, warn};
-use ollama_rs::generation::options::GenerationOptions;
-use playwright::{
-    api::{Browser, BrowserContext, BrowserType},
-    Playwright,
-};
-use pollster::FutureExt;
+use log::warn;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, Semaphore};
 
-use crate::{embedding, EMBEDDING_MODEL, G_OLLAMA, MAX_EMBEDDING_SIZE};
+use crate::{
+    error::{Error, Result},
+    fetch, AppState,
+};
 
-#[io_cached(
-    map_error = r##" | e | { format!("Failed to cache: {}", e) }"##,
-    disk = true,
-    convert = r#"{ format!("{}", text) }"#,
-    ty = "DiskCache<String, Vec<f64>>"
-)]
-pub async fn generate_embedding(text: &str) -> Result<Vec<f64>, String> {
-    Ok(G_OLLAMA
-        .generate_embeddings(
-            EMBEDDING_MODEL.to_string(),
-            text.to_string(),
-            None, //Some(GenerationOptions::default().num_gpu(0)),
-        )
-        .await
-        .map_err(|e| format!("Failed to generate embedding: {}", e))?
-        .embeddings)
+lazy_static! {
+    static ref EMBEDDING_CACHE: DiskCache<String, Vec<f64>> = DiskCache::new("GENERATE_EMBEDDING")
+        .build()
+        .expect("Failed to open embedding cache");
+}
+
+/// Cache key for an embedding. It includes the model id so that switching
+/// models never returns vectors produced by another model. The id is length
+/// prefixed, as ids such as `nomic-embed-text:latest` contain separators.
+fn embedding_cache_key(model_id: &str, text: &str) -> String {
+    format!("{}:{}:{}", model_id.len(), model_id, text)
+}
+
+pub async fn generate_embedding(state: &AppState, text: &str) -> Result<Vec<f64>> {
+    Ok(generate_embeddings(state, &[text.to_string()])
+        .await?
+        .remove(0))
+}
+
+/// Embeds `texts` in one batch, skipping the ones that are already cached.
+pub async fn generate_embeddings(state: &AppState, texts: &[String]) -> Result<Vec<Vec<f64>>> {
+    let mut embeddings = Vec::with_capacity(texts.len());
+    let mut missing = Vec::new();
+    for (idx, text) in texts.iter().enumerate() {
+        let cached = EMBEDDING_CACHE
+            .cache_get(&embedding_cache_key(state.embedder.model_id(), text))
+            .map_err(|e| Error::Cache(e.to_string()))?;
+        if cached.is_none() {
+            missing.push(idx);
+        }
+        embeddings.push(cached);
+    }
+
+    if !missing.is_empty() {
+        let missing_texts = missing
+            .iter()
+            .map(|&idx| texts[idx].clone())
+            .collect::<Vec<String>>();
+        let generated = state.embedder.embed_batch(&missing_texts).await?;
+        for (idx, embedding) in missing.into_iter().zip(generated) {
+            EMBEDDING_CACHE
+                .cache_set(
+                    embedding_cache_key(state.embedder.model_id(), &texts[idx]),
+                    embedding.clone(),
+                )
+                .map_err(|e| Error::Cache(e.to_string()))?;
+            embeddings[idx] = Some(embedding);
+        }
+    }
+
+    embeddings
+        .into_iter()
+        .map(|e| {
+            e.ok_or_else(|| Error::Embedding("Embedder returned too few embeddings".to_string()))
+        })
+        .collect()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -39,10 +73,11 @@ pub struct LargeEmbedding {
     pub texts: Vec<String>,
 }
 pub async fn generate_large_embedding(
+    state: &AppState,
     text: &str,
     chunk_size: Option<usize>,
-) -> Result<LargeEmbedding, String> {
-    let chunk_size = chunk_size.unwrap_or(MAX_EMBEDDING_SIZE);
+) -> Result<LargeEmbedding> {
+    let chunk_size = chunk_size.unwrap_or(state.config.search.max_embedding_size);
     let chars = text.chars().collect::<Vec<char>>();
     let mut char_chunks: Vec<String> = Vec::new();
     let mut start_idx = 0;
@@ -66,11 +101,7 @@ pub async fn generate_large_embedding(
         start_idx = split_idx;
     }
 
-    let mut embeddings: Vec<Vec<f64>> = Vec::new();
-    for chunk in char_chunks.clone() {
-        let embedding = generate_embedding(&chunk).await?;
-        embeddings.push(embedding);
-    }
+    let embeddings = generate_embeddings(state, &char_chunks).await?;
 
     Ok(LargeEmbedding {
         embeddings,
@@ -87,104 +118,38 @@ pub struct WebsiteEmbedding {
     pub link: String,
 }
 
-pub async fn get_website_embedding(
-    url: &str,
-    pw_context: Arc<BrowserContext>,
-) -> Result<WebsiteEmbedding, String> {
-    lazy_static! {
-        static ref PW_SEMAPHORE: Semaphore = Semaphore::new(8);
-    }
-
-    #[io_cached(
-        map_error = r##" | e | { format!("Failed to cache: {}", e) }"##,
-        disk = true,
-        convert = r#"{ format!("{}", url) }"#,
-        ty = "DiskCache<String, WebsiteEmbedding>"
-    )]
-    async fn get_website_embedding_cached(
-        url: &str,
-        pw_context: Arc<BrowserContext>,
-    ) -> Result<WebsiteEmbedding, String> {
-        let page = pw_context
-            .new_page()
-            .await
-            .map_err(|e| format!("Failed to create page: {}", e))?;
-        if page
-            .goto_builder(&url)
-            .timeout(15000.0)
-            .wait_until(playwright::api::DocumentLoadState::NetworkIdle)
-            .goto()
-            .await
-            .is_err()
-        {
-            page.close(None)
-                .await
-                .map_err(|_| "Failed to navigate to URL".to_string())?;
-            return Err("Failed to navigate to URL".to_string());
-        }
-
-        //page.wait_for_timeout(10000.0).await;
-
-        let text_content: String = page
-            .eval("document.body.innerText")
-            .await
-            .map_err(|e| format!("Failed to evaluate JS: {}", e))?;
-
-        let image_data: Vec<(String, Option<String>)> = page
-            .eval(
-                "
-                Array.from(document.querySelectorAll('img')).map((img) => {
-                    return [img.src, img.alt || img.title || null];
-                })
-            ",
-            )
-            .await
-            .map_err(|e| format!("Failed to evaluate JS: {}", e))?;
-
-        //info!("image links: {:?}", image_data);
-
-        page.close(None).await.expect("Failed to close page");
-
-        let res = generate_large_embedding(&text_content, None).await?;
-        let embedding = WebsiteEmbedding {
-            embeddings: res.embeddings,
-            url: url.to_string(),
-            texts: res.texts,
-            images: image_data
-                .into_iter()
-                .map(|x| (x.0, x.1.unwrap_or_default()))
-                .filter(|x| {
-                    x.0.len() < 256
-                        && !x.0.is_empty()
-                        && x.1.len() < 256
-                        && !x.1.is_empty()
-                        && x.0.starts_with("http")
-                })
-                .collect(),
-            link: url.to_string(),
-        };
-
-        Ok(embedding)
-    }
-
-    // let _permit = PW_SEMAPHORE
-    //     .acquire()
-    //     .await
-    //     .expect("Failed to acquire semaphore");
-
-    get_website_embedding_cached(url.clone(), pw_context).await
+/// Cached per model and chunk size, as both change the stored chunks.
+#[io_cached(
+    map_error = r##" | e | { Error::Cache(e.to_string()) }"##,
+    disk = true,
+    convert = r#"{ embedding_cache_key(state.embedder.model_id(), &format!("{}:{}", state.config.search.max_embedding_size, url)) }"#,
+    ty = "DiskCache<String, WebsiteEmbedding>"
+)]
+pub async fn get_website_embedding(state: &AppState, url: &str) -> Result<WebsiteEmbedding> {
+    let page = fetch::fetch_page(state, url).await?;
+
+    let res = generate_large_embedding(state, &page.text, None).await?;
+    let embedding = WebsiteEmbedding {
+        embeddings: res.embeddings,
+        url: url.to_string(),
+        texts: res.texts,
+        images: page.images,
+        link: url.to_string(),
+    };
+
+    Ok(embedding)
 }
 
 //#[io_cached(
-//    map_error = r##" | e | { format!("Failed to cache: {}", e) }"##,
+//    map_error = r##" | e | { Error::Cache(e.to_string()) }"##,
 //    disk = true,
 //    convert = r#"{ format!("{:?}", vec1) }"#,
 //    ty = "DiskCache<String, f64>"
 //)]
-pub fn vec_cos_sim(vec1: &[f64], vec2: &[f64]) -> Result<f64, String> {
+pub fn vec_cos_sim(vec1: &[f64], vec2: &[f64]) -> Result<f64> {
     if vec1.len() != vec2.len() {
         warn!("Vector lengths do not match");
-        return Err("Vector lengths do not match".to_string());
+        return Err(Error::Embedding("Vector lengths do not match".to_string()));
     }
 
     let mut dot_product = 0.0;
@@ -206,3 +171,25 @@ pub fn vec_cos_sim(vec1: &[f64], vec2: &[f64]) -> Result<f64, String> {
 
     Ok(dot_product / (vec1_magnitude * vec2_magnitude))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::embedder::{Embedder, HashEmbedder};
+
+    use super::*;
+
+    #[test]
+    fn cache_keys_depend_on_the_model() {
+        let small = HashEmbedder::new(8);
+        let large = HashEmbedder::new(16);
+        let key = |embedder: &HashEmbedder, text| embedding_cache_key(embedder.model_id(), text);
+        assert_eq!(key(&small, "text"), key(&HashEmbedder::new(8), "text"));
+        assert_ne!(key(&small, "text"), key(&large, "text"));
+        assert_ne!(key(&small, "text"), key(&small, "other text"));
+        // Separators in the model id cannot move text into it
+        assert_ne!(
+            embedding_cache_key("a:b", "c"),
+            embedding_cache_key("a", "b:c")
+        );
+    }
+}
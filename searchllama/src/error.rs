@@ -0,0 +1,41 @@
+use thiserror::Error;
+use warp::{http::StatusCode, reply::Response, Reply};
+
+/// Errors raised by the search and chat pipelines.
+///
+/// The variant names the subsystem that failed; the message is meant to be
+/// shown to the client as is.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Llm(String),
+    #[error("{0}")]
+    Embedding(String),
+    #[error("{0}")]
+    Search(String),
+    #[error("{0}")]
+    Fetch(String),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Cache error: {0}")]
+    Cache(String),
+    #[error("Task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    /// The database has a schema this build cannot use.
+    #[error("Unsupported database schema: {0}")]
+    Schema(String),
+    /// A session or other record the client referred to does not exist.
+    #[error("{0}")]
+    NotFound(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A `{"error": message}` reply for the JSON endpoints.
+pub fn error_reply(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status,
+    )
+    .into_response()
+}
@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use log::debug;
+use playwright::api::{BrowserContext, Page};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::{
+    browser_pool::BrowserPool,
+    config::FetchMode,
+    error::{Error, Result},
+    AppState,
+};
+
+const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+
+/// Elements that never contain page content.
+const SKIP_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "nav", "header", "footer", "aside", "form",
+    "iframe", "svg", "button", "select", "textarea", "canvas", "dialog",
+];
+/// Class and id words that mark navigation and other boilerplate.
+const BOILERPLATE_HINTS: &[&str] = &[
+    "nav",
+    "menu",
+    "footer",
+    "sidebar",
+    "comment",
+    "cookie",
+    "consent",
+    "banner",
+    "advert",
+    "promo",
+    "share",
+    "social",
+    "related",
+    "breadcrumb",
+    "subscribe",
+    "newsletter",
+    "popup",
+    "modal",
+];
+/// Elements that start a new line in the extracted text.
+const BLOCK_TAGS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "li",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "blockquote",
+    "table",
+    "tr",
+    "br",
+    "figcaption",
+    "hr",
+];
+/// Minimum length of a paragraph for it to count towards its container.
+const MIN_PARAGRAPH_LEN: usize = 25;
+/// Bytes of a page read over HTTP; the rest is dropped.
+const MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+/// Thin HTTP pages from a domain before it goes straight to Playwright.
+const JS_DOMAIN_MISSES: u32 = 2;
+/// How long a domain is remembered after its last thin page.
+const JS_DOMAIN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Domains remembered at once; the least recently seen is forgotten first.
+const MAX_JS_DOMAINS: usize = 1024;
+
+lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("Failed to build HTTP client");
+    static ref PARAGRAPHS: Selector = Selector::parse("p, pre, td, blockquote").unwrap();
+    static ref LINKS: Selector = Selector::parse("a").unwrap();
+    static ref IMAGES: Selector = Selector::parse("img").unwrap();
+    static ref FALLBACK_CONTAINERS: Selector = Selector::parse("article, main, body").unwrap();
+    static ref JS_DOMAINS: Mutex<JsDomains> = Mutex::new(JsDomains::default());
+}
+
+/// Domains where the HTTP fetcher did not find enough content.
+#[derive(Debug, Default)]
+struct JsDomains {
+    /// Thin pages and when the last one was seen.
+    domains: HashMap<String, (u32, Instant)>,
+}
+
+impl JsDomains {
+    /// Whether pages of `domain` were thin often and recently enough to skip
+    /// the HTTP fetch.
+    fn needs_js(&self, domain: &str, now: Instant) -> bool {
+        self.domains.get(domain).is_some_and(|&(misses, seen)| {
+            misses >= JS_DOMAIN_MISSES && now.duration_since(seen) < JS_DOMAIN_TTL
+        })
+    }
+
+    fn record_miss(&mut self, domain: String, now: Instant) {
+        self.domains
+            .retain(|_, (_, seen)| now.duration_since(*seen) < JS_DOMAIN_TTL);
+        if !self.domains.contains_key(&domain) && self.domains.len() >= MAX_JS_DOMAINS {
+            let oldest = self
+                .domains
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(domain, _)| domain.clone());
+            if let Some(oldest) = oldest {
+                self.domains.remove(&oldest);
+            }
+        }
+        let entry = self.domains.entry(domain).or_insert((0, now));
+        *entry = (entry.0 + 1, now);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PageContent {
+    pub text: String,
+    /// `(src, alt)` pairs.
+    pub images: Vec<(String, String)>,
+}
+
+/// Fetches the readable content of `url`, with plain HTTP or Playwright
+/// depending on the configured mode and the domain.
+pub async fn fetch_page(state: &AppState, url: &str) -> Result<PageContent> {
+    let browsers = state.browsers.as_deref();
+    let config = &state.config.fetcher;
+    let domain = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+        .unwrap_or_default();
+
+    let use_http = match config.mode {
+        FetchMode::Http => true,
+        FetchMode::Playwright => false,
+        FetchMode::Auto => {
+            browsers.is_none()
+                || !(config
+                    .playwright_domains
+                    .iter()
+                    .any(|d| domain_matches(&domain, d))
+                    || JS_DOMAINS.lock().unwrap().needs_js(&domain, Instant::now()))
+        }
+    };
+
+    let mut http_page = None;
+    if use_http {
+        let timeout = Duration::from_secs(config.timeout_secs);
+        match fetch_with_http(url, timeout).await {
+            Ok(page) if page.text.chars().count() >= config.min_text_length => return Ok(page),
+            Ok(page) if browsers.is_none() => return Ok(page),
+            Err(e) if browsers.is_none() => return Err(e),
+            Ok(page) => {
+                debug!("Too little content over HTTP, using Playwright: {}", url);
+                JS_DOMAINS
+                    .lock()
+                    .unwrap()
+                    .record_miss(domain, Instant::now());
+                http_page = Some(page);
+            }
+            Err(e) => debug!("HTTP fetch failed, using Playwright: {}: {}", url, e),
+        }
+    }
+
+    let Some(browsers) = browsers else {
+        return Err(Error::Fetch("Playwright is not available".to_string()));
+    };
+    match (fetch_with_playwright(url, browsers).await, http_page) {
+        (Err(e), Some(page)) => {
+            debug!("Playwright failed, using the HTTP result: {}: {}", url, e);
+            Ok(page)
+        }
+        (result, _) => result,
+    }
+}
+
+pub fn domain_matches(domain: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches('.').to_lowercase();
+    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+pub async fn fetch_with_http(url: &str, timeout: Duration) -> Result<PageContent> {
+    let response = HTTP_CLIENT
+        .get(url)
+        .timeout(timeout)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| Error::Fetch(format!("Failed to fetch {}: {}", url, e)))?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if !content_type.is_empty() && !content_type.contains("html") {
+        return Err(Error::Fetch(format!(
+            "Unsupported content type: {}",
+            content_type
+        )));
+    }
+
+    let base = response.url().clone();
+    let html = read_body(response, MAX_BODY_BYTES)
+        .await
+        .map_err(|e| Error::Fetch(format!("Failed to read {}: {}", url, e)))?;
+
+    Ok(extract_content(&html, &base))
+}
+
+/// The body of `response` as text, cut after `limit` bytes.
+async fn read_body(mut response: reqwest::Response, limit: usize) -> reqwest::Result<String> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        let take = chunk.len().min(limit - body.len());
+        body.extend_from_slice(&chunk[..take]);
+        if body.len() >= limit {
+            debug!("Page truncated to {} bytes: {}", limit, response.url());
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Closes the page when dropped, including when the fetch is cancelled.
+struct PageGuard(Page);
+
+impl Drop for PageGuard {
+    fn drop(&mut self) {
+        let page = self.0.clone();
+        tokio::spawn(async move {
+            let _ = page.close(None).await;
+        });
+    }
+}
+
+pub async fn fetch_with_playwright(url: &str, browsers: &BrowserPool) -> Result<PageContent> {
+    let lease = browsers.acquire().await?;
+    let result = render_page(url, &lease.context, browsers.navigation_timeout()).await;
+    if result.is_err() {
+        browsers.check(&lease).await;
+    }
+
+    result
+}
+
+async fn render_page(
+    url: &str,
+    context: &BrowserContext,
+    timeout: Duration,
+) -> Result<PageContent> {
+    let page = PageGuard(
+        context
+            .new_page()
+            .await
+            .map_err(|e| Error::Fetch(format!("Failed to create page: {}", e)))?,
+    );
+    let page = &page.0;
+    if page
+        .goto_builder(url)
+        .timeout(timeout.as_millis() as f64)
+        .wait_until(playwright::api::DocumentLoadState::NetworkIdle)
+        .goto()
+        .await
+        .is_err()
+    {
+        return Err(Error::Fetch("Failed to navigate to URL".to_string()));
+    }
+
+    let text: String = page
+        .eval("document.body.innerText")
+        .await
+        .map_err(|e| Error::Fetch(format!("Failed to evaluate JS: {}", e)))?;
+
+    let image_data: Vec<(String, Option<String>)> = page
+        .eval(
+            "
+            Array.from(document.querySelectorAll('img')).map((img) => {
+                return [img.src, img.alt || img.title || null];
+            })
+        ",
+        )
+        .await
+        .map_err(|e| Error::Fetch(format!("Failed to evaluate JS: {}", e)))?;
+
+    Ok(PageContent {
+        text,
+        images: filter_images(
+            image_data
+                .into_iter()
+                .map(|x| (x.0, x.1.unwrap_or_default())),
+        ),
+    })
+}
+
+fn filter_images(images: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    images
+        .filter(|x| {
+            x.0.len() < 256
+                && !x.0.is_empty()
+                && x.1.len() < 256
+                && !x.1.is_empty()
+                && x.0.starts_with("http")
+        })
+        .collect()
+}
+
+/// Extracts the main text of a page, readability style: paragraphs score
+/// their parent and grandparent, the best container wins after a link
+/// density penalty, and navigation and boilerplate are skipped.
+pub fn extract_content(html: &str, base: &Url) -> PageContent {
+    let document = Html::parse_document(html);
+
+    let mut scores: HashMap<_, f64> = HashMap::new();
+    for paragraph in document.select(&PARAGRAPHS) {
+        if paragraph
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .any(|a| is_boilerplate(&a))
+        {
+            continue;
+        }
+        let text = paragraph.text().collect::<String>();
+        let len = text.trim().chars().count();
+        if len < MIN_PARAGRAPH_LEN {
+            continue;
+        }
+
+        let score = 1.0 + text.matches(',').count() as f64 + (len as f64 / 100.0).min(3.0);
+        let mut ancestors = paragraph.ancestors().filter_map(ElementRef::wrap);
+        if let Some(parent) = ancestors.next() {
+            *scores.entry(parent.id()).or_default() += score;
+        }
+        if let Some(grandparent) = ancestors.next() {
+            *scores.entry(grandparent.id()).or_default() += score / 2.0;
+        }
+    }
+
+    let candidate = scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = ElementRef::wrap(document.tree.get(id)?)?;
+            Some((element, score * (1.0 - link_density(&element))))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(element, _)| element)
+        .or_else(|| document.select(&FALLBACK_CONTAINERS).next());
+
+    let text = match candidate {
+        Some(element) => {
+            let mut raw = String::new();
+            collect_text(element, &mut raw);
+            raw.lines()
+                .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        None => String::new(),
+    };
+
+    let images = filter_images(document.select(&IMAGES).filter_map(|img| {
+        let src = base.join(img.value().attr("src")?).ok()?;
+        let alt = img
+            .value()
+            .attr("alt")
+            .or_else(|| img.value().attr("title"))
+            .unwrap_or_default();
+        Some((src.to_string(), alt.to_string()))
+    }));
+
+    PageContent { text, images }
+}
+
+fn is_boilerplate(element: &ElementRef) -> bool {
+    let value = element.value();
+    let name = value.name();
+    if SKIP_TAGS.contains(&name) {
+        return true;
+    }
+    if matches!(name, "html" | "body" | "main" | "article") {
+        return false;
+    }
+    if matches!(
+        value.attr("role"),
+        Some("navigation" | "banner" | "contentinfo" | "complementary")
+    ) || value.attr("aria-hidden") == Some("true")
+        || value.attr("hidden").is_some()
+    {
+        return true;
+    }
+
+    let hints = format!(
+        "{} {}",
+        value.attr("class").unwrap_or_default(),
+        value.attr("id").unwrap_or_default()
+    )
+    .to_lowercase();
+    hints
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| BOILERPLATE_HINTS.iter().any(|hint| word.starts_with(hint)))
+}
+
+fn link_density(element: &ElementRef) -> f64 {
+    let total = element.text().map(str::len).sum::<usize>();
+    if total == 0 {
+        return 1.0;
+    }
+    let links = element
+        .select(&LINKS)
+        .flat_map(|a| a.text())
+        .map(str::len)
+        .sum::<usize>();
+
+    links as f64 / total as f64
+}
+
+fn collect_text(element: ElementRef, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => {
+                out.push_str(text);
+                out.push(' ');
+            }
+            Node::Element(_) => {
+                let Some(child) = ElementRef::wrap(child) else {
+                    continue;
+                };
+                if is_boilerplate(&child) {
+                    continue;
+                }
+                let block = BLOCK_TAGS.contains(&child.value().name());
+                if block {
+                    out.push('\n');
+                }
+                collect_text(child, out);
+                if block {
+                    out.push('\n');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    const ARTICLE: &str = include_str!("../fixtures/pages/article.html");
+
+    #[test]
+    fn extracts_the_article_without_boilerplate() {
+        let base = Url::parse("https://blog.example.com/posts/borrowing").unwrap();
+        let page = extract_content(ARTICLE, &base);
+
+        assert!(page.text.starts_with("Understanding the borrow checker"));
+        assert!(page
+            .text
+            .contains("Every reference has a lifetime, the scope for which it is valid,"));
+        for boilerplate in [
+            "Home",
+            "Subscribe to our newsletter",
+            "We use cookies",
+            "Related posts",
+            "Great post",
+            "All rights reserved",
+            "tracking()",
+        ] {
+            assert!(
+                !page.text.contains(boilerplate),
+                "{:?} in {:?}",
+                boilerplate,
+                page.text
+            );
+        }
+        assert_eq!(
+            page.images,
+            vec![(
+                "https://blog.example.com/images/borrows.png".to_string(),
+                "Two borrows of the same value".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn js_domains_need_repeated_misses_and_expire() {
+        let now = Instant::now();
+        let mut domains = JsDomains::default();
+        domains.record_miss("app.example.com".to_string(), now);
+        assert!(!domains.needs_js("app.example.com", now));
+        domains.record_miss("app.example.com".to_string(), now);
+        assert!(domains.needs_js("app.example.com", now));
+        assert!(!domains.needs_js("app.example.com", now + JS_DOMAIN_TTL));
+
+        for i in 0..MAX_JS_DOMAINS + 10 {
+            domains.record_miss(format!("{}.example.com", i), now + Duration::from_secs(1));
+        }
+        assert_eq!(domains.domains.len(), MAX_JS_DOMAINS);
+        assert!(!domains.domains.contains_key("app.example.com"));
+    }
+
+    #[tokio::test]
+    async fn large_pages_are_cut() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n",
+            );
+            let chunk = [b'a'; 64 * 1024];
+            while stream.write_all(&chunk).is_ok() {}
+        });
+
+        let response = HTTP_CLIENT.get(&url).send().await.unwrap();
+        let body = read_body(response, 100_000).await.unwrap();
+        assert_eq!(body.len(), 100_000);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use log::{info, warn};
+use searchllama_types::types::{
+    upsert_entry, HistoryEntry, SearchEvent, SearchFrame, SearchRequest,
+};
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, Sender};
+use tokio_util::sync::CancellationToken;
+use warp::{http::StatusCode, reply::Response, Reply};
+
+use crate::{
+    database,
+    error::{error_reply, Error},
+    handle_search_request, sse, AppState,
+};
+
+/// Entries returned by `GET /history` unless a limit is given.
+const DEFAULT_LIMIT: usize = 50;
+/// Most entries returned by one `GET /history`.
+const MAX_LIMIT: usize = 200;
+
+/// Records the search run with `request` once its pipeline is done.
+///
+/// Returns the sender the pipeline should use: frames pass through to
+/// `sender` and are collected on the way. Private searches, searches that
+/// could not run and searches cancelled by `token` are not recorded.
+pub fn record(
+    state: &Arc<AppState>,
+    request: &SearchRequest,
+    sender: Sender<SearchFrame>,
+    failed: Arc<AtomicBool>,
+    token: CancellationToken,
+) -> Sender<SearchFrame> {
+    if request.private.unwrap_or(false) {
+        return sender;
+    }
+
+    let (tap, mut frames) = mpsc::channel::<SearchFrame>(10);
+    let state = state.clone();
+    let mut entry = HistoryEntry {
+        id: uuid::Uuid::new_v4().to_string(),
+        request: request.clone(),
+        created_at: chrono::Utc::now().timestamp(),
+        session_id: None,
+        results: Vec::new(),
+        answer: String::new(),
+    };
+    tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            match &frame.event {
+                SearchEvent::Session { session_id } => entry.session_id = Some(session_id.clone()),
+                SearchEvent::CachedResults { results } => {
+                    for result in results {
+                        upsert_entry(&mut entry.results, result.clone());
+                    }
+                }
+                SearchEvent::ResultUpsert { entry: result } => {
+                    upsert_entry(&mut entry.results, result.clone())
+                }
+                SearchEvent::AnswerToken { text } => entry.answer.push_str(text),
+                _ => {}
+            }
+            // Fails once the client went away
+            let _ = sender.send(frame).await;
+        }
+
+        // The client's stream cannot end before `sender` is dropped, so a
+        // cancelled token means the search was abandoned half way
+        if failed.load(Ordering::Relaxed) || token.is_cancelled() {
+            return;
+        }
+        entry.results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        if let Err(e) = database::save_search(&state.db, &entry).await {
+            warn!("Failed to record search: {}", e);
+        }
+    });
+
+    tap
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+fn database_error(e: Error) -> Response {
+    warn!("History request failed: {}", e);
+    error_reply(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+}
+
+fn not_found(id: &str) -> Response {
+    error_reply(
+        StatusCode::NOT_FOUND,
+        &format!("Unknown history entry: {}", id),
+    )
+}
+
+/// `GET /history`
+pub async fn list(state: Arc<AppState>, query: HistoryQuery) -> Result<Response, Infallible> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    Ok(
+        match database::list_searches(&state.db, limit, offset).await {
+            Ok(entries) => warp::reply::json(&entries).into_response(),
+            Err(e) => database_error(e),
+        },
+    )
+}
+
+/// `GET /history/{id}`
+pub async fn get(id: String, state: Arc<AppState>) -> Result<Response, Infallible> {
+    Ok(match database::load_search(&state.db, &id).await {
+        Ok(Some(entry)) => warp::reply::json(&entry).into_response(),
+        Ok(None) => not_found(&id),
+        Err(e) => database_error(e),
+    })
+}
+
+/// `DELETE /history/{id}`
+pub async fn delete(id: String, state: Arc<AppState>) -> Result<Response, Infallible> {
+    Ok(match database::delete_search(&state.db, &id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => not_found(&id),
+        Err(e) => database_error(e),
+    })
+}
+
+/// `POST /history/{id}/rerun`: runs the search again with the same options,
+/// streamed like `/search`.
+pub async fn rerun(id: String, state: Arc<AppState>) -> Result<Response, Infallible> {
+    let entry = match database::load_search(&state.db, &id).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return Ok(not_found(&id)),
+        Err(e) => return Ok(database_error(e)),
+    };
+    info!("Running search {} again: {:?}", id, entry.request);
+
+    let events = handle_search_request(state, entry.request).await;
+    Ok(sse::reply(events).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use searchllama_types::types::{Entry, Status};
+
+    use super::*;
+
+    fn entry(url: &str, score: f64) -> SearchFrame {
+        SearchEvent::ResultUpsert {
+            entry: Entry {
+                score,
+                url: url.to_string(),
+                title: url.to_uppercase(),
+                description: String::new(),
+            },
+        }
+        .into()
+    }
+
+    /// Sends the frames of a search through `record`, and waits until it is
+    /// done with them.
+    async fn record_search(state: &Arc<AppState>, request: SearchRequest, cancel: bool) {
+        let (sender, mut receiver) = mpsc::channel(10);
+        let token = CancellationToken::new();
+        let tap = record(
+            state,
+            &request,
+            sender,
+            Arc::new(AtomicBool::new(false)),
+            token.clone(),
+        );
+        let frames = [
+            entry("a", 0.2),
+            entry("b", 0.9),
+            SearchEvent::AnswerToken { text: "An ".into() }.into(),
+            SearchEvent::AnswerToken {
+                text: "answer".into(),
+            }
+            .into(),
+        ];
+        for frame in frames {
+            tap.send(frame).await.unwrap();
+        }
+        if cancel {
+            token.cancel();
+        } else {
+            tap.send(
+                SearchEvent::Done {
+                    status: Status::Done,
+                }
+                .into(),
+            )
+            .await
+            .unwrap();
+        }
+        drop(tap);
+        // Closes once the recording is saved
+        while receiver.recv().await.is_some() {}
+    }
+
+    #[tokio::test]
+    async fn records_finished_searches_only() {
+        let state = Arc::new(crate::AppState::offline(&["Unused."], "{}").await);
+
+        record_search(&state, SearchRequest::new("borrow checker"), false).await;
+        let private = SearchRequest {
+            private: Some(true),
+            ..SearchRequest::new("private")
+        };
+        record_search(&state, private, false).await;
+        record_search(&state, SearchRequest::new("cancelled"), true).await;
+
+        let entries = database::list_searches(&state.db, DEFAULT_LIMIT, 0)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].request.query, "borrow checker");
+        assert_eq!(entries[0].answer, "An answer");
+        let urls = entries[0]
+            .results
+            .iter()
+            .map(|e| e.url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(urls, vec!["b", "a"]);
+    }
+}
@@ -0,0 +1,145 @@
+//! BM25 scoring of page text, as SQLite's FTS5 computes it.
+//!
+//! Pages in the database are found through the `pages_fts` table, but pages
+//! being crawled are not in it yet, so the score is computed here for both,
+//! from the statistics of the table.
+
+use std::collections::HashMap;
+
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Splits text into lowercase terms like the `unicode61` tokenizer of
+/// `pages_fts`: runs of letters and digits, everything else separates them.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// The distinct terms of a query, in order.
+pub fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for term in tokenize(query) {
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+/// BM25 of a query against the pages in the database.
+#[derive(Debug, Clone, Default)]
+pub struct Bm25 {
+    /// Query terms with the number of pages containing each.
+    terms: Vec<(String, f64)>,
+    pages: f64,
+    average_length: f64,
+}
+
+impl Bm25 {
+    /// `documents` maps each query term to the number of pages containing it;
+    /// `tokens` is the number of terms in all pages together.
+    pub fn new(
+        terms: Vec<String>,
+        documents: &HashMap<String, i64>,
+        pages: i64,
+        tokens: i64,
+    ) -> Self {
+        Self {
+            terms: terms
+                .into_iter()
+                .map(|term| {
+                    let count = documents.get(&term).copied().unwrap_or(0) as f64;
+                    (term, count)
+                })
+                .collect(),
+            pages: pages as f64,
+            average_length: if pages > 0 {
+                tokens as f64 / pages as f64
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// An FTS5 query matching pages with any of the terms, or `None` without
+    /// terms. Each term is quoted so that none is read as an operator.
+    pub fn match_query(&self) -> Option<String> {
+        if self.terms.is_empty() {
+            return None;
+        }
+        Some(
+            self.terms
+                .iter()
+                .map(|(term, _)| format!("\"{}\"", term))
+                .collect::<Vec<String>>()
+                .join(" OR "),
+        )
+    }
+
+    /// Score of a page, 0 if it has none of the terms. Higher is better,
+    /// unlike the `bm25()` function of FTS5, which is negated.
+    pub fn score(&self, title: &str, text: &str) -> f64 {
+        if self.terms.is_empty() {
+            return 0.0;
+        }
+        let mut frequencies: HashMap<String, f64> = HashMap::new();
+        let mut length: f64 = 0.0;
+        for token in tokenize(title).chain(tokenize(text)) {
+            length += 1.0;
+            if self.terms.iter().any(|(term, _)| *term == token) {
+                *frequencies.entry(token).or_default() += 1.0;
+            }
+        }
+        // A page that is not in the database yet counts as one more
+        let pages = self.pages.max(1.0);
+        let average_length = if self.average_length > 0.0 {
+            self.average_length
+        } else {
+            length.max(1.0)
+        };
+
+        self.terms
+            .iter()
+            .map(|(term, documents)| {
+                let frequency = frequencies.get(term).copied().unwrap_or(0.0);
+                // FTS5 floors the IDF of terms in most pages the same way
+                let idf = ((pages - documents + 0.5) / (documents + 0.5))
+                    .ln()
+                    .max(1e-6);
+                idf * frequency * (K1 + 1.0)
+                    / (frequency + K1 * (1.0 - B + B * length / average_length))
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_identifiers_and_versions() {
+        assert_eq!(
+            tokenize("Error E0502 in tokio-1.38.0!").collect::<Vec<String>>(),
+            vec!["error", "e0502", "in", "tokio", "1", "38", "0"]
+        );
+        assert_eq!(query_terms("the THE cat"), vec!["the", "cat"]);
+
+        let bm25 = Bm25::new(query_terms("OR e0502"), &HashMap::new(), 0, 0);
+        assert_eq!(bm25.match_query().as_deref(), Some("\"or\" OR \"e0502\""));
+        assert_eq!(Bm25::default().match_query(), None);
+    }
+
+    #[test]
+    fn rare_terms_weigh_more() {
+        let documents = HashMap::from([("rust".to_string(), 90), ("e0502".to_string(), 2)]);
+        let bm25 = Bm25::new(query_terms("rust e0502"), &documents, 100, 10_000);
+
+        let common = bm25.score("Rust", "rust borrow checker");
+        let rare = bm25.score("Errors", "error e0502 explained");
+        assert!(rare > common && common > 0.0);
+        assert_eq!(bm25.score("Other", "nothing in common"), 0.0);
+    }
+}
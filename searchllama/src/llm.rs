@@ -0,0 +1,452 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use ollama_rs::{generation::completion::request::GenerationRequest, Ollama};
+use searchllama_types::sse::SseDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, LlmBackend},
+    error::{Error, Result},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "system" => Some(Role::System),
+            "user" => Some(Role::User),
+            "assistant" => Some(Role::Assistant),
+            _ => None,
+        }
+    }
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+/// The latest turns of `history` whose text fits in `max_chars`. It starts
+/// with a user message, so that no reply is sent without its question.
+pub fn recent_history(history: &[Message], max_chars: usize) -> &[Message] {
+    let mut start = history.len();
+    let mut chars = 0;
+    while start > 0 {
+        chars += history[start - 1].content.chars().count();
+        if chars > max_chars {
+            break;
+        }
+        start -= 1;
+    }
+    while history.get(start).is_some_and(|m| m.role != Role::User) {
+        start += 1;
+    }
+    &history[start..]
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+}
+
+impl CompletionRequest {
+    pub fn new(model: impl Into<String>, prompt: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            messages: vec![Message::new(Role::User, prompt)],
+        }
+    }
+
+    pub fn system(mut self, system: impl Into<String>) -> Self {
+        self.messages.insert(0, Message::new(Role::System, system));
+        self
+    }
+
+    /// Inserts earlier turns of the conversation before the prompt.
+    pub fn history(mut self, history: Vec<Message>) -> Self {
+        let prompt = self.messages.pop();
+        self.messages.extend(history);
+        self.messages.extend(prompt);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompletionChunk {
+    pub text: String,
+}
+
+pub type CompletionStream = Pin<Box<dyn Stream<Item = Result<CompletionChunk>> + Send>>;
+
+/// A backend that turns a conversation into a stream of generated text.
+#[async_trait]
+pub trait LanguageModel: Send + Sync {
+    async fn complete_stream(&self, request: CompletionRequest) -> Result<CompletionStream>;
+
+    async fn complete(&self, request: CompletionRequest) -> Result<String> {
+        let mut stream = self.complete_stream(request).await?;
+        let mut text = String::new();
+        while let Some(chunk) = stream.next().await {
+            text.push_str(&chunk?.text);
+        }
+        Ok(text)
+    }
+}
+
+pub fn from_config(config: &Config) -> Result<Arc<dyn LanguageModel>> {
+    Ok(match config.llm.backend {
+        LlmBackend::Ollama => Arc::new(OllamaModel::new(&config.ollama.url)?),
+        LlmBackend::OpenAi => Arc::new(OpenAiModel::new(
+            &config.openai.url,
+            config.openai.api_key.clone(),
+        )),
+        LlmBackend::Mock => Arc::new(ScriptedModel::new(config.mock.responses.clone())),
+    })
+}
+
+/// The system prompt and prompt of a conversation for the generate API of
+/// Ollama. It takes a single prompt, so earlier turns are rendered as a
+/// transcript in front of the last user message.
+fn ollama_prompt(messages: &[Message]) -> (String, String) {
+    let system = messages
+        .iter()
+        .filter(|m| m.role == Role::System)
+        .map(|m| m.content.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let turns = messages
+        .iter()
+        .filter(|m| m.role != Role::System)
+        .collect::<Vec<&Message>>();
+    let prompt = match turns.as_slice() {
+        [message] => message.content.clone(),
+        _ => turns
+            .iter()
+            .map(|m| match m.role {
+                Role::Assistant => format!("Assistant: {}", m.content),
+                _ => format!("User: {}", m.content),
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+    };
+    (system, prompt)
+}
+
+pub struct OllamaModel {
+    ollama: Ollama,
+}
+
+impl OllamaModel {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            ollama: Ollama::try_new(url)
+                .map_err(|e| Error::Llm(format!("Invalid Ollama URL: {}", e)))?,
+        })
+    }
+}
+
+#[async_trait]
+impl LanguageModel for OllamaModel {
+    async fn complete_stream(&self, request: CompletionRequest) -> Result<CompletionStream> {
+        let (system, prompt) = ollama_prompt(&request.messages);
+        let mut generation_request = GenerationRequest::new(request.model, prompt);
+        if !system.is_empty() {
+            generation_request = generation_request.system(system);
+        }
+
+        let stream = self
+            .ollama
+            .generate_stream(generation_request)
+            .await
+            .map_err(|e| Error::Llm(format!("Failed to generate response: {}", e)))?;
+
+        Ok(Box::pin(stream.map(|responses| {
+            let responses =
+                responses.map_err(|e| Error::Llm(format!("Failed to read response: {}", e)))?;
+            Ok(CompletionChunk {
+                text: responses.into_iter().map(|r| r.response).collect(),
+            })
+        })))
+    }
+}
+
+/// Any server implementing the OpenAI `/v1/chat/completions` API, such as
+/// llama.cpp, vLLM or LM Studio.
+pub struct OpenAiModel {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiModel {
+    pub fn new(base_url: &str, api_key: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiStreamChunk {
+    choices: Vec<OpenAiStreamChoice>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiStreamChoice {
+    delta: OpenAiDelta,
+}
+
+#[derive(Deserialize)]
+struct OpenAiDelta {
+    content: Option<String>,
+}
+
+/// Decodes the server-sent events of a streamed completion: one
+/// `data: {json}` event per chunk, terminated by `data: [DONE]`.
+#[derive(Debug, Default)]
+struct OpenAiStreamParser {
+    decoder: SseDecoder,
+    done: bool,
+}
+
+impl OpenAiStreamParser {
+    /// Feeds a chunk of the body and returns the completion chunks it
+    /// completed. Nothing is returned after `[DONE]`.
+    fn push(&mut self, data: &[u8]) -> Vec<Result<CompletionChunk>> {
+        let mut chunks = Vec::new();
+        for event in self.decoder.push(data) {
+            if self.done {
+                break;
+            }
+            if event.data.trim() == "[DONE]" {
+                self.done = true;
+                break;
+            }
+            chunks.push(
+                serde_json::from_str::<OpenAiStreamChunk>(&event.data)
+                    .map(|chunk| CompletionChunk {
+                        text: chunk
+                            .choices
+                            .into_iter()
+                            .filter_map(|choice| choice.delta.content)
+                            .collect(),
+                    })
+                    .map_err(|e| Error::Llm(format!("Failed to decode chunk: {}", e))),
+            );
+        }
+        chunks
+    }
+}
+
+#[async_trait]
+impl LanguageModel for OpenAiModel {
+    async fn complete_stream(&self, request: CompletionRequest) -> Result<CompletionStream> {
+        let body = serde_json::json!({
+            "model": request.model,
+            "messages": request.messages,
+            "stream": true,
+        });
+
+        let mut builder = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| Error::Llm(format!("Failed to send request: {}", e)))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(Error::Llm(format!(
+                "Completion request failed ({}): {}",
+                status, text
+            )));
+        }
+
+        // Events may be split across network packets, even inside a character
+        let bytes = Box::pin(response.bytes_stream());
+        let stream = stream::unfold(
+            (bytes, OpenAiStreamParser::default(), VecDeque::new(), false),
+            |(mut bytes, mut parser, mut pending, mut failed)| async move {
+                loop {
+                    if let Some(item) = pending.pop_front() {
+                        return Some((item, (bytes, parser, pending, failed)));
+                    }
+                    if parser.done || failed {
+                        return None;
+                    }
+                    match bytes.next().await {
+                        Some(Ok(data)) => pending.extend(parser.push(&data)),
+                        Some(Err(e)) => {
+                            failed = true;
+                            pending.push_back(Err(Error::Llm(format!(
+                                "Failed to read response: {}",
+                                e
+                            ))));
+                        }
+                        None => return None,
+                    }
+                }
+            },
+        );
+
+        Ok(Box::pin(stream))
+    }
+}
+
+/// Replays a fixed list of responses, one per completion, cycling when the
+/// list is exhausted. Used to run the pipeline without a model server.
+pub struct ScriptedModel {
+    responses: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl ScriptedModel {
+    pub fn new(responses: Vec<String>) -> Self {
+        Self {
+            responses,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl LanguageModel for ScriptedModel {
+    async fn complete_stream(&self, _request: CompletionRequest) -> Result<CompletionStream> {
+        if self.responses.is_empty() {
+            return Err(Error::Llm("Scripted model has no responses".to_string()));
+        }
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.responses.len();
+        let chunks = self.responses[idx]
+            .split_inclusive(' ')
+            .map(|text| {
+                Ok(CompletionChunk {
+                    text: text.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Box::pin(stream::iter(chunks)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(chunks: Vec<Result<CompletionChunk>>) -> Vec<String> {
+        chunks
+            .into_iter()
+            .map(|chunk| chunk.unwrap().text)
+            .collect()
+    }
+
+    #[test]
+    fn parses_deltas_split_inside_a_character() {
+        let body = "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n\
+                    data: {\"choices\":[{\"delta\":{\"content\":\"Grüße\"}}]}\n\n\
+                    data: {\"choices\":[{\"delta\":{\"content\":\" 🦀\"}}]}\n\n\
+                    data: [DONE]\n\n\
+                    data: {\"choices\":[{\"delta\":{\"content\":\"after\"}}]}\n\n";
+        let bytes = body.as_bytes();
+        for split in 0..=bytes.len() {
+            let mut parser = OpenAiStreamParser::default();
+            let mut chunks = parser.push(&bytes[..split]);
+            chunks.extend(parser.push(&bytes[split..]));
+            assert_eq!(
+                texts(chunks),
+                vec!["", "Grüße", " 🦀"],
+                "split at {}",
+                split
+            );
+            assert!(parser.done);
+        }
+    }
+
+    #[test]
+    fn reports_undecodable_chunks() {
+        let mut parser = OpenAiStreamParser::default();
+        let chunks = parser.push(b"data: {\"choices\": 1}\n\n: keep-alive\n\n");
+        assert_eq!(chunks.len(), 1);
+        assert!(matches!(chunks[0], Err(Error::Llm(_))));
+        assert!(!parser.done);
+    }
+
+    #[test]
+    fn renders_ollama_transcripts() {
+        let single = [Message::new(Role::User, "Hi")];
+        assert_eq!(ollama_prompt(&single), (String::new(), "Hi".to_string()));
+
+        let request = CompletionRequest::new("model", "And now?")
+            .system("Be brief.")
+            .history(vec![
+                Message::new(Role::User, "Hi"),
+                Message::new(Role::Assistant, "Hello"),
+            ]);
+        assert_eq!(
+            ollama_prompt(&request.messages),
+            (
+                "Be brief.".to_string(),
+                "User: Hi\n\nAssistant: Hello\n\nUser: And now?".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_the_latest_turns_that_fit() {
+        let history = [
+            Message::new(Role::User, "first question"),
+            Message::new(Role::Assistant, "a long first answer"),
+            Message::new(Role::User, "second"),
+            Message::new(Role::Assistant, "answer"),
+        ];
+        assert_eq!(recent_history(&history, 1000).len(), 4);
+        // The reply that still fits is dropped with its question
+        let recent = recent_history(&history, 30);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].content, "second");
+        assert!(recent_history(&history, 5).is_empty());
+        assert!(recent_history(&[], 5).is_empty());
+    }
+}
@@ -1,83 +1,289 @@
 use std::{
     convert::Infallible,
-    process::id,
+    fmt::Display,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use embedding::vec_cos_sim;
-use futures::{stream, Stream, StreamExt};
+use citations::{format_source, CitationParser};
+use embedder::Embedder;
+use futures::{Future, Stream, StreamExt};
 use lazy_static::lazy_static;
-use log::{debug, info, warn};
-use ollama_rs::{
-    generation::{
-        completion::{request::GenerationRequest, GenerationContext},
-        images::Image,
-        options::GenerationOptions,
-    },
-    Ollama,
-};
-use playwright::{api::BrowserType, Playwright};
-use pollster::FutureExt;
+use lexical::Bm25;
+use llm::{CompletionRequest, LanguageModel};
+use log::{debug, error, info, warn};
+use playwright::Playwright;
+use providers::SearchProvider;
+use ranking::{LiveRanking, Scores};
 use search::calculate_entry_similarity;
-use searchllama_types::types::{ChatRequest, ChatResponse, Entry, SearchRequest, SearchResponse};
+use searchllama_types::types::{
+    ChatRequest, ChatResponse, Citation, Entry, ResultsRequest, SearchEvent, SearchFrame,
+    SearchRequest, Stage, StageError, StageState,
+};
+use sqlx::SqlitePool;
 use tokio::sync::mpsc::{self, Sender};
-use warp::Filter;
+use tokio_util::sync::CancellationToken;
+use vector_codec::VectorEncoding;
+use vector_index::VectorIndex;
+use warp::{sse::Event, Filter};
+
+use crate::{
+    browser_pool::BrowserPool,
+    config::{Config, FetchMode},
+    database::{IndexedPage, Session, SessionSnippet},
+    error::{Error, Result},
+    llm::{Message, Role},
+};
 
+mod browser_pool;
+mod citations;
+mod config;
 mod database;
+mod embedder;
 mod embedding;
+mod error;
+mod fetch;
+mod history;
+mod lexical;
+mod llm;
+mod openai;
+mod providers;
+mod ranking;
+mod results;
 mod search;
+mod sse;
+mod vector_codec;
+mod vector_index;
+
+/// Crawled pages written to the index per transaction.
+const INDEX_BATCH_SIZE: usize = 16;
+/// How often the vector index is saved next to the database.
+const VECTOR_INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Characters of earlier turns sent with a chat message. Older turns are
+/// left out, so that long sessions still fit in the model's context.
+const MAX_CHAT_HISTORY_CHARS: usize = 16_000;
 
-pub const MAX_ENTRIES: usize = 50;
-pub const EMBEDDING_MODEL: &str = "nomic-embed-text:latest";
-pub const SEARCH_MODEL: &str = "gemma2:2b";
-pub const JUDGEMENT_MODEL: &str = "gemma2:2b";
-pub const MAX_EMBEDDING_SIZE: usize = 1024;
-pub const SNIPPET_TARGET_SIZE: usize = 512;
-pub const SNIPPET_NUMBER: usize = 10;
-pub const MIN_CONFIDENCE: f64 = 0.72;
 lazy_static! {
-    pub static ref G_OLLAMA: Ollama = Ollama::default();
     pub static ref G_REWEST_CLIENT: reqwest::Client = reqwest::Client::new();
 }
 
+/// State shared by all request handlers.
+pub struct AppState {
+    pub config: Config,
+    /// The database, shared by all requests.
+    pub db: SqlitePool,
+    pub vectors: Arc<VectorIndex>,
+    /// Model and format of the embeddings in the database.
+    pub encoding: VectorEncoding,
+    pub llm: Arc<dyn LanguageModel>,
+    pub embedder: Arc<dyn Embedder>,
+    pub search_provider: Arc<dyn SearchProvider>,
+    /// `None` when pages are only fetched over plain HTTP.
+    pub browsers: Option<Arc<BrowserPool>>,
+}
+
+/// Sends a frame to the client. A closed channel means the client went away,
+/// which is not an error for the pipeline.
+async fn send_frame<T>(sender: &Sender<T>, frame: impl Into<T>) {
+    let _ = sender.send(frame.into()).await;
+}
+
+fn stage_error(stage: Stage, error: &Error, subject: Option<&str>) -> StageError {
+    warn!(
+        "{:?} failed{}: {}",
+        stage,
+        subject.map(|s| format!(" for {}", s)).unwrap_or_default(),
+        error
+    );
+    StageError {
+        stage,
+        message: error.to_string(),
+        subject: subject.map(str::to_string),
+    }
+}
+
+/// Sends a search error event; the rest of the pipeline keeps running.
+async fn report(sender: &Sender<SearchFrame>, stage: Stage, error: &Error, subject: Option<&str>) {
+    let event = SearchEvent::Error(stage_error(stage, error, subject));
+    send_frame(sender, event).await;
+}
+
+/// Writes the pages crawled so far to the index in one transaction.
+async fn write_index(state: &AppState, sender: &Sender<SearchFrame>, pages: &mut Vec<IndexedPage>) {
+    if pages.is_empty() {
+        return;
+    }
+    if let Err(e) =
+        database::update_entries(&state.db, &state.vectors, &state.encoding, pages).await
+    {
+        report(sender, Stage::Database, &e, None).await;
+    }
+    pages.clear();
+}
+
+async fn stage_status(sender: &Sender<SearchFrame>, stage: Stage, state: StageState) {
+    send_frame(sender, SearchEvent::StageStatus { stage, state }).await;
+}
+
+/// Runs `future` to completion unless `token` is cancelled first.
+async fn until_cancelled<F: Future>(token: &CancellationToken, future: F) -> Option<F::Output> {
+    tokio::select! {
+        _ = token.cancelled() => None,
+        output = future => Some(output),
+    }
+}
+
 async fn handle_search_request(
+    state: Arc<AppState>,
+    query: SearchRequest,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let (sender, receiver) = mpsc::channel(10);
+    // Only set when the pipeline cannot run at all
+    let failed = Arc::new(AtomicBool::new(false));
+    let token = CancellationToken::new();
+    let stream = sse::event_stream(receiver, failed.clone(), token.clone(), |status| {
+        SearchEvent::Done { status }.into()
+    });
+
+    run_search(state, query, sender, failed, token, true).await;
+
+    stream
+}
+
+/// Runs the search pipeline, sending its events to `sender`.
+///
+/// Returns once the cached results are sent. The rest of the pipeline runs in
+/// the background until it is done or `token` is cancelled, and the channel
+/// closes when it finishes. `failed` is set when the search cannot run at all.
+/// With `session`, the answer starts a chat session for follow-up questions.
+pub async fn run_search(
+    state: Arc<AppState>,
     query: SearchRequest,
-) -> impl Stream<Item = Result<String, Infallible>> {
-    let query_embedding = embedding::generate_embedding(&format!(
-        "{} ({})",
-        query.query,
-        chrono::Local::now().to_rfc3339()
-    ))
+    sender: Sender<SearchFrame>,
+    failed: Arc<AtomicBool>,
+    token: CancellationToken,
+    session: bool,
+) {
+    let sender = Arc::new(history::record(
+        &state,
+        &query,
+        sender,
+        failed.clone(),
+        token.clone(),
+    ));
+
+    let query_embedding = match embedding::generate_embedding(
+        &state,
+        &format!("{} ({})", query.query, chrono::Local::now().to_rfc3339()),
+    )
     .await
-    .expect("Failed to generate embedding");
+    {
+        Ok(query_embedding) => query_embedding,
+        Err(e) => {
+            report(&sender, Stage::QueryEmbedding, &e, None).await;
+            failed.store(true, Ordering::Relaxed);
+            return;
+        }
+    };
 
-    let mut results = database::query_db(&query_embedding).await;
+    let session_id = if !session {
+        None
+    } else {
+        match database::create_session(&state.db, &query.query).await {
+            Ok(session_id) => {
+                let event = SearchEvent::Session {
+                    session_id: session_id.clone(),
+                };
+                send_frame(&sender, event).await;
+                Some(session_id)
+            }
+            Err(e) => {
+                report(&sender, Stage::Database, &e, None).await;
+                None
+            }
+        }
+    };
+
+    let bm25 = match database::lexical_scorer(&state.db, &query.query).await {
+        Ok(bm25) => bm25,
+        Err(e) => {
+            report(&sender, Stage::Database, &e, None).await;
+            Bm25::default()
+        }
+    };
+    let filter = search::ResultFilter::new(&query.sources, query.time_range);
+    let results = match database::query_db(
+        &state.db,
+        &state.vectors,
+        &state.encoding,
+        &state.config.ranking,
+        &bm25,
+        &query_embedding,
+        &filter,
+        state.config.search.max_entries,
+    )
+    .await
+    {
+        Ok(results) => results,
+        Err(e) => {
+            report(&sender, Stage::Database, &e, None).await;
+            Vec::new()
+        }
+    };
+    // Crawled pages are ranked among the cached ones
+    let mut live_ranking = LiveRanking::new(state.config.ranking.clone());
+    for page in &results {
+        live_ranking.upsert(page.entry.clone(), page.scores);
+    }
+    // Sent as the cached results below
+    live_ranking.all_changes();
+    let live_ranking = Arc::new(tokio::sync::Mutex::new(live_ranking));
+    let bm25 = Arc::new(bm25);
+    let cached_results = results.iter().map(|page| page.entry.clone()).collect();
+    send_frame(
+        &sender,
+        SearchEvent::CachedResults {
+            results: cached_results,
+        },
+    )
+    .await;
 
-    let (sender, receiver) = mpsc::channel(10);
-    let sender = Arc::new(sender);
     {
+        let state = state.clone();
         let sender = sender.clone();
         let query_embedding = query_embedding.clone();
         let query = query.clone();
-        let mut top_urls = results.clone();
-        top_urls.truncate(SNIPPET_NUMBER);
+        let mut top_urls = results;
+        top_urls.truncate(state.config.search.snippet_number);
         let top_url_titles = top_urls
             .iter()
-            .map(|entry| entry.1.clone())
+            .map(|page| page.entry.title.clone())
             .collect::<Vec<String>>();
         let top_urls = top_urls
             .iter()
-            .map(|entry| entry.0.clone())
+            .map(|page| page.entry.url.clone())
             .collect::<Vec<String>>();
+        let token = token.clone();
         tokio::spawn(async move {
-            let related_queries = G_OLLAMA.generate(
-        GenerationRequest::new(JUDGEMENT_MODEL.to_string(), format!("Generate search queries for: {}", query.query))
-                    .system("You are a helpful assistant. Show each query on a new line. without any explanation or numbering.".to_string())
-                ).await.unwrap().response.split('\n').filter(|q| !q.is_empty()).map(|q| q.trim().to_string()).collect::<Vec<String>>();
+            let mut related_queries = Vec::new();
+            if query.related_queries.unwrap_or(true) {
+                stage_status(&sender, Stage::RelatedQueries, StageState::Started).await;
+                related_queries = match until_cancelled(&token, state.llm.complete(
+            CompletionRequest::new(state.config.models.judgement.clone(), format!("Generate search queries for: {}", query.query))
+                        .system("You are a helpful assistant. Show each query on a new line. without any explanation or numbering.")
+                    )).await {
+                    None => return,
+                    Some(Ok(response)) => response.split('\n').filter(|q| !q.is_empty()).map(|q| q.trim().to_string()).collect::<Vec<String>>(),
+                    Some(Err(e)) => {
+                        report(&sender, Stage::RelatedQueries, &e, None).await;
+                        Vec::new()
+                    }
+                };
+                stage_status(&sender, Stage::RelatedQueries, StageState::Finished).await;
+            }
 
             //             let explanation_needed_string = G_OLLAMA
             //                 .generate(
@@ -104,29 +310,38 @@ async fn handle_search_request(
             //             info!("Explanation needed: {}", explanation_needed_string);
 
             //             let explanation_needed = !explanation_needed_string.contains("no");
-            let explanation_needed = true;
+            let explanation_needed = query.answer.unwrap_or(true);
 
             info!("Related queries: {:?}", related_queries);
 
-            let best_snippets = search::get_best_matching_snippets(
-                &query_embedding,
-                &top_urls,
-                &top_url_titles,
-                None,
+            stage_status(&sender, Stage::Snippets, StageState::Started).await;
+            let Some((best_snippets, failures)) = until_cancelled(
+                &token,
+                search::get_best_matching_snippets(
+                    &state,
+                    &query_embedding,
+                    &top_urls,
+                    &top_url_titles,
+                ),
             )
             .await
-            .expect("Failed to get best matching snippets");
+            else {
+                return;
+            };
+            for (url, e) in failures {
+                report(&sender, Stage::Snippets, &e, Some(&url)).await;
+            }
+            stage_status(&sender, Stage::Snippets, StageState::Finished).await;
 
-            let mean_score = best_snippets
-                .iter()
-                .map(|entry| entry.score.unwrap())
-                .sum::<f64>()
-                / best_snippets.len() as f64;
+            let mean_score = search::mean_score(&best_snippets);
 
             info!("Mean score: {}", mean_score); // Log the mean score
 
             async fn spawn_lm_thread(
-                sender: Arc<Sender<String>>,
+                state: Arc<AppState>,
+                sender: Arc<Sender<SearchFrame>>,
+                token: CancellationToken,
+                session_id: Option<String>,
                 query: SearchRequest,
                 best_snippets: Vec<search::SnippetInfo>,
             ) {
@@ -134,22 +349,19 @@ async fn handle_search_request(
                 let best_snippets = best_snippets.clone();
                 let query = query.clone();
                 tokio::spawn(async move {
+                    stage_status(&sender, Stage::Answer, StageState::Started).await;
                     let snippets = best_snippets
                         .iter()
-                        .map(|entry| {
-                            format!(
-                                "From \"{}\" ![]({}):\n\"{}\"",
-                                entry.title.as_ref().unwrap_or(&String::from("Unkown")),
-                                entry.url.as_ref().unwrap_or(&String::from("Unkown")),
-                                entry.text
+                        .enumerate()
+                        .map(|(idx, entry)| {
+                            format_source(
+                                idx + 1,
+                                entry.title.as_deref().unwrap_or("Unkown"),
+                                entry.url.as_deref().unwrap_or("Unkown"),
+                                &entry.text,
                             )
                         })
                         .collect::<Vec<String>>();
-                    let images = best_snippets
-                        .iter()
-                        .map(|entry| entry.images.clone())
-                        .flatten()
-                        .collect::<Vec<(String, String)>>();
                     let prompt = format!(
                         "Sources:\n\"{}\"\n\n
 local current time: {}\n\n
@@ -166,114 +378,161 @@ Anwer this question: '{}'.",
 
                     info!("Prompt: {}", prompt);
 
-                    let mut response_stream = G_OLLAMA
-                    .generate_stream(
-                        GenerationRequest::new(
-                            SEARCH_MODEL.to_string(),
+                    if let Some(session_id) = &session_id {
+                        let snippets = best_snippets
+                            .iter()
+                            .map(|entry| SessionSnippet {
+                                url: entry.url.clone().unwrap_or_default(),
+                                title: entry.title.clone().unwrap_or_default(),
+                                text: entry.text.clone(),
+                            })
+                            .collect::<Vec<SessionSnippet>>();
+                        if let Err(e) =
+                            database::save_snippets(&state.db, session_id, &snippets).await
+                        {
+                            report(&sender, Stage::Database, &e, None).await;
+                        }
+                    }
+
+                    let response_stream = state.llm
+                    .complete_stream(
+                        CompletionRequest::new(
+                            state.config.models.search.clone(),
                             prompt,
                         )
-                        .system(format!(
-"You are a helpful assistant.
+                        .system("You are a helpful assistant.
 You are given a list of snippets from the internet and a question.
 You must answer the question based on the snippets whithout mentioning that you received snippets from the internet.
+Cite the snippets you use by their number in square brackets, like [1] or [2][3], right after the sentence they support.
 Use correct markdown formatting.
 Answer with the language used in the question.
 only use emojis for country flags when needed.
 Use the local current time as a reference point in your answer and if asked for time for example.
 If you don't know the answer, say 'I don't know'.
-",
-                        )
-                        )
-                        .options(GenerationOptions::default()),
-                    ).await.expect("Failed to generate response");
-
-                    while let Some(response) = response_stream.next().await {
-                        if let Ok(response) = response {
-                            let mut search_response = SearchResponse {
-                                results: Vec::new(),
-                                summary: response.iter().map(|s| s.response.clone()).collect(),
-                                summary_context: None,
-                            };
+"
+                        ),
+                    ).await;
+                    let mut response_stream = match response_stream {
+                        Ok(response_stream) => response_stream,
+                        Err(e) => {
+                            report(&sender, Stage::Answer, &e, None).await;
+                            return;
+                        }
+                    };
 
-                            // for chunk in &response {
-                            //     if let Some(context) = &chunk.context {
-                            //         info!("Context: {}", context.0.len());
-                            //         search_response.summary_context = Some(context.clone().0);
-                            //     }
-                            // }
-
-                            for chunk in &response {
-                                if chunk.context.is_some() {
-                                    search_response.summary_context = Some(
-                                        chunk.context.as_ref().expect("Context is none").clone().0,
-                                    );
+                    // Dropping the stream on cancellation closes the connection,
+                    // which stops the generation
+                    let mut citations = CitationParser::new(best_snippets.len());
+                    let mut answer = String::new();
+                    while let Some(response) = until_cancelled(&token, response_stream.next())
+                        .await
+                        .flatten()
+                    {
+                        match response {
+                            Ok(chunk) => {
+                                let cited = citations.push(&chunk.text);
+                                answer.push_str(&chunk.text);
+                                let event = SearchEvent::AnswerToken { text: chunk.text };
+                                send_frame(&sender, event).await;
+
+                                for index in cited {
+                                    let entry = &best_snippets[index - 1];
+                                    let citation = Citation {
+                                        index,
+                                        url: entry.url.clone().unwrap_or_default(),
+                                        title: entry.title.clone().unwrap_or_default(),
+                                        snippet: entry.text.clone(),
+                                    };
+                                    send_frame(&sender, SearchEvent::Citation(citation)).await;
                                 }
                             }
+                            Err(e) => {
+                                report(&sender, Stage::Answer, &e, None).await;
+                                return;
+                            }
+                        }
+                    }
+                    if token.is_cancelled() {
+                        return;
+                    }
+                    stage_status(&sender, Stage::Answer, StageState::Finished).await;
 
-                            let search_response_string = serde_json::to_string(&search_response)
-                                .expect("Failed to serialize search response");
-
-                            sender
-                                .send(search_response_string)
-                                .await
-                                .expect("Failed to send search response");
+                    if let Some(session_id) = &session_id {
+                        let turn = [
+                            Message::new(Role::User, query.query),
+                            Message::new(Role::Assistant, answer),
+                        ];
+                        if let Err(e) =
+                            database::append_messages(&state.db, session_id, &turn).await
+                        {
+                            report(&sender, Stage::Database, &e, None).await;
                         }
                     }
                 });
             }
 
             let need_to_respond = Arc::new(AtomicBool::new(true));
-            if mean_score > MIN_CONFIDENCE && explanation_needed {
-                need_to_respond.store(false, Ordering::Relaxed);
-                spawn_lm_thread(sender.clone(), query.clone(), best_snippets.clone()).await;
+            if mean_score > state.config.search.min_confidence
+                && explanation_needed
+                && need_to_respond.swap(false, Ordering::AcqRel)
+            {
+                spawn_lm_thread(
+                    state.clone(),
+                    sender.clone(),
+                    token.clone(),
+                    session_id.clone(),
+                    query.clone(),
+                    best_snippets.clone(),
+                )
+                .await;
             }
 
             let best_snippets = Arc::new(tokio::sync::Mutex::new(best_snippets));
-            let mut queries = vec![query.clone()];
-            related_queries
-                .into_iter()
-                .for_each(|q| queries.push(SearchRequest { query: q }));
+            let mut queries = vec![query.query.clone()];
+            queries.extend(related_queries);
 
             {
                 let queries = queries.clone();
                 let user_query = query;
+                stage_status(&sender, Stage::WebSearch, StageState::Started).await;
+                let mut crawls = Vec::new();
                 for (idx, query) in queries.into_iter().enumerate() {
+                    let state = state.clone();
                     let query_embedding = query_embedding.clone();
                     let sender = sender.clone();
                     let best_snippets = Arc::clone(&best_snippets);
                     let need_to_respond = Arc::clone(&need_to_respond);
                     let user_query = user_query.clone();
-                    tokio::spawn(async move {
-                        let results = search::query_ddg(
-                            &query.query,
-                            match idx {
-                                0 => 10,
-                                _ => 3,
-                            },
-                        )
-                        .await
-                        .expect("Failed to query DDG");
-
-                        let pw = Playwright::initialize()
-                            .block_on()
-                            .expect("Failed to initialize Playwright");
-                        let chromium = pw.chromium();
-                        let browser = chromium
-                            .launcher()
-                            .headless(true)
-                            .launch()
-                            .await
-                            .expect("Failed to launch browser");
-                        let context = Arc::new(
-                            browser
-                                .context_builder()
-                                .build()
+                    let filter = filter.clone();
+                    let session_id = session_id.clone();
+                    let token = token.clone();
+                    let live_ranking = Arc::clone(&live_ranking);
+                    let bm25 = Arc::clone(&bm25);
+                    crawls.push(tokio::spawn(async move {
+                        let max_results = match idx {
+                            0 => user_query
+                                .max_results
+                                .unwrap_or(state.config.search.max_results),
+                            _ => state.config.search.related_max_results,
+                        };
+                        let web_query = search::web_query(&user_query, &query, max_results);
+                        let results =
+                            match until_cancelled(&token, search::query_web(&state, &web_query))
                                 .await
-                                .expect("Failed to create context"),
-                        );
+                            {
+                                None => return,
+                                Some(Ok(results)) => results,
+                                Some(Err(e)) => {
+                                    report(&sender, Stage::WebSearch, &e, Some(&query)).await;
+                                    Vec::new()
+                                }
+                            };
 
                         let mut join_set = tokio::task::JoinSet::new();
-                        for result in results.into_iter() {
+                        for result in results
+                            .into_iter()
+                            .filter(|result| filter.allows_url(&result.url))
+                        {
                             let url = result.url;
                             let title = result.title;
                             let desc = result.body;
@@ -285,192 +544,298 @@ If you don't know the answer, say 'I don't know'.
                                 description: desc,
                             };
                             let url = url.clone();
-                            let context = context.clone();
+                            let state = state.clone();
                             join_set.spawn(async move {
-                                (embedding::get_website_embedding(&url, context).await, entry)
+                                (embedding::get_website_embedding(&state, &url).await, entry)
                             });
                         }
                         //let mut pbar = tqdm::pbar(Some(join_set.len()));
-                        while let Some(Ok((embedding, entry))) = join_set.join_next().await {
-                            if let Ok(embedding) = embedding {
-                                if need_to_respond.load(Ordering::Relaxed) && explanation_needed {
-                                    let snippet = search::get_best_matching_snippet(
-                                        &entry.url,
-                                        &query_embedding,
-                                        context.clone(),
-                                    )
-                                    .await
-                                    .expect("Failed to get best matching snippet");
-
-                                    let mut lock = best_snippets.lock().await;
-                                    lock.push(snippet);
-                                    lock.sort_by(|a, b| {
-                                        a.score.partial_cmp(&b.score).unwrap().reverse()
-                                    });
-                                    lock.truncate(SNIPPET_NUMBER);
-
-                                    let mean_score =
-                                        lock.iter().map(|entry| entry.score.unwrap()).sum::<f64>()
-                                            / lock.len() as f64;
-
-                                    info!("Mean score: {}", mean_score);
-
-                                    if mean_score > MIN_CONFIDENCE
-                                        && need_to_respond.load(Ordering::Relaxed)
-                                    {
-                                        info!(
-                                            "Mean score [{}] > MIN_CONFIDENCE [{}]",
-                                            mean_score, MIN_CONFIDENCE
-                                        );
-
-                                        need_to_respond.store(false, Ordering::Relaxed);
-                                        spawn_lm_thread(
-                                            sender.clone(),
-                                            user_query.clone(),
-                                            lock.clone(),
-                                        )
-                                        .await;
-                                    }
+                        let mut indexed = Vec::new();
+                        while let Some(joined) = until_cancelled(&token, join_set.join_next())
+                            .await
+                            .flatten()
+                        {
+                            let (embedding, entry) = match joined {
+                                Ok(joined) => joined,
+                                Err(e) => {
+                                    report(&sender, Stage::Fetch, &e.into(), None).await;
+                                    continue;
                                 }
-
-                                let title_embedding = embedding::generate_embedding(&entry.title)
-                                    .await
-                                    .expect("Failed to generate embedding for title");
-
-                                let mut entry_with_score = entry.clone();
-                                entry_with_score.score = calculate_entry_similarity(
-                                    &query_embedding,
-                                    &title_embedding,
-                                    &embedding.embeddings,
-                                );
-                                if entry_with_score.score > 10.0 || entry_with_score.score < -10.0 {
+                            };
+                            let embedding = match embedding {
+                                Ok(embedding) => embedding,
+                                Err(e) => {
+                                    report(&sender, Stage::Fetch, &e, Some(&entry.url)).await;
                                     continue;
                                 }
+                            };
+
+                            if need_to_respond.load(Ordering::Acquire) && explanation_needed {
+                                match search::get_best_matching_snippet(
+                                    &state,
+                                    &entry.url,
+                                    &query_embedding,
+                                )
+                                .await
+                                {
+                                    Ok(mut snippet) => {
+                                        snippet.title = Some(entry.title.clone());
+                                        snippet.url = Some(entry.url.clone());
+                                        let mut lock = best_snippets.lock().await;
+                                        // Related queries often find the same pages
+                                        if !lock.iter().any(|s| s.url == snippet.url) {
+                                            lock.push(snippet);
+                                        }
+                                        search::sort_snippets(&mut lock);
+                                        lock.truncate(state.config.search.snippet_number);
 
-                                debug!(
-                                    "Entry: {} - Score: {}",
-                                    entry_with_score.title, entry_with_score.score
-                                );
+                                        let mean_score = search::mean_score(&lock);
 
-                                let search_response = SearchResponse {
-                                    results: vec![entry_with_score],
-                                    summary: String::new(),
-                                    summary_context: None,
+                                        info!("Mean score: {}", mean_score);
+
+                                        let min_confidence = state.config.search.min_confidence;
+                                        // Only one crawl may start the answer
+                                        if mean_score > min_confidence
+                                            && need_to_respond.swap(false, Ordering::AcqRel)
+                                        {
+                                            info!(
+                                                "Mean score [{}] > min_confidence [{}]",
+                                                mean_score, min_confidence
+                                            );
+
+                                            spawn_lm_thread(
+                                                state.clone(),
+                                                sender.clone(),
+                                                token.clone(),
+                                                session_id.clone(),
+                                                user_query.clone(),
+                                                lock.clone(),
+                                            )
+                                            .await;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        report(&sender, Stage::Snippets, &e, Some(&entry.url)).await
+                                    }
+                                }
+                            }
+
+                            let title_embedding =
+                                match embedding::generate_embedding(&state, &entry.title).await {
+                                    Ok(title_embedding) => title_embedding,
+                                    Err(e) => {
+                                        report(&sender, Stage::Ranking, &e, Some(&entry.url)).await;
+                                        continue;
+                                    }
                                 };
-                                let response_str = serde_json::to_string(&search_response).unwrap();
-                                sender
-                                    .send(response_str)
-                                    .await
-                                    .expect("Failed to send response");
 
-                                database::update_entry(
-                                    &entry.url,
-                                    &entry.title,
-                                    &entry.description,
-                                    embedding::generate_embedding(&entry.title).await.unwrap(),
-                                    embedding.embeddings,
-                                )
-                                .await;
-                            } else {
-                                warn!("Failed to get embedding for url: {}", entry.url);
+                            let similarity = calculate_entry_similarity(
+                                &query_embedding,
+                                &title_embedding,
+                                &embedding.embeddings,
+                            );
+                            if !(-10.0..=10.0).contains(&similarity) {
+                                continue;
+                            }
+                            let text = embedding.texts.concat();
+                            let scores = Scores {
+                                similarity,
+                                lexical: bm25.score(&entry.title, &text),
+                            };
+
+                            debug!("Entry: {} - Scores: {:?}", entry.title, scores);
+
+                            // Other results move when the blend is relative to them,
+                            // so their new scores are sent in batches
+                            let changed = {
+                                let mut live_ranking = live_ranking.lock().await;
+                                live_ranking.upsert(entry.clone(), scores);
+                                live_ranking.due_changes(Instant::now())
+                            };
+                            for entry in changed {
+                                send_frame(&sender, SearchEvent::ResultUpsert { entry }).await;
+                            }
+
+                            indexed.push(IndexedPage {
+                                url: entry.url,
+                                title: entry.title,
+                                summary: entry.description,
+                                title_embedding,
+                                body_embeddings: embedding.embeddings,
+                                chunks: embedding.texts,
+                            });
+                            if indexed.len() >= INDEX_BATCH_SIZE {
+                                write_index(&state, &sender, &mut indexed).await;
                             }
                             //pbar.update(1).unwrap();
                         }
 
-                        if need_to_respond.load(Ordering::Relaxed) && explanation_needed {
-                            need_to_respond.store(false, Ordering::Relaxed);
-                            spawn_lm_thread(
-                                sender.clone(),
-                                user_query,
-                                best_snippets.lock().await.clone(),
-                            )
-                            .await;
+                        // Abort the fetches still running when cancelled
+                        join_set.shutdown().await;
+                        write_index(&state, &sender, &mut indexed).await;
+                        let changed = live_ranking.lock().await.all_changes();
+                        for entry in changed {
+                            send_frame(&sender, SearchEvent::ResultUpsert { entry }).await;
                         }
 
-                        context.close().await.expect("Failed to close context");
-                        browser.close().await.expect("Failed to close browser");
-
                         //pbar.close().unwrap();
-                    });
+                    }));
+                }
+
+                futures::future::join_all(crawls).await;
+                if !token.is_cancelled() {
+                    stage_status(&sender, Stage::WebSearch, StageState::Finished).await;
+                }
+
+                // Answer from the best snippets found when no crawl was confident enough
+                if explanation_needed
+                    && !token.is_cancelled()
+                    && need_to_respond.swap(false, Ordering::AcqRel)
+                {
+                    spawn_lm_thread(
+                        state.clone(),
+                        sender.clone(),
+                        token.clone(),
+                        session_id,
+                        user_query,
+                        best_snippets.lock().await.clone(),
+                    )
+                    .await;
                 }
             }
         });
     }
+}
 
-    results.truncate(MAX_ENTRIES); // Truncate the results to MAX_ENTRIES
-    let response = SearchResponse {
-        results: results
-            .into_iter()
-            .map(|(url, text, desc, score)| Entry {
-                score,
-                url,
-                title: text,
-                description: desc,
-            })
-            .collect(),
-        summary: String::new(),
-        summary_context: None,
+/// Sends a chat error event.
+async fn report_chat(sender: &Sender<ChatResponse>, error: &Error) {
+    let chat_response = ChatResponse {
+        error: Some(stage_error(Stage::Chat, error, None)),
+        ..Default::default()
     };
+    send_frame(sender, chat_response).await;
+}
 
-    let json = serde_json::to_string(&response).unwrap();
-    sender.send(json).await.expect("Failed to send response");
-
-    stream::unfold(receiver, |mut receiver| async move {
-        receiver
-            .recv()
-            .await
-            .map(|item| (Ok(item + "\t"), receiver))
-    })
+/// Loads the session of a chat request, or starts a new one.
+async fn chat_session(state: &AppState, request: &ChatRequest) -> Result<Session> {
+    match &request.session_id {
+        Some(id) => database::load_session(&state.db, id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("Unknown session: {}", id))),
+        None => Ok(Session {
+            id: database::create_session(&state.db, &request.message).await?,
+            query: request.message.clone(),
+            messages: Vec::new(),
+            snippets: Vec::new(),
+        }),
+    }
 }
 
 async fn handle_chat_request(
-    message: String,
-    context: Vec<i32>,
-) -> impl Stream<Item = Result<String, Infallible>> {
-    let (sender, mut receiver) = mpsc::channel(8);
+    state: Arc<AppState>,
+    request: ChatRequest,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let (sender, receiver) = mpsc::channel(8);
     let sender = Arc::new(sender); // Create an Arc to share the sender across threads
+    let failed = Arc::new(AtomicBool::new(false));
+    let token = CancellationToken::new();
+
+    let session = chat_session(&state, &request).await;
+    let session_id = session.as_ref().ok().map(|session| session.id.clone());
+    let stream = sse::event_stream(receiver, failed.clone(), token.clone(), |status| {
+        ChatResponse {
+            session_id,
+            status: Some(status),
+            ..Default::default()
+        }
+    });
+    let session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            report_chat(&sender, &e).await;
+            failed.store(true, Ordering::Relaxed);
+            return stream;
+        }
+    };
 
     tokio::spawn(async move {
-        let mut response_stream = G_OLLAMA
-            .generate_stream(
-                GenerationRequest::new(SEARCH_MODEL.to_string(), message)
-                    .context(GenerationContext { 0: context })
-                    .system(
-                        "You are a helpful assistant.
-                    If you don't know the answer, say 'I don't know'"
-                            .to_string(),
-                    ),
-            )
-            .await
-            .expect("Failed to generate response");
+        let mut system = "You are a helpful assistant.
+If you don't know the answer, say 'I don't know'"
+            .to_string();
+        if !session.snippets.is_empty() {
+            let sources = session
+                .snippets
+                .iter()
+                .enumerate()
+                .map(|(idx, s)| format_source(idx + 1, &s.title, &s.url, &s.text))
+                .collect::<Vec<String>>();
+            system.push_str(&format!(
+                "\nThe conversation started with a web search for '{}'. Use these sources when they are relevant and cite them by their number in square brackets, like [1]:\n\n{}",
+                session.query,
+                sources.join("\n\n")
+            ));
+        }
 
-        while let Some(response) = response_stream.next().await {
-            if let Ok(response) = response {
-                let mut chat_response = ChatResponse {
-                    response: response.iter().map(|c| c.response.clone()).collect(),
-                    context: None,
-                };
+        let response_stream = state
+            .llm
+            .complete_stream(
+                CompletionRequest::new(state.config.models.search.clone(), &request.message)
+                    .history(
+                        llm::recent_history(&session.messages, MAX_CHAT_HISTORY_CHARS).to_vec(),
+                    )
+                    .system(system),
+            )
+            .await;
+        let mut response_stream = match response_stream {
+            Ok(response_stream) => response_stream,
+            Err(e) => {
+                report_chat(&sender, &e).await;
+                failed.store(true, Ordering::Relaxed);
+                return;
+            }
+        };
 
-                for chunk in response.iter() {
-                    if chunk.context.is_some() {
-                        chat_response.context = Some(chunk.context.clone().unwrap().0);
-                    }
+        let mut reply = String::new();
+        while let Some(response) = until_cancelled(&token, response_stream.next())
+            .await
+            .flatten()
+        {
+            match response {
+                Ok(chunk) => {
+                    reply.push_str(&chunk.text);
+                    let chat_response = ChatResponse {
+                        response: chunk.text,
+                        ..Default::default()
+                    };
+                    send_frame(&sender, chat_response).await;
+                }
+                Err(e) => {
+                    report_chat(&sender, &e).await;
+                    failed.store(true, Ordering::Relaxed);
+                    return;
                 }
-
-                let response_json =
-                    serde_json::to_string(&chat_response).expect("Failed to serialize response");
-
-                sender.send(response_json).await.unwrap();
             }
         }
+        if token.is_cancelled() {
+            return;
+        }
+
+        let turn = [
+            Message::new(Role::User, request.message),
+            Message::new(Role::Assistant, reply),
+        ];
+        if let Err(e) = database::append_messages(&state.db, &session.id, &turn).await {
+            report_chat(&sender, &e).await;
+        }
     });
 
-    stream::unfold(receiver, |mut receiver| async move {
-        receiver
-            .recv()
-            .await
-            .map(|item| (Ok(item + "\t"), receiver))
-    })
+    stream
+}
+
+/// Logs that the server could not `what` and exits.
+fn exit_on_startup_error(what: &str, error: impl Display) -> ! {
+    error!("Failed to {}: {}", what, error);
+    std::process::exit(1);
 }
 
 #[tokio::main]
@@ -479,49 +844,484 @@ async fn main() {
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let db = match database::connect(&config.database).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to open the database: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let embedder = embedder::from_config(&config)
+        .unwrap_or_else(|e| exit_on_startup_error("create the embedder", e));
+    let encoding = VectorEncoding {
+        model: embedder.model_id().to_string(),
+        format: config.database.embedding_format,
+    };
+    let vectors = match database::open_vector_index(&db, &config.database, &encoding).await {
+        Ok(vectors) => Arc::new(vectors),
+        Err(e) => {
+            error!("Failed to load the vector index: {}", e);
+            std::process::exit(1);
+        }
+    };
+    vectors.spawn_saves(VECTOR_INDEX_SAVE_INTERVAL);
+    let llm = llm::from_config(&config)
+        .unwrap_or_else(|e| exit_on_startup_error("create the language model", e));
+    let search_provider = providers::from_config(&config)
+        .unwrap_or_else(|e| exit_on_startup_error("create the search provider", e));
+    let bind = config.server.bind;
+
+    let mut browsers = None;
+    if config.fetcher.mode != FetchMode::Http {
         // prepare playwright
-        let pw = Playwright::initialize()
+        let prepared = Playwright::initialize()
             .await
-            .expect("Failed to initialize playwright");
-        pw.prepare().expect("Failed to prepare playwright");
+            .map_err(|e| e.to_string())
+            .and_then(|pw| pw.prepare().map_err(|e| e.to_string()));
+        match (prepared, config.fetcher.mode) {
+            (Ok(()), _) => {
+                let pool = BrowserPool::new(&config.playwright);
+                pool.spawn_health_checks();
+                browsers = Some(pool);
+            }
+            (Err(e), FetchMode::Auto) => warn!("Playwright unavailable, using HTTP only: {}", e),
+            (Err(e), _) => exit_on_startup_error("prepare Playwright", e),
+        }
     }
 
-    // GET /search with json body that will be serialized into a struct with serde_json
-    let search_router = warp::path!("search")
-        .and(warp::post())
+    let state = Arc::new(AppState {
+        config,
+        db,
+        vectors,
+        encoding,
+        llm,
+        embedder,
+        search_provider,
+        browsers,
+    });
+
+    let with_state = warp::any().map(move || state.clone());
+
+    // POST /search with a json body, or GET /search?query=... for EventSource
+    let search_request = warp::post()
         .and(warp::body::json())
-        .and_then(|query: SearchRequest| async move {
+        .or(warp::get().and(warp::query::<SearchRequest>()))
+        .unify();
+    let search_router = warp::path!("search")
+        .and(with_state.clone())
+        .and(search_request)
+        .and_then(|state: Arc<AppState>, query: SearchRequest| async move {
             info!("Received search request: {:?}", query);
 
-            let res_stream = handle_search_request(query).await;
-            let body = warp::hyper::Body::wrap_stream(res_stream);
-            let response = warp::http::Response::new(body);
-
-            Ok(response) as Result<_, Infallible>
+            let events = handle_search_request(state, query).await;
 
-            //Ok::<_, Infallible>(warp::reply::json(&response))
+            Ok(sse::reply(events)) as Result<_, Infallible>
         });
 
-    let chat_router = warp::path!("chat")
-        .and(warp::post())
+    // POST /chat with a json body, or GET /chat?session_id=...&message=...
+    let chat_request = warp::post()
         .and(warp::body::json())
-        .and_then(|query: ChatRequest| async move {
+        .or(warp::get().and(warp::query::<ChatRequest>()))
+        .unify();
+    let chat_router = warp::path!("chat")
+        .and(with_state.clone())
+        .and(chat_request)
+        .and_then(|state: Arc<AppState>, query: ChatRequest| async move {
             info!("Received chat request: {:?}", query);
 
-            let res_stream = handle_chat_request(query.message, query.context).await;
-            let body = warp::hyper::Body::wrap_stream(res_stream);
-            let response = warp::http::Response::new(body);
+            let events = handle_chat_request(state, query).await;
 
-            Ok(response) as Result<_, Infallible>
+            Ok(sse::reply(events)) as Result<_, Infallible>
         });
 
     let cors = warp::cors()
         .allow_any_origin() // You can specify a particular origin here if needed
         .allow_headers(vec!["Content-Type", "Authorization"])
-        .allow_methods(&[warp::http::Method::GET, warp::http::Method::POST]);
+        .allow_methods(&[
+            warp::http::Method::GET,
+            warp::http::Method::POST,
+            warp::http::Method::DELETE,
+        ]);
+
+    // OpenAI-compatible API, so other tools can use the search as a model
+    let completions_router = warp::path!("v1" / "chat" / "completions")
+        .and(warp::post())
+        .and(with_state.clone())
+        .and(warp::body::json())
+        .and_then(openai::chat_completions);
+    let models_router = warp::path!("v1" / "models")
+        .and(warp::get())
+        .map(openai::models);
+
+    // A page of the local index: POST /results with a json body, or GET
+    // /results?query=...&cursor=...
+    let results_request = warp::post()
+        .and(warp::body::json())
+        .or(warp::get().and(warp::query::<ResultsRequest>()))
+        .unify();
+    let results_router = warp::path!("results")
+        .and(with_state.clone())
+        .and(results_request)
+        .and_then(results::handle_results_request);
+
+    let history_router = warp::path!("history")
+        .and(warp::get())
+        .and(with_state.clone())
+        .and(warp::query::<history::HistoryQuery>())
+        .and_then(history::list)
+        .or(warp::path!("history" / String)
+            .and(warp::get())
+            .and(with_state.clone())
+            .and_then(history::get))
+        .or(warp::path!("history" / String)
+            .and(warp::delete())
+            .and(with_state.clone())
+            .and_then(history::delete))
+        .or(warp::path!("history" / String / "rerun")
+            .and(warp::post())
+            .and(with_state)
+            .and_then(history::rerun));
+
+    let routes = search_router
+        .or(chat_router)
+        .or(results_router)
+        .or(history_router)
+        .or(completions_router)
+        .or(models_router)
+        .with(cors);
+
+    info!("Listening on {}", bind);
+    warp::serve(routes).run(bind).await;
+}
 
-    let routes = search_router.or(chat_router).with(cors);
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::atomic::AtomicUsize,
+        thread,
+    };
+
+    use async_trait::async_trait;
+    use searchllama_types::types::{Status, TimeRange};
+
+    use super::*;
+    use crate::{
+        config::{EmbeddingBackend, LlmBackend, SearchBackend},
+        llm::{CompletionChunk, CompletionStream},
+        providers::SearchQuery,
+        search::SearchResult,
+    };
+
+    impl AppState {
+        /// A state that works offline: an in-memory database, the hash
+        /// embedder, the scripted model giving `answers` in turn, and the
+        /// fixture provider serving `results`, a JSON map of queries to
+        /// results.
+        pub(crate) async fn offline(answers: &[&str], results: &str) -> Self {
+            let fixture = std::env::temp_dir().join(format!(
+                "searchllama-fixture-{}.json",
+                uuid::Uuid::new_v4().simple()
+            ));
+            std::fs::write(&fixture, results).unwrap();
+
+            let mut config = Config::default();
+            config.database.path = database::IN_MEMORY.into();
+            config.embedding.backend = EmbeddingBackend::Hash;
+            config.llm.backend = LlmBackend::Mock;
+            config.mock.responses = answers.iter().map(|a| a.to_string()).collect();
+            config.web_search.backend = SearchBackend::Fixture;
+            config.search_fixture.path = fixture.clone();
+            config.fetcher.mode = FetchMode::Http;
+            config.validate().unwrap();
+            let search_provider = providers::from_config(&config).unwrap();
+            std::fs::remove_file(&fixture).unwrap();
+
+            let db = database::connect(&config.database).await.unwrap();
+            let embedder = embedder::from_config(&config).unwrap();
+            let encoding = VectorEncoding {
+                model: embedder.model_id().to_string(),
+                format: config.database.embedding_format,
+            };
+            let vectors = database::open_vector_index(&db, &config.database, &encoding)
+                .await
+                .unwrap();
+            Self {
+                llm: llm::from_config(&config).unwrap(),
+                config,
+                db,
+                vectors: Arc::new(vectors),
+                encoding,
+                embedder,
+                search_provider,
+                browsers: None,
+            }
+        }
+    }
+
+    /// A search that only uses the fixture results.
+    pub(crate) fn offline_request(query: &str) -> SearchRequest {
+        SearchRequest {
+            related_queries: Some(false),
+            // Skips the web search cache, which outlives the test
+            time_range: Some(TimeRange::Year),
+            ..SearchRequest::new(query)
+        }
+    }
 
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+    /// The frames of a search, decoded from its server-sent events.
+    pub(crate) async fn search_frames(
+        state: Arc<AppState>,
+        request: SearchRequest,
+    ) -> Vec<SearchFrame> {
+        handle_search_request(state, request)
+            .await
+            .map(|event| {
+                let event = event.unwrap().to_string();
+                let data = event
+                    .lines()
+                    .find_map(|line| line.strip_prefix("data:"))
+                    .unwrap();
+                serde_json::from_str(data).unwrap()
+            })
+            .collect()
+            .await
+    }
+
+    /// Serves `html` to every request, and returns its URL.
+    fn serve_page(html: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/article", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    html.len(),
+                    html
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn answers_from_a_crawled_page() {
+        let url = serve_page(include_str!("../fixtures/pages/article.html"));
+        let results = serde_json::json!({
+            "*": [{ "url": url, "title": "Understanding the borrow checker", "body": "" }]
+        });
+        let answer = "The borrow checker enforces the rules of references [1].";
+        let state = AppState::offline(&[answer], &results.to_string()).await;
+
+        let frames = search_frames(Arc::new(state), offline_request("borrow checker")).await;
+        let events = frames.iter().map(|frame| &frame.event).collect::<Vec<_>>();
+
+        let tokens = events
+            .iter()
+            .filter_map(|event| match event {
+                SearchEvent::AnswerToken { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<String>();
+        assert_eq!(tokens, answer);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, SearchEvent::ResultUpsert { entry } if entry.url == url)));
+        assert!(events.iter().any(
+            |event| matches!(event, SearchEvent::Citation(citation) if citation.index == 1 && citation.url == url)
+        ));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, SearchEvent::Error(_))));
+        assert!(matches!(
+            events.last(),
+            Some(SearchEvent::Done {
+                status: Status::Done
+            })
+        ));
+    }
+
+    struct FailingProvider;
+
+    #[async_trait]
+    impl SearchProvider for FailingProvider {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        async fn search(&self, _query: &SearchQuery) -> Result<Vec<SearchResult>> {
+            Err(Error::Search("The search engine is down".to_string()))
+        }
+    }
+
+    struct FailingEmbedder;
+
+    #[async_trait]
+    impl Embedder for FailingEmbedder {
+        fn model_id(&self) -> &str {
+            "failing"
+        }
+
+        async fn dimension(&self) -> Result<usize> {
+            Ok(8)
+        }
+
+        async fn embed_batch(&self, _texts: &[String]) -> Result<Vec<Vec<f64>>> {
+            Err(Error::Embedding("The embedder is down".to_string()))
+        }
+    }
+
+    fn stage_errors(frames: &[SearchFrame]) -> Vec<&StageError> {
+        frames
+            .iter()
+            .filter_map(|frame| match &frame.event {
+                SearchEvent::Error(error) => Some(error),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn answer(frames: &[SearchFrame]) -> String {
+        frames
+            .iter()
+            .filter_map(|frame| match &frame.event {
+                SearchEvent::AnswerToken { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn status(frames: &[SearchFrame]) -> Option<Status> {
+        match frames.last().map(|frame| &frame.event) {
+            Some(SearchEvent::Done { status }) => Some(*status),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_pages_and_providers_are_reported_and_the_rest_runs() {
+        let url = serve_page(include_str!("../fixtures/pages/article.html"));
+        // Nothing listens on the discard port
+        let missing = "http://127.0.0.1:9/missing";
+        let results = serde_json::json!({
+            "*": [
+                { "url": missing, "title": "Missing", "body": "" },
+                { "url": url, "title": "Understanding the borrow checker", "body": "" },
+            ]
+        });
+        let state = AppState::offline(&["Borrows are checked [1]."], &results.to_string()).await;
+        let frames = search_frames(Arc::new(state), offline_request("borrow checker")).await;
+
+        let errors = stage_errors(&frames);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].stage, Stage::Fetch);
+        assert_eq!(errors[0].subject.as_deref(), Some(missing));
+        assert_eq!(answer(&frames), "Borrows are checked [1].");
+        assert_eq!(status(&frames), Some(Status::Done));
+
+        let mut state = AppState::offline(&["I don't know."], "{}").await;
+        state.search_provider = Arc::new(FailingProvider);
+        let frames = search_frames(Arc::new(state), offline_request("borrow checker")).await;
+
+        let errors = stage_errors(&frames);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].stage, Stage::WebSearch);
+        assert_eq!(errors[0].message, "The search engine is down");
+        assert_eq!(errors[0].subject.as_deref(), Some("borrow checker"));
+        assert_eq!(answer(&frames), "I don't know.");
+        assert_eq!(status(&frames), Some(Status::Done));
+    }
+
+    #[tokio::test]
+    async fn a_failing_query_embedding_fails_the_search() {
+        let mut state = AppState::offline(&["Unused."], "{}").await;
+        state.embedder = Arc::new(FailingEmbedder);
+        let frames = search_frames(Arc::new(state), offline_request("borrow checker")).await;
+
+        let events = frames
+            .iter()
+            .map(|frame| frame.event.kind())
+            .collect::<Vec<&str>>();
+        assert_eq!(events, vec!["error", "done"]);
+        assert_eq!(stage_errors(&frames)[0].stage, Stage::QueryEmbedding);
+        assert_eq!(status(&frames), Some(Status::Failed));
+    }
+
+    /// Generates a token every few milliseconds until it is dropped.
+    struct EndlessModel {
+        tokens: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LanguageModel for EndlessModel {
+        async fn complete_stream(&self, _request: CompletionRequest) -> Result<CompletionStream> {
+            let tokens = self.tokens.clone();
+            Ok(Box::pin(futures::stream::unfold(
+                tokens,
+                |tokens| async move {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    tokens.fetch_add(1, Ordering::Relaxed);
+                    let chunk = CompletionChunk {
+                        text: "more ".to_string(),
+                    };
+                    Some((Ok(chunk), tokens))
+                },
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_the_stream_stops_the_answer() {
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let mut state = AppState::offline(&["Unused."], "{}").await;
+        state.llm = Arc::new(EndlessModel {
+            tokens: tokens.clone(),
+        });
+
+        let (sender, receiver) = mpsc::channel(10);
+        let failed = Arc::new(AtomicBool::new(false));
+        let token = CancellationToken::new();
+        let mut events = Box::pin(sse::event_stream(
+            receiver,
+            failed.clone(),
+            token.clone(),
+            |status| SearchEvent::Done { status }.into(),
+        ));
+        let request = offline_request("borrow checker");
+        run_search(
+            Arc::new(state),
+            request,
+            sender,
+            failed,
+            token.clone(),
+            false,
+        )
+        .await;
+
+        while let Some(event) = events.next().await {
+            if event.unwrap().to_string().starts_with("event:answer_token") {
+                break;
+            }
+        }
+        assert!(!token.is_cancelled());
+        drop(events);
+        assert!(token.is_cancelled());
+
+        // The token in flight may still be generated, then nothing more
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let generated = tokens.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(tokens.load(Ordering::Relaxed), generated);
+    }
 }
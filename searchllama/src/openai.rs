@@ -0,0 +1,438 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use futures::{future, stream, Stream, StreamExt};
+use log::{error, info};
+use searchllama_types::types::{
+    Citation, SearchEvent, SearchFrame, SearchRequest, Stage, StageState,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, Receiver};
+use tokio_util::sync::CancellationToken;
+use warp::{http::StatusCode, reply::Response, sse::Event, Reply};
+
+use crate::{run_search, sse, AppState};
+
+/// Id under which the search pipeline is listed as a model.
+pub const MODEL_ID: &str = "searchllama";
+
+/// A `/v1/chat/completions` request. Only the last user message is searched
+/// for: earlier turns and sampling parameters are ignored.
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+}
+
+/// Message content is either a string or a list of typed parts.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContentPart {
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+impl MessageContent {
+    fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletion {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<Choice>,
+    /// The sources cited in the answer. Not part of the OpenAI format.
+    sources: Vec<Citation>,
+}
+
+#[derive(Debug, Serialize)]
+struct Choice {
+    index: usize,
+    message: AssistantMessage,
+    finish_reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct AssistantMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletionChunk {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<ChunkChoice>,
+    /// Sent with the last chunk.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<Citation>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChunkChoice {
+    index: usize,
+    delta: Delta,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail {
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+impl ErrorBody {
+    fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            error: ErrorDetail {
+                message: message.into(),
+                kind,
+            },
+        }
+    }
+}
+
+/// Progress of the answer, distilled from the search events.
+enum Update {
+    Token(String),
+    Finished {
+        sources: Vec<Citation>,
+        error: Option<String>,
+    },
+}
+
+/// Follows the search events until the answer is complete or failed.
+fn answer_updates(
+    receiver: Receiver<SearchFrame>,
+    failed: Arc<AtomicBool>,
+) -> impl Stream<Item = Update> {
+    stream::unfold(Some((receiver, Vec::new())), move |state| {
+        let failed = failed.clone();
+        async move {
+            let (mut receiver, mut sources) = state?;
+            loop {
+                let Some(frame) = receiver.recv().await else {
+                    // The pipeline ended without finishing an answer
+                    let error = failed
+                        .load(Ordering::Relaxed)
+                        .then(|| "The search failed".to_string());
+                    return Some((Update::Finished { sources, error }, None));
+                };
+                match frame.event {
+                    SearchEvent::AnswerToken { text } => {
+                        return Some((Update::Token(text), Some((receiver, sources))));
+                    }
+                    SearchEvent::Citation(citation) => sources.push(citation),
+                    SearchEvent::StageStatus {
+                        stage: Stage::Answer,
+                        state: StageState::Finished,
+                    } => {
+                        return Some((
+                            Update::Finished {
+                                sources,
+                                error: None,
+                            },
+                            None,
+                        ))
+                    }
+                    SearchEvent::Error(e)
+                        if matches!(e.stage, Stage::Answer | Stage::QueryEmbedding) =>
+                    {
+                        let error = Some(e.message);
+                        return Some((Update::Finished { sources, error }, None));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    })
+}
+
+fn data(value: &impl Serialize) -> Event {
+    Event::default().json_data(value).unwrap_or_else(|e| {
+        error!("Failed to serialize event: {}", e);
+        Event::default().comment("serialization error")
+    })
+}
+
+/// `POST /v1/chat/completions`: searches for the last user message and
+/// answers in the OpenAI format, streamed or not. The search is private and
+/// starts no chat session, since the client keeps the conversation itself.
+pub async fn chat_completions(
+    state: Arc<AppState>,
+    request: ChatCompletionRequest,
+) -> Result<Response, Infallible> {
+    let Some(query) = request
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .and_then(|m| m.content.as_ref())
+        .map(MessageContent::text)
+        .filter(|text| !text.trim().is_empty())
+    else {
+        let body = ErrorBody::new("invalid_request_error", "No user message to answer");
+        return Ok(
+            warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST)
+                .into_response(),
+        );
+    };
+    info!("Received chat completion request: {}", query);
+
+    let (sender, receiver) = mpsc::channel(10);
+    let failed = Arc::new(AtomicBool::new(false));
+    let token = CancellationToken::new();
+    // Stops the pipeline once the answer is sent or the client went away
+    let guard = token.clone().drop_guard();
+    let search = SearchRequest {
+        answer: Some(true),
+        private: Some(true),
+        ..SearchRequest::new(&query)
+    };
+    run_search(state, search, sender, failed.clone(), token, false).await;
+    let updates = answer_updates(receiver, failed);
+
+    let id = format!("chatcmpl-{}", uuid::Uuid::new_v4().simple());
+    let created = chrono::Utc::now().timestamp();
+    let model = request.model.unwrap_or_else(|| MODEL_ID.to_string());
+
+    if request.stream {
+        let chunk = move |delta: Delta, finish_reason, sources| ChatCompletionChunk {
+            id: id.clone(),
+            object: "chat.completion.chunk",
+            created,
+            model: model.clone(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+            sources,
+        };
+        let role = Delta {
+            role: Some("assistant"),
+            ..Default::default()
+        };
+        let first = data(&chunk(role, None, Vec::new()));
+
+        let events = updates.map(move |update| {
+            let _guard = &guard;
+            match update {
+                Update::Token(text) => {
+                    let delta = Delta {
+                        content: Some(text),
+                        ..Default::default()
+                    };
+                    data(&chunk(delta, None, Vec::new()))
+                }
+                Update::Finished {
+                    error: Some(error), ..
+                } => data(&ErrorBody::new("server_error", error)),
+                Update::Finished { sources, .. } => {
+                    data(&chunk(Delta::default(), Some("stop"), sources))
+                }
+            }
+        });
+        let events = stream::once(future::ready(first))
+            .chain(events)
+            .chain(stream::once(future::ready(Event::default().data("[DONE]"))))
+            .map(Ok);
+
+        return Ok(sse::reply(events).into_response());
+    }
+
+    let mut content = String::new();
+    let mut result = (Vec::new(), None);
+    let mut updates = Box::pin(updates);
+    while let Some(update) = updates.next().await {
+        match update {
+            Update::Token(text) => content.push_str(&text),
+            Update::Finished { sources, error } => result = (sources, error),
+        }
+    }
+    drop(guard);
+
+    let (sources, error) = result;
+    if let (Some(error), true) = (error, content.is_empty()) {
+        let body = ErrorBody::new("server_error", error);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&body),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response());
+    }
+
+    let completion = ChatCompletion {
+        id,
+        object: "chat.completion",
+        created,
+        model,
+        choices: vec![Choice {
+            index: 0,
+            message: AssistantMessage {
+                role: "assistant",
+                content,
+            },
+            finish_reason: "stop",
+        }],
+        sources,
+    };
+    Ok(warp::reply::json(&completion).into_response())
+}
+
+/// `GET /v1/models`
+pub fn models() -> impl Reply {
+    warp::reply::json(&serde_json::json!({
+        "object": "list",
+        "data": [{
+            "id": MODEL_ID,
+            "object": "model",
+            "created": 0,
+            "owned_by": "searchllama",
+        }],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use searchllama_types::types::StageError;
+
+    use super::*;
+
+    /// The updates of a pipeline that sends `events` and then ends.
+    async fn collect_updates(events: Vec<SearchEvent>, failed: bool) -> Vec<Update> {
+        let (sender, receiver) = mpsc::channel(events.len().max(1));
+        for event in events {
+            sender.send(event.into()).await.unwrap();
+        }
+        drop(sender);
+        answer_updates(receiver, Arc::new(AtomicBool::new(failed)))
+            .collect()
+            .await
+    }
+
+    fn error(stage: Stage) -> SearchEvent {
+        SearchEvent::Error(StageError {
+            stage,
+            message: format!("{:?} failed", stage),
+            subject: None,
+        })
+    }
+
+    fn token(text: &str) -> SearchEvent {
+        SearchEvent::AnswerToken {
+            text: text.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn finishes_with_the_answer_and_its_sources() {
+        let citation = Citation {
+            index: 1,
+            url: "https://example.com".to_string(),
+            title: "Example".to_string(),
+            snippet: "text".to_string(),
+        };
+        let updates = collect_updates(
+            vec![
+                error(Stage::Fetch),
+                token("Hello"),
+                SearchEvent::Citation(citation),
+                token(" world"),
+                SearchEvent::StageStatus {
+                    stage: Stage::Answer,
+                    state: StageState::Finished,
+                },
+                token("ignored"),
+            ],
+            false,
+        )
+        .await;
+
+        assert_eq!(updates.len(), 3);
+        assert!(matches!(&updates[0], Update::Token(text) if text == "Hello"));
+        assert!(matches!(&updates[1], Update::Token(text) if text == " world"));
+        match &updates[2] {
+            Update::Finished {
+                sources,
+                error: None,
+            } => assert_eq!(sources[0].url, "https://example.com"),
+            _ => panic!("not finished without an error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_and_embedding_errors_end_the_answer() {
+        for stage in [Stage::Answer, Stage::QueryEmbedding] {
+            let updates =
+                collect_updates(vec![token("Hel"), error(stage), token("lo")], false).await;
+            assert_eq!(updates.len(), 2);
+            assert!(
+                matches!(&updates[1], Update::Finished { error: Some(e), .. } if e.contains("failed"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_closed_channel_finishes_the_answer() {
+        let updates = collect_updates(vec![token("Hello")], false).await;
+        assert!(matches!(
+            &updates[..],
+            [Update::Token(_), Update::Finished { error: None, .. }]
+        ));
+
+        let updates = collect_updates(Vec::new(), true).await;
+        assert!(matches!(
+            &updates[..],
+            [Update::Finished { error: Some(_), .. }]
+        ));
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::debug;
+use reqwest::{StatusCode, Url};
+use scraper::{ElementRef, Html, Selector};
+use searchllama_types::types::TimeRange;
+use tokio::sync::Semaphore;
+
+use super::{SafeSearch, SearchProvider, SearchQuery};
+use crate::{
+    error::{Error, Result},
+    search::SearchResult,
+};
+
+const ENDPOINT: &str = "https://html.duckduckgo.com/html/";
+const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+/// Upper bound on result pages fetched for a single query.
+const MAX_PAGES: usize = 5;
+
+lazy_static! {
+    static ref RESULT: Selector = Selector::parse("div.result").unwrap();
+    static ref TITLE: Selector = Selector::parse("a.result__a").unwrap();
+    static ref SNIPPET: Selector = Selector::parse(".result__snippet").unwrap();
+    static ref NAV_FORM: Selector = Selector::parse("div.nav-link form").unwrap();
+    static ref NEXT_BUTTON: Selector = Selector::parse("input[type=submit]").unwrap();
+    static ref HIDDEN_INPUT: Selector = Selector::parse("input[type=hidden]").unwrap();
+    static ref ANOMALY: Selector = Selector::parse(".anomaly-modal__mask").unwrap();
+}
+
+/// Scrapes the JavaScript-free DuckDuckGo results page.
+pub struct DuckDuckGo {
+    client: reqwest::Client,
+    // DuckDuckGo rate limits aggressively, so queries run one at a time.
+    semaphore: Semaphore,
+    region: String,
+    safe_search: SafeSearch,
+}
+
+/// One parsed results page.
+#[derive(Debug, Default)]
+pub struct ResultsPage {
+    pub results: Vec<SearchResult>,
+    /// Form fields to post to get the next page, if there is one.
+    pub next_page: Option<Vec<(String, String)>>,
+}
+
+impl DuckDuckGo {
+    /// `region` is a DuckDuckGo region code such as `us-en`, or `wt-wt` for
+    /// no region.
+    pub fn new(region: &str, safe_search: SafeSearch) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .expect("Failed to build HTTP client"),
+            semaphore: Semaphore::new(1),
+            region: region.to_string(),
+            safe_search,
+        }
+    }
+
+    async fn fetch_page(&self, form: &[(String, String)]) -> Result<ResultsPage> {
+        let response = self
+            .client
+            .post(ENDPOINT)
+            .form(form)
+            .send()
+            .await
+            .map_err(|e| Error::Search(format!("Failed to query DuckDuckGo: {}", e)))?;
+
+        // A rate-limited request gets a 202 with a captcha instead of results.
+        if response.status() == StatusCode::ACCEPTED {
+            return Err(Error::Search(
+                "DuckDuckGo rate limited the request".to_string(),
+            ));
+        }
+        let html = response
+            .error_for_status()
+            .map_err(|e| Error::Search(format!("Failed to query DuckDuckGo: {}", e)))?
+            .text()
+            .await
+            .map_err(|e| Error::Search(format!("Failed to read DuckDuckGo response: {}", e)))?;
+
+        parse_page(&html)
+    }
+}
+
+fn safe_search_param(safe_search: SafeSearch) -> &'static str {
+    match safe_search {
+        SafeSearch::Strict => "1",
+        SafeSearch::Moderate => "-1",
+        SafeSearch::Off => "-2",
+    }
+}
+
+fn time_range_param(time_range: TimeRange) -> &'static str {
+    match time_range {
+        TimeRange::Day => "d",
+        TimeRange::Week => "w",
+        TimeRange::Month => "m",
+        TimeRange::Year => "y",
+    }
+}
+
+/// Sets `key` in a form, replacing any existing value.
+fn set_param(form: &mut Vec<(String, String)>, key: &str, value: &str) {
+    form.retain(|(k, _)| k != key);
+    form.push((key.to_string(), value.to_string()));
+}
+
+#[async_trait]
+impl SearchProvider for DuckDuckGo {
+    fn name(&self) -> &str {
+        "duckduckgo"
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .map_err(|e| Error::Search(format!("Failed to acquire semaphore: {}", e)))?;
+
+        let region = query.region.as_deref().unwrap_or(&self.region);
+        let safe_search = safe_search_param(query.safe_search.unwrap_or(self.safe_search));
+
+        let mut form = vec![("q".to_string(), query.query.clone())];
+        let mut results = Vec::new();
+        let mut seen = HashSet::new();
+        for page in 0..MAX_PAGES {
+            // The next-page form carries the region but not the safe-search
+            // level, so both are set explicitly on every request.
+            set_param(&mut form, "kl", region);
+            set_param(&mut form, "kp", safe_search);
+            if let Some(time_range) = query.time_range {
+                set_param(&mut form, "df", time_range_param(time_range));
+            }
+
+            let ResultsPage {
+                results: page_results,
+                next_page,
+            } = self.fetch_page(&form).await?;
+            debug!(
+                "DuckDuckGo page {} for '{}': {} results",
+                page,
+                query.query,
+                page_results.len()
+            );
+
+            results.extend(
+                page_results
+                    .into_iter()
+                    .filter(|r| seen.insert(r.url.clone())),
+            );
+            if results.len() >= query.max_results {
+                break;
+            }
+            match next_page {
+                Some(next_form) => form = next_form,
+                None => break,
+            }
+        }
+        results.truncate(query.max_results);
+
+        Ok(results)
+    }
+}
+
+pub fn parse_page(html: &str) -> Result<ResultsPage> {
+    let document = Html::parse_document(html);
+
+    if document.select(&ANOMALY).next().is_some() {
+        return Err(Error::Search(
+            "DuckDuckGo rate limited the request".to_string(),
+        ));
+    }
+
+    let results = document
+        .select(&RESULT)
+        .filter(|result| !result.value().classes().any(|c| c == "result--ad"))
+        .filter_map(parse_result)
+        .collect();
+
+    let next_page = document
+        .select(&NAV_FORM)
+        .find(|form| {
+            form.select(&NEXT_BUTTON)
+                .any(|button| button.value().attr("value") == Some("Next"))
+        })
+        .map(|form| {
+            form.select(&HIDDEN_INPUT)
+                .filter_map(|input| {
+                    let name = input.value().attr("name")?;
+                    let value = input.value().attr("value").unwrap_or_default();
+                    Some((name.to_string(), value.to_string()))
+                })
+                .collect()
+        });
+
+    Ok(ResultsPage { results, next_page })
+}
+
+fn parse_result(result: ElementRef) -> Option<SearchResult> {
+    let link = result.select(&TITLE).next()?;
+    let url = decode_redirect(link.value().attr("href")?)?;
+    let title = collapse_whitespace(&link.text().collect::<String>());
+    let body = result
+        .select(&SNIPPET)
+        .next()
+        .map(|s| collapse_whitespace(&s.text().collect::<String>()))
+        .unwrap_or_default();
+
+    Some(SearchResult { url, title, body })
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Result links point to `//duckduckgo.com/l/?uddg=<target>`; returns the
+/// target, or the link itself when it is not a redirect.
+fn decode_redirect(href: &str) -> Option<String> {
+    let absolute = match href {
+        h if h.starts_with("//") => format!("https:{}", h),
+        h if h.starts_with('/') => format!("https://duckduckgo.com{}", h),
+        h => h.to_string(),
+    };
+    let url = Url::parse(&absolute).ok()?;
+
+    let target = if url.domain() == Some("duckduckgo.com") && url.path() == "/l/" {
+        url.query_pairs()
+            .find(|(key, _)| key == "uddg")
+            .map(|(_, target)| target.into_owned())?
+    } else {
+        url.into()
+    };
+
+    target.starts_with("http").then_some(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESULTS_PAGE: &str = include_str!("../../fixtures/duckduckgo/results.html");
+    const LAST_PAGE: &str = include_str!("../../fixtures/duckduckgo/last_page.html");
+    const NO_RESULTS: &str = include_str!("../../fixtures/duckduckgo/no_results.html");
+    const ANOMALY_PAGE: &str = include_str!("../../fixtures/duckduckgo/anomaly.html");
+
+    #[test]
+    fn parses_results_and_skips_ads() {
+        let page = parse_page(RESULTS_PAGE).unwrap();
+        let urls = page
+            .results
+            .iter()
+            .map(|r| r.url.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(
+            urls,
+            vec![
+                "https://www.rust-lang.org/",
+                "https://doc.rust-lang.org/book/",
+                "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+            ]
+        );
+        assert_eq!(page.results[0].title, "Rust Programming Language");
+        assert_eq!(
+            page.results[0].body,
+            "A language empowering everyone to build reliable and efficient software."
+        );
+    }
+
+    #[test]
+    fn decodes_redirect_links() {
+        assert_eq!(
+            decode_redirect(
+                "//duckduckgo.com/l/?uddg=https%3A%2F%2Fexample.com%2Fa%3Fb%3Dc%26d%3De&rut=abc"
+            )
+            .as_deref(),
+            Some("https://example.com/a?b=c&d=e")
+        );
+        assert_eq!(
+            decode_redirect("https://example.com/direct").as_deref(),
+            Some("https://example.com/direct")
+        );
+        assert_eq!(decode_redirect("//duckduckgo.com/l/?rut=abc"), None);
+    }
+
+    #[test]
+    fn result_without_snippet_has_empty_body() {
+        let page = parse_page(LAST_PAGE).unwrap();
+
+        assert_eq!(page.results.len(), 2);
+        assert_eq!(page.results[1].url, "https://blog.rust-lang.org/");
+        assert_eq!(page.results[1].body, "");
+    }
+
+    #[test]
+    fn extracts_next_page_form() {
+        let page = parse_page(RESULTS_PAGE).unwrap();
+        let next = page.next_page.unwrap();
+
+        assert!(next.contains(&("q".to_string(), "rust".to_string())));
+        assert!(next.contains(&("s".to_string(), "10".to_string())));
+        assert!(next.contains(&("dc".to_string(), "11".to_string())));
+        assert!(next.contains(&("kl".to_string(), "us-en".to_string())));
+    }
+
+    #[test]
+    fn last_page_has_no_next_page() {
+        // The last page only has a "Previous" button.
+        assert!(parse_page(LAST_PAGE).unwrap().next_page.is_none());
+    }
+
+    #[test]
+    fn no_results_page_is_empty() {
+        let page = parse_page(NO_RESULTS).unwrap();
+
+        assert!(page.results.is_empty());
+        assert!(page.next_page.is_none());
+    }
+
+    #[test]
+    fn anomaly_page_is_an_error() {
+        assert!(parse_page(ANOMALY_PAGE).is_err());
+    }
+
+    #[test]
+    fn set_param_replaces_existing_value() {
+        let mut form = vec![
+            ("q".to_string(), "rust".to_string()),
+            ("kl".to_string(), "wt-wt".to_string()),
+        ];
+        set_param(&mut form, "kl", "de-de");
+
+        assert_eq!(
+            form,
+            vec![
+                ("q".to_string(), "rust".to_string()),
+                ("kl".to_string(), "de-de".to_string()),
+            ]
+        );
+    }
+}
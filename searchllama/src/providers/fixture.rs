@@ -0,0 +1,48 @@
+use std::{collections::HashMap, path::Path};
+
+use async_trait::async_trait;
+
+use super::{SearchProvider, SearchQuery};
+use crate::{
+    error::{Error, Result},
+    search::SearchResult,
+};
+
+/// Key whose results are returned for queries missing from the fixture.
+const FALLBACK_KEY: &str = "*";
+
+/// Serves canned results from a JSON file mapping each query to a list of
+/// `{ "url", "title", "body" }` objects.
+pub struct FixtureProvider {
+    results: HashMap<String, Vec<SearchResult>>,
+}
+
+impl FixtureProvider {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Search(format!("Failed to read {}: {}", path.display(), e)))?;
+        let results = serde_json::from_str(&contents)
+            .map_err(|e| Error::Search(format!("Failed to parse {}: {}", path.display(), e)))?;
+
+        Ok(Self { results })
+    }
+}
+
+#[async_trait]
+impl SearchProvider for FixtureProvider {
+    fn name(&self) -> &str {
+        "fixture"
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+        let mut results = self
+            .results
+            .get(&query.query)
+            .or_else(|| self.results.get(FALLBACK_KEY))
+            .cloned()
+            .unwrap_or_default();
+        results.truncate(query.max_results);
+
+        Ok(results)
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+pub use searchllama_types::types::SafeSearch;
+use searchllama_types::types::TimeRange;
+
+use crate::{
+    config::{Config, SearchBackend},
+    error::{Error, Result},
+    search::{ImageSearchResult, SearchResult},
+};
+
+pub mod duckduckgo;
+pub mod fixture;
+pub mod searxng;
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub query: String,
+    pub max_results: usize,
+    /// Provider-specific region code; `None` uses the provider default.
+    pub region: Option<String>,
+    pub safe_search: Option<SafeSearch>,
+    pub time_range: Option<TimeRange>,
+}
+
+impl SearchQuery {
+    pub fn new(query: impl Into<String>, max_results: usize) -> Self {
+        Self {
+            query: query.into(),
+            max_results,
+            region: None,
+            safe_search: None,
+            time_range: None,
+        }
+    }
+}
+
+/// A web search engine.
+#[async_trait]
+pub trait SearchProvider: Send + Sync {
+    /// Short identifier, used in cache keys and logs.
+    fn name(&self) -> &str;
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>>;
+
+    async fn images(&self, _query: &SearchQuery) -> Result<Vec<ImageSearchResult>> {
+        Err(Error::Search(format!(
+            "{} does not support image search",
+            self.name()
+        )))
+    }
+}
+
+pub fn from_config(config: &Config) -> Result<Arc<dyn SearchProvider>> {
+    Ok(match config.web_search.backend {
+        SearchBackend::DuckDuckGo => Arc::new(duckduckgo::DuckDuckGo::new(
+            &config.duckduckgo.region,
+            config.duckduckgo.safe_search,
+        )),
+        SearchBackend::SearXng => Arc::new(searxng::SearXng::new(&config.searxng.url)),
+        SearchBackend::Fixture => {
+            Arc::new(fixture::FixtureProvider::load(&config.search_fixture.path)?)
+        }
+    })
+}
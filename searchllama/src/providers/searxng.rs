@@ -0,0 +1,260 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::{SafeSearch, SearchProvider, SearchQuery};
+use crate::{
+    error::{Error, Result},
+    search::{ImageSearchResult, SearchResult},
+};
+
+/// Number of result pages requested at most to fill `max_results`.
+const MAX_PAGES: usize = 3;
+
+/// A SearXNG instance with the JSON output format enabled.
+pub struct SearXng {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct SearXngResponse {
+    results: Vec<SearXngResult>,
+}
+
+#[derive(Deserialize)]
+struct SearXngResult {
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    content: String,
+    img_src: Option<String>,
+}
+
+impl SearXng {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn query(
+        &self,
+        query: &SearchQuery,
+        category: &str,
+        page: usize,
+    ) -> Result<Vec<SearXngResult>> {
+        let mut params = vec![
+            ("q", query.query.clone()),
+            ("format", "json".to_string()),
+            ("categories", category.to_string()),
+            ("pageno", page.to_string()),
+        ];
+        if let Some(region) = &query.region {
+            params.push(("language", region.clone()));
+        }
+        if let Some(safe_search) = query.safe_search {
+            params.push(("safesearch", safe_search_param(safe_search).to_string()));
+        }
+        if let Some(time_range) = query.time_range {
+            params.push(("time_range", time_range.to_string()));
+        }
+
+        let response = self
+            .client
+            .get(format!("{}/search", self.base_url))
+            .query(&params)
+            .send()
+            .await
+            .map_err(|e| Error::Search(format!("Failed to query SearXNG: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(Error::Search(format!(
+                "SearXNG returned {}",
+                response.status()
+            )));
+        }
+
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Error::Search(format!("Failed to read SearXNG response: {}", e)))?;
+        parse_response(&body)
+    }
+
+    async fn query_pages(&self, query: &SearchQuery, category: &str) -> Result<Vec<SearXngResult>> {
+        let mut results = Vec::new();
+        for page in 1..=MAX_PAGES {
+            let page_results = self.query(query, category, page).await?;
+            if page_results.is_empty() {
+                break;
+            }
+            results.extend(page_results);
+            if results.len() >= query.max_results {
+                break;
+            }
+        }
+        results.truncate(query.max_results);
+
+        Ok(results)
+    }
+}
+
+fn parse_response(body: &str) -> Result<Vec<SearXngResult>> {
+    Ok(serde_json::from_str::<SearXngResponse>(body)
+        .map_err(|e| Error::Search(format!("Failed to decode SearXNG response: {}", e)))?
+        .results)
+}
+
+fn safe_search_param(safe_search: SafeSearch) -> &'static str {
+    match safe_search {
+        SafeSearch::Off => "0",
+        SafeSearch::Moderate => "1",
+        SafeSearch::Strict => "2",
+    }
+}
+
+#[async_trait]
+impl SearchProvider for SearXng {
+    fn name(&self) -> &str {
+        "searxng"
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+        Ok(self
+            .query_pages(query, "general")
+            .await?
+            .into_iter()
+            .map(|r| SearchResult {
+                url: r.url,
+                title: r.title,
+                body: r.content,
+            })
+            .collect())
+    }
+
+    async fn images(&self, query: &SearchQuery) -> Result<Vec<ImageSearchResult>> {
+        Ok(self
+            .query_pages(query, "images")
+            .await?
+            .into_iter()
+            .filter_map(|r| {
+                r.img_src.map(|img_url| ImageSearchResult {
+                    img_url,
+                    title: r.title,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use searchllama_types::types::TimeRange;
+
+    use super::*;
+
+    const RESULTS: &str = include_str!("../../fixtures/searxng/results.json");
+    const NO_RESULTS: &str = include_str!("../../fixtures/searxng/no_results.json");
+
+    /// Answers one request per response with the status and JSON body, and
+    /// reports the request lines.
+    fn serve(responses: Vec<(u16, &'static str)>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                requests
+                    .send(request.lines().next().unwrap_or_default().to_string())
+                    .unwrap();
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[test]
+    fn parses_saved_responses() {
+        let results = parse_response(RESULTS).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].url,
+            "https://doc.rust-lang.org/book/ch04-02-references-and-borrowing.html"
+        );
+        assert!(results[0]
+            .content
+            .starts_with("A reference is like a pointer"));
+        // Results without content have an empty body
+        assert_eq!(results[1].content, "");
+        assert_eq!(results[0].img_src, None);
+        assert!(results[2].img_src.is_some());
+
+        assert!(parse_response(NO_RESULTS).unwrap().is_empty());
+        assert!(matches!(
+            parse_response("<html>format not enabled</html>"),
+            Err(Error::Search(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn searches_pages_until_they_run_out() {
+        let (url, requests) = serve(vec![(200, RESULTS), (200, NO_RESULTS)]);
+        let query = SearchQuery {
+            safe_search: Some(SafeSearch::Strict),
+            time_range: Some(TimeRange::Week),
+            ..SearchQuery::new("rust borrow checker", 10)
+        };
+
+        let results = SearXng::new(&url).search(&query).await.unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[1].title,
+            "MIR borrow check - Rust Compiler Development Guide"
+        );
+
+        let first = requests.recv().unwrap();
+        assert!(first.starts_with("GET /search?q=rust+borrow+checker&format=json"));
+        assert!(first.contains("&pageno=1&safesearch=2&time_range=week"));
+        assert!(requests.recv().unwrap().contains("&pageno=2"));
+    }
+
+    #[tokio::test]
+    async fn stops_at_max_results_and_reports_errors() {
+        let (url, _requests) = serve(vec![(200, RESULTS), (500, "{}"), (200, NO_RESULTS)]);
+        let searxng = SearXng::new(&url);
+
+        let results = searxng.search(&SearchQuery::new("rust", 2)).await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        let error = searxng
+            .search(&SearchQuery::new("rust", 2))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Search(ref message) if message.contains("500")));
+
+        let images = searxng.images(&SearchQuery::new("rust", 2)).await.unwrap();
+        assert!(images.is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! Blends the embedding similarity of results with their BM25 score.
+//!
+//! Both blends depend on the other results: reciprocal rank fusion on the
+//! ranks, the weighted sum on the best BM25 score. A score is only meaningful
+//! among the results it was fused with.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use searchllama_types::types::Entry;
+
+use crate::config::{Fusion, RankingConfig};
+
+/// Least time between two batches of changed results, so that a new page
+/// does not resend every result it moved.
+const CHANGES_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The two scores a result is ranked by.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Scores {
+    /// `search::calculate_entry_similarity` of the query and the page.
+    pub similarity: f64,
+    /// BM25 of the query terms in the page, 0 if it has none.
+    pub lexical: f64,
+}
+
+/// Ranks of `values` from 1, highest first. Ties keep the order of `keys`.
+fn ranks(values: &[f64], keys: &[&str]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[b].total_cmp(&values[a]).then(keys[a].cmp(keys[b])));
+    let mut ranks = vec![0; values.len()];
+    for (rank, idx) in order.into_iter().enumerate() {
+        ranks[idx] = rank + 1;
+    }
+    ranks
+}
+
+/// The blended score of every result. `keys` breaks ties between ranks.
+pub fn fuse(config: &RankingConfig, scores: &[Scores], keys: &[&str]) -> Vec<f64> {
+    match config.fusion {
+        Fusion::Rrf => {
+            let similarity: Vec<f64> = scores.iter().map(|s| s.similarity).collect();
+            let lexical: Vec<f64> = scores.iter().map(|s| s.lexical).collect();
+            let similarity_ranks = ranks(&similarity, keys);
+            let lexical_ranks = ranks(&lexical, keys);
+            scores
+                .iter()
+                .enumerate()
+                .map(|(idx, s)| {
+                    let mut score = 1.0 / (config.rrf_k + similarity_ranks[idx] as f64);
+                    // Pages without any of the terms have no lexical rank
+                    if s.lexical > 0.0 {
+                        score += 1.0 / (config.rrf_k + lexical_ranks[idx] as f64);
+                    }
+                    score
+                })
+                .collect()
+        }
+        Fusion::Weighted => {
+            let best = scores.iter().map(|s| s.lexical).fold(0.0, f64::max);
+            let weight = config.lexical_weight;
+            scores
+                .iter()
+                .map(|s| {
+                    let lexical = if best > 0.0 { s.lexical / best } else { 0.0 };
+                    (1.0 - weight) * s.similarity + weight * lexical
+                })
+                .collect()
+        }
+    }
+}
+
+/// The results of a search as they come in, with their blended scores.
+#[derive(Debug, Clone)]
+pub struct LiveRanking {
+    config: RankingConfig,
+    entries: Vec<(Entry, Scores)>,
+    /// URLs of the results that changed since the last batch.
+    changed: HashSet<String>,
+    last_batch: Option<Instant>,
+}
+
+impl LiveRanking {
+    pub fn new(config: RankingConfig) -> Self {
+        Self {
+            config,
+            entries: Vec::new(),
+            changed: HashSet::new(),
+            last_batch: None,
+        }
+    }
+
+    /// Adds or replaces a result, and rescores the others.
+    pub fn upsert(&mut self, entry: Entry, scores: Scores) {
+        match self.entries.iter_mut().find(|(e, _)| e.url == entry.url) {
+            Some(existing) => *existing = (entry.clone(), scores),
+            None => self.entries.push((entry.clone(), scores)),
+        }
+
+        let all: Vec<Scores> = self.entries.iter().map(|(_, s)| *s).collect();
+        let keys: Vec<&str> = self.entries.iter().map(|(e, _)| e.url.as_str()).collect();
+        let fused = fuse(&self.config, &all, &keys);
+
+        for ((e, _), score) in self.entries.iter_mut().zip(fused) {
+            if e.url == entry.url || (e.score - score).abs() > 1e-12 {
+                e.score = score;
+                self.changed.insert(e.url.clone());
+            }
+        }
+    }
+
+    /// The results whose score changed since the last batch, with their
+    /// current score, unless the last batch was taken too recently at `now`.
+    pub fn due_changes(&mut self, now: Instant) -> Vec<Entry> {
+        if self
+            .last_batch
+            .is_some_and(|last| now.duration_since(last) < CHANGES_INTERVAL)
+        {
+            return Vec::new();
+        }
+        self.last_batch = Some(now);
+        self.all_changes()
+    }
+
+    /// The results whose score changed since the last batch, however recent.
+    pub fn all_changes(&mut self) -> Vec<Entry> {
+        let changed = std::mem::take(&mut self.changed);
+        self.entries
+            .iter()
+            .filter(|(e, _)| changed.contains(&e.url))
+            .map(|(e, _)| e.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(fusion: Fusion) -> RankingConfig {
+        RankingConfig {
+            fusion,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rank_fusion_rewards_lexical_matches() {
+        let scores = [
+            Scores {
+                similarity: 0.9,
+                lexical: 0.0,
+            },
+            Scores {
+                similarity: 0.8,
+                lexical: 5.0,
+            },
+        ];
+        let fused = fuse(&config(Fusion::Rrf), &scores, &["a", "b"]);
+        assert_eq!(fused[0], 1.0 / 61.0);
+        assert_eq!(fused[1], 1.0 / 62.0 + 1.0 / 61.0);
+
+        // Without a lexical weight the similarity alone decides
+        let unweighted = RankingConfig {
+            lexical_weight: 0.0,
+            ..config(Fusion::Weighted)
+        };
+        assert_eq!(fuse(&unweighted, &scores, &["a", "b"]), vec![0.9, 0.8]);
+    }
+
+    #[test]
+    fn live_ranking_reports_changed_scores() {
+        let mut ranking = LiveRanking::new(config(Fusion::Weighted));
+        let entry = |url: &str| Entry {
+            score: 0.0,
+            url: url.to_string(),
+            title: String::new(),
+            description: String::new(),
+        };
+        ranking.upsert(
+            entry("a"),
+            Scores {
+                similarity: 0.5,
+                lexical: 1.0,
+            },
+        );
+        assert_eq!(ranking.all_changes().len(), 1);
+
+        // A better lexical match lowers the normalized score of the first
+        ranking.upsert(
+            entry("b"),
+            Scores {
+                similarity: 0.5,
+                lexical: 2.0,
+            },
+        );
+        let changed = ranking.all_changes();
+        let urls: Vec<&str> = changed.iter().map(|e| e.url.as_str()).collect();
+        assert_eq!(urls, vec!["a", "b"]);
+        assert!(changed[1].score > changed[0].score);
+
+        // Nothing else moves when a result without terms comes in
+        ranking.upsert(entry("c"), Scores::default());
+        assert_eq!(ranking.all_changes().len(), 1);
+    }
+
+    #[test]
+    fn changes_are_batched() {
+        let mut ranking = LiveRanking::new(config(Fusion::Rrf));
+        let entry = |url: String| Entry {
+            score: 0.0,
+            url,
+            title: String::new(),
+            description: String::new(),
+        };
+        let scores = |i: usize| Scores {
+            similarity: i as f64 / 100.0,
+            lexical: i as f64,
+        };
+
+        // The first page goes out at once
+        let start = Instant::now();
+        ranking.upsert(entry("0".to_string()), scores(0));
+        assert_eq!(ranking.due_changes(start).len(), 1);
+
+        // Each better page moves every other one, but they are sent together
+        for i in 1..20 {
+            ranking.upsert(entry(i.to_string()), scores(i));
+            assert!(ranking.due_changes(start + CHANGES_INTERVAL / 2).is_empty());
+        }
+        let changed = ranking.due_changes(start + CHANGES_INTERVAL);
+        assert_eq!(changed.len(), 20);
+        assert_eq!(changed[19].score, 2.0 / 61.0);
+        assert!(ranking.all_changes().is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use std::{convert::Infallible, sync::Arc};
+
+use log::{info, warn};
+use searchllama_types::types::{Entry, ResultsPage, ResultsRequest};
+use warp::{http::StatusCode, reply::Response, Reply};
+
+use crate::{database, embedding, error::error_reply, search::ResultFilter, AppState};
+
+/// Largest page `/results` returns.
+const MAX_PAGE_SIZE: usize = 200;
+/// Pages of the index that are ranked; the rest are never listed.
+const RANKING_DEPTH: usize = 1000;
+
+/// Position in the ranking: the score and URL of the last result of a page.
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    score: f64,
+    url: String,
+}
+
+impl Cursor {
+    /// The score is stored bit for bit so that it compares exactly.
+    fn encode(&self) -> String {
+        format!("{:016x}{}", self.score.to_bits(), self.url)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let bits = cursor.get(..16)?;
+        Some(Self {
+            score: f64::from_bits(u64::from_str_radix(bits, 16).ok()?),
+            url: cursor[16..].to_string(),
+        })
+    }
+
+    /// Whether `(score, url)` is ranked at or before the cursor, in the order
+    /// of `query_db`: score descending, then URL ascending.
+    fn is_past(&self, score: f64, url: &str) -> bool {
+        score > self.score || (score == self.score && url <= self.url.as_str())
+    }
+}
+
+/// Cuts a page out of `ranked`, starting after `cursor` if given and at
+/// `offset` otherwise.
+fn page(ranked: &[Entry], limit: usize, offset: usize, cursor: Option<&Cursor>) -> ResultsPage {
+    let start = match cursor {
+        Some(cursor) => ranked.partition_point(|entry| cursor.is_past(entry.score, &entry.url)),
+        None => offset.min(ranked.len()),
+    };
+    let end = (start + limit).min(ranked.len());
+
+    let results = ranked[start..end].to_vec();
+    let next_cursor = match results.last() {
+        Some(last) if end < ranked.len() => Some(
+            Cursor {
+                score: last.score,
+                url: last.url.clone(),
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+
+    ResultsPage {
+        results,
+        next_cursor,
+        total: ranked.len(),
+    }
+}
+
+/// `GET /results` or `POST /results`: a page of the local index ranked for a
+/// query, without searching the web.
+pub async fn handle_results_request(
+    state: Arc<AppState>,
+    request: ResultsRequest,
+) -> Result<Response, Infallible> {
+    info!("Received results request: {:?}", request);
+
+    let cursor = match request.cursor.as_deref() {
+        Some(cursor) => match Cursor::decode(cursor) {
+            Some(cursor) => Some(cursor),
+            None => return Ok(error_reply(StatusCode::BAD_REQUEST, "Invalid cursor")),
+        },
+        None => None,
+    };
+    let limit = request
+        .limit
+        .unwrap_or(state.config.search.max_entries)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let query_embedding = match embedding::generate_embedding(&state, &request.query).await {
+        Ok(query_embedding) => query_embedding,
+        Err(e) => {
+            warn!("Failed to embed results query: {}", e);
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &e.to_string(),
+            ));
+        }
+    };
+
+    let filter = ResultFilter::new(&request.sources, request.time_range);
+    let ranked = match database::lexical_scorer(&state.db, &request.query).await {
+        Ok(bm25) => {
+            database::query_db(
+                &state.db,
+                &state.vectors,
+                &state.encoding,
+                &state.config.ranking,
+                &bm25,
+                &query_embedding,
+                &filter,
+                RANKING_DEPTH,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    match ranked {
+        Ok(ranked) => {
+            let ranked: Vec<Entry> = ranked.into_iter().map(|page| page.entry).collect();
+            let page = page(&ranked, limit, request.offset.unwrap_or(0), cursor.as_ref());
+            Ok(warp::reply::json(&page).into_response())
+        }
+        Err(e) => {
+            warn!("Failed to query the index: {}", e);
+            Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &e.to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked() -> Vec<Entry> {
+        [
+            ("a", 0.9),
+            ("b", 0.5),
+            ("c", 0.5),
+            ("d", -0.25),
+            ("e", -0.5),
+        ]
+        .into_iter()
+        .map(|(url, score)| Entry {
+            score,
+            url: url.to_string(),
+            title: String::new(),
+            description: String::new(),
+        })
+        .collect()
+    }
+
+    fn urls(page: &ResultsPage) -> Vec<&str> {
+        page.results.iter().map(|e| e.url.as_str()).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            score: -0.123456789,
+            url: "https://example.com/a?b=c".to_string(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), None);
+    }
+
+    #[test]
+    fn cursor_pages_cover_the_ranking_once() {
+        let ranked = ranked();
+        let first = page(&ranked, 2, 0, None);
+        assert_eq!(urls(&first), vec!["a", "b"]);
+        assert_eq!(first.total, 5);
+
+        let cursor = Cursor::decode(first.next_cursor.as_deref().unwrap()).unwrap();
+        let second = page(&ranked, 2, 0, Some(&cursor));
+        assert_eq!(urls(&second), vec!["c", "d"]);
+
+        let cursor = Cursor::decode(second.next_cursor.as_deref().unwrap()).unwrap();
+        let last = page(&ranked, 2, 0, Some(&cursor));
+        assert_eq!(urls(&last), vec!["e"]);
+        assert!(last.next_cursor.is_none());
+    }
+
+    #[test]
+    fn offsets_past_the_end_are_empty() {
+        let ranked = ranked();
+        assert_eq!(urls(&page(&ranked, 2, 3, None)), vec!["d", "e"]);
+        let past = page(&ranked, 2, 10, None);
+        assert!(past.results.is_empty() && past.next_cursor.is_none());
+    }
+}
@@ -1,91 +1,47 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::sync::Arc;
 
 use async_recursion::async_recursion;
 use cached::proc_macro::io_cached;
 use cached::DiskCache;
-use lazy_static::lazy_static;
-use log::info;
-use ollama_rs::{generation::completion::request::GenerationRequest, Ollama};
-use playwright::{
-    api::{browser, BrowserContext},
-    Playwright,
-};
-use pyo3::prelude::*;
+use log::warn;
+use reqwest::Url;
+use searchllama_types::types::{SearchRequest, TimeRange};
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinSet;
-use tqdm::tqdm;
 
 use crate::{
+    database,
     embedding::{self, get_website_embedding, vec_cos_sim},
-    MAX_EMBEDDING_SIZE, SNIPPET_TARGET_SIZE,
+    error::{Error, Result},
+    fetch,
+    providers::SearchQuery,
+    AppState,
 };
 
-lazy_static! {
-    static ref DDGS: Py<PyAny> = {
-        Python::with_gil(|py| -> Py<PyAny> {
-            py.import_bound("duckduckgo_search")
-                .unwrap()
-                .getattr("DDGS")
-                .unwrap()
-                .into()
-        })
-    };
-    static ref REQUEST_CLIENT: reqwest::Client = reqwest::Client::new();
-    static ref OLLAMA: ollama_rs::Ollama = ollama_rs::Ollama::new("http://192.168.1.199", 11434);
-}
-
-lazy_static! {
-    static ref DDG_SEMAPHORE: tokio::sync::Semaphore = tokio::sync::Semaphore::new(1);
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SearchResult {
     pub url: String,
     pub title: String,
     pub body: String,
 }
+/// Results of a web search, cached for a day. Searches limited to a time
+/// range are relative to now, so they are never cached.
+pub async fn query_web(state: &AppState, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+    if query.time_range.is_some() {
+        return state.search_provider.search(query).await;
+    }
+    query_web_cached(state, query).await
+}
+
 #[io_cached(
-    map_error = r##" | e | { format!("Failed to cache: {}", e) }"##,
+    map_error = r##" | e | { Error::Cache(e.to_string()) }"##,
     disk = true,
-    convert = r#"{ format!("{}{}", query, max_results) }"#,
+    time = 86400,
+    convert = r#"{ format!("{}:{:?}", state.search_provider.name(), query) }"#,
     ty = "DiskCache<String, Vec<SearchResult>>"
 )]
-pub async fn query_ddg(query: &str, max_results: usize) -> Result<Vec<SearchResult>, String> {
-    let mut outputs = Vec::new();
-
-    let _permit = DDG_SEMAPHORE.acquire().await.unwrap();
-
-    Python::with_gil(|py| {
-        let code = PyModule::from_code_bound(
-            py,
-            "def gert(query, max_results):
-           from duckduckgo_search import DDGS
-           results = DDGS().text(query, max_results=max_results)
-           return results
-        ",
-            "",
-            "",
-        )
-        .expect("Failed to import module")
-        .getattr("gert")
-        .expect("Failed to get attribute");
-
-        let results: Vec<HashMap<String, String>> =
-            code.call1((query, max_results)).unwrap().extract().unwrap();
-
-        outputs.extend(results.into_iter().map(|r| SearchResult {
-            url: r["href"].to_string(),
-            title: r["title"].to_string(),
-            body: r["body"].to_string(),
-        }));
-
-        //info!("Results: {:?}", results);
-    });
-
-    Ok(outputs)
+async fn query_web_cached(state: &AppState, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+    state.search_provider.search(query).await
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -94,46 +50,57 @@ pub struct ImageSearchResult {
     pub title: String,
 }
 #[io_cached(
-    map_error = r##" | e | { format!("Failed to cache: {}", e) }"##,
+    map_error = r##" | e | { Error::Cache(e.to_string()) }"##,
     disk = true,
-    convert = r#"{ format!("{}{}", query, max_results) }"#,
+    convert = r#"{ format!("{}:{:?}", state.search_provider.name(), query) }"#,
     ty = "DiskCache<String, Vec<ImageSearchResult>>"
 )]
-pub async fn query_ddg_images(
-    query: &str,
-    max_results: usize,
-) -> Result<Vec<ImageSearchResult>, String> {
-    let mut results = Vec::new();
+pub async fn query_images(state: &AppState, query: &SearchQuery) -> Result<Vec<ImageSearchResult>> {
+    state.search_provider.images(query).await
+}
 
-    let _permit = DDG_SEMAPHORE
-        .acquire()
-        .await
-        .expect("Failed to acquire semaphore");
+/// Builds the web search for `query` with the options of `request`.
+pub fn web_query(request: &SearchRequest, query: &str, max_results: usize) -> SearchQuery {
+    SearchQuery {
+        region: request.region.clone(),
+        safe_search: request.safe_search,
+        time_range: request.time_range,
+        ..SearchQuery::new(query, max_results)
+    }
+}
 
-    Python::with_gil(|py| {
-        let code = PyModule::from_code_bound(
-            py,
-            "def gert(query, max_results):
-    from duckduckgo_search import DDGS
-    with DDGS() as ddgs:
-        search_results = ddgs.images(query, max_images=max_results)
-        return search_results",
-            "",
-            "",
-        )
-        .expect("Failed to create Python module")
-        .getattr("gert")
-        .expect("Failed to get function");
+/// Which results a search may return.
+#[derive(Debug, Clone, Default)]
+pub struct ResultFilter {
+    /// Allowed domains; empty allows all.
+    pub sources: Vec<String>,
+    /// Unix time before which cached entries are too old.
+    pub indexed_after: Option<i64>,
+}
 
-        let r: Vec<HashMap<String, String>> =
-            code.call1((query, max_results)).unwrap().extract().unwrap();
-        results.extend(r.into_iter().map(|r| ImageSearchResult {
-            img_url: r["image"].clone(),
-            title: r["title"].clone(),
-        }));
-    });
+impl ResultFilter {
+    pub fn new(sources: &[String], time_range: Option<TimeRange>) -> Self {
+        Self {
+            sources: sources.to_vec(),
+            indexed_after: time_range
+                .map(|range| chrono::Utc::now().timestamp() - range.duration().as_secs() as i64),
+        }
+    }
 
-    Ok(results)
+    pub fn allows_url(&self, url: &str) -> bool {
+        if self.sources.is_empty() {
+            return true;
+        }
+        let Some(domain) = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+        else {
+            return false;
+        };
+        self.sources
+            .iter()
+            .any(|source| fetch::domain_matches(&domain, source))
+    }
 }
 
 pub fn calculate_entry_similarity(
@@ -158,166 +125,223 @@ pub struct SnippetInfo {
     pub title: Option<String>,
     pub url: Option<String>,
 }
+/// The chunks stored for `url` if there are any and they can be compared
+/// with the query.
+async fn stored_chunks(
+    state: &AppState,
+    url: &str,
+    query_embedding: &[f64],
+) -> Option<Vec<database::Chunk>> {
+    match database::page_chunks(&state.db, &state.encoding, url).await {
+        Ok(chunks)
+            if !chunks.is_empty()
+                && chunks
+                    .iter()
+                    .all(|chunk| chunk.embedding.len() == query_embedding.len()) =>
+        {
+            Some(chunks)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Could not load the chunks of {}: {}", url, e);
+            None
+        }
+    }
+}
+
 pub async fn get_best_matching_snippet(
+    state: &AppState,
     url: &str,
     query_embedding: &[f64],
-    pw_context: Arc<BrowserContext>,
-) -> Result<SnippetInfo, String> {
-    let web_embedding = get_website_embedding(url, pw_context).await?;
-    let best_chunk = web_embedding
-        .embeddings
-        .iter()
-        .zip(web_embedding.texts.iter())
-        .fold(
-            (f64::MIN, vec![], String::new()),
-            |acc, (body_emb, body)| {
-                let sim = vec_cos_sim(query_embedding, body_emb).unwrap();
-                if sim > acc.0 {
-                    (sim, body_emb.clone(), body.to_string())
-                } else {
-                    acc
-                }
-            },
-        );
+) -> Result<SnippetInfo> {
+    // Indexed pages are not fetched again
+    let (embeddings, texts, images) = match stored_chunks(state, url, query_embedding).await {
+        Some(chunks) => {
+            let (embeddings, texts) = chunks
+                .into_iter()
+                .map(|chunk| (chunk.embedding, chunk.text))
+                .unzip();
+            (embeddings, texts, Vec::new())
+        }
+        None => {
+            let web_embedding = get_website_embedding(state, url).await?;
+            (
+                web_embedding.embeddings,
+                web_embedding.texts,
+                web_embedding.images,
+            )
+        }
+    };
+    let best_chunk = embeddings.iter().zip(texts.iter()).try_fold(
+        (f64::MIN, vec![], String::new()),
+        |acc, (body_emb, body)| {
+            let sim = vec_cos_sim(query_embedding, body_emb)?;
+            Ok::<_, Error>(if sim > acc.0 {
+                (sim, body_emb.clone(), body.to_string())
+            } else {
+                acc
+            })
+        },
+    )?;
     let mut best_chunk = SnippetInfo {
         embedding: best_chunk.1,
         text: best_chunk.2,
         score: Some(best_chunk.0),
-        images: web_embedding.images,
+        images,
         title: None,
-        url: None
+        url: None,
     };
 
     #[async_recursion]
     async fn find_best_snippet(
+        state: &AppState,
         query_embedding: &[f64],
         current_chunk: &mut SnippetInfo,
         chunk_size: usize,
         target_size: usize,
-    ) {
-        let embeddings = embedding::generate_large_embedding(&current_chunk.text, Some(chunk_size))
-            .await
-            .expect("Failed to generate embedding");
+    ) -> Result<()> {
+        let embeddings =
+            embedding::generate_large_embedding(state, &current_chunk.text, Some(chunk_size))
+                .await?;
 
-        let best_chunk = embeddings.embeddings.iter().zip(embeddings.texts).fold(
-            (f64::MIN, vec![], String::new()),
-            |acc, (body_emb, body)| {
-                let sim = vec_cos_sim(query_embedding, body_emb)
-                    .expect("Failed to calculate cosine similarity");
-                if sim > acc.0 {
-                    (sim, body_emb.clone(), body)
-                } else {
-                    acc
-                }
-            },
-        );
+        let best_chunk = embeddings
+            .embeddings
+            .iter()
+            .zip(embeddings.texts)
+            .try_fold(
+                (f64::MIN, vec![], String::new()),
+                |acc, (body_emb, body)| {
+                    let sim = vec_cos_sim(query_embedding, body_emb)?;
+                    Ok::<_, Error>(if sim > acc.0 {
+                        (sim, body_emb.clone(), body)
+                    } else {
+                        acc
+                    })
+                },
+            )?;
         let best_chunk = SnippetInfo {
             embedding: best_chunk.1,
             text: best_chunk.2,
             score: Some(best_chunk.0),
             images: current_chunk.images.clone(),
             title: None,
-            url: None
+            url: None,
         };
         *current_chunk = best_chunk;
 
-        if current_chunk.text.len() < target_size {
-            return;
+        if current_chunk.text.len() < target_size || chunk_size < 2 {
+            Ok(())
         } else {
-            find_best_snippet(query_embedding, current_chunk, chunk_size / 2, target_size).await;
+            find_best_snippet(
+                state,
+                query_embedding,
+                current_chunk,
+                chunk_size / 2,
+                target_size,
+            )
+            .await
         }
     }
 
     find_best_snippet(
+        state,
         query_embedding,
         &mut best_chunk,
-        MAX_EMBEDDING_SIZE / 2,
-        SNIPPET_TARGET_SIZE,
+        state.config.search.max_embedding_size / 2,
+        state.config.search.snippet_target_size,
     )
-    .await;
+    .await?;
 
     Ok(best_chunk)
 }
 
+/// Snippets of `urls` sorted by score, and the URLs they could not be
+/// extracted from.
 pub async fn get_best_matching_snippets(
+    state: &Arc<AppState>,
     query: &[f64],
     urls: &[String],
     titles: &[String],
-    pw_context: Option<Arc<BrowserContext>>,
-) -> Result<Vec<SnippetInfo>, String> {
-    let mut pw = None;
-    let mut chromium = None;
-    let mut browser = None;
-    let pw_context = match pw_context {
-        Some(pw) => pw,
-        None => {
-            pw = Some(
-                Playwright::initialize()
-                    .await
-                    .expect("Failed to initialize Playwright"),
-            );
-            chromium = Some(pw.as_ref().unwrap().chromium());
-            browser = Some(
-                chromium
-                    .as_ref()
-                    .unwrap()
-                    .launcher()
-                    .headless(true)
-                    .launch()
-                    .await
-                    .expect("Failed to launch browser"),
-            );
-            let context = Arc::new(
-                browser
-                    .as_ref()
-                    .unwrap()
-                    .context_builder()
-                    .build()
-                    .await
-                    .expect("Failed to create context"),
-            );
-
-            context
-        }
-    };
-
+) -> (Vec<SnippetInfo>, Vec<(String, Error)>) {
     let mut join_set = JoinSet::new();
-    for url in urls.iter() {
+    for (idx, url) in urls.iter().enumerate() {
         let url = url.to_string();
         let query = query.to_vec();
-        let pw_context = pw_context.clone();
-        join_set.spawn(
-            async move { get_best_matching_snippet(&url, &query, pw_context.clone()).await },
-        );
+        let state = state.clone();
+        join_set.spawn(async move { (idx, get_best_matching_snippet(&state, &url, &query).await) });
     }
 
     let mut snippets = Vec::new();
-    let mut idx = 0;
+    let mut failures = Vec::new();
     while let Some(result) = join_set.join_next().await {
-        if let Ok(Ok(mut snippet)) = result {
-            snippet.title = Some(titles[idx].clone());
-            snippet.url = Some(urls[idx].clone());
-            snippets.push(snippet);
+        match result {
+            Ok((idx, Ok(mut snippet))) => {
+                snippet.title = Some(titles[idx].clone());
+                snippet.url = Some(urls[idx].clone());
+                snippets.push(snippet);
+            }
+            Ok((idx, Err(e))) => failures.push((urls[idx].clone(), e)),
+            Err(e) => failures.push((String::new(), e.into())),
         }
-        idx += 1;
     }
 
-    // Sort the snippets by their score
+    sort_snippets(&mut snippets);
+
+    (snippets, failures)
+}
+
+/// Score of a snippet, `None` if it has none or it is not a number.
+fn snippet_score(snippet: &SnippetInfo) -> Option<f64> {
+    snippet.score.filter(|score| !score.is_nan())
+}
+
+/// Sorts snippets best first. Snippets without a score go last.
+pub fn sort_snippets(snippets: &mut [SnippetInfo]) {
     snippets.sort_by(|a, b| {
-        a.score
-            .unwrap()
-            .partial_cmp(&b.score.unwrap())
-            .unwrap()
-            .reverse()
+        let a = snippet_score(a).unwrap_or(f64::NEG_INFINITY);
+        let b = snippet_score(b).unwrap_or(f64::NEG_INFINITY);
+        b.total_cmp(&a)
     });
+}
+
+/// Mean score of the snippets that have one, 0 if none has.
+pub fn mean_score(snippets: &[SnippetInfo]) -> f64 {
+    let scores: Vec<f64> = snippets.iter().filter_map(snippet_score).collect();
+    if scores.is_empty() {
+        return 0.0;
+    }
+    scores.iter().sum::<f64>() / scores.len() as f64
+}
 
-    pw_context
-        .close()
-        .await
-        .expect("Failed to close Playwright context");
-    if let Some(browser) = browser {
-        browser.close().await.expect("Failed to close browser");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snippet(score: Option<f64>) -> SnippetInfo {
+        SnippetInfo {
+            embedding: Vec::new(),
+            text: String::new(),
+            score,
+            images: Vec::new(),
+            title: None,
+            url: None,
+        }
     }
 
-    Ok(snippets)
+    #[test]
+    fn snippets_without_scores_go_last() {
+        let mut snippets = vec![
+            snippet(None),
+            snippet(Some(0.2)),
+            snippet(Some(f64::NAN)),
+            snippet(Some(0.9)),
+        ];
+        sort_snippets(&mut snippets);
+        assert_eq!(snippets[0].score, Some(0.9));
+        assert_eq!(snippets[1].score, Some(0.2));
+        assert!((mean_score(&snippets) - 0.55).abs() < 1e-12);
+
+        assert_eq!(mean_score(&[]), 0.0);
+        assert_eq!(mean_score(&[snippet(None)]), 0.0);
+    }
 }
@@ -0,0 +1,159 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{stream, Stream, StreamExt};
+use log::error;
+use searchllama_types::types::{ChatResponse, SearchFrame, Status};
+use serde::Serialize;
+use tokio::sync::mpsc::Receiver;
+use tokio_util::sync::CancellationToken;
+use warp::sse::Event;
+
+/// Hyper only notices a disconnected client when writing to it, so a comment
+/// is sent whenever the stream has been quiet for this long.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A message sent to the client as one server-sent event.
+pub trait Frame: Serialize {
+    /// The SSE event type, so clients can dispatch without decoding the data.
+    fn event(&self) -> &'static str;
+}
+
+impl Frame for SearchFrame {
+    fn event(&self) -> &'static str {
+        self.event.kind()
+    }
+}
+
+impl Frame for ChatResponse {
+    fn event(&self) -> &'static str {
+        if self.status.is_some() {
+            "status"
+        } else if self.error.is_some() {
+            "error"
+        } else {
+            "message"
+        }
+    }
+}
+
+/// Streams the frames of a pipeline as numbered events, followed by a
+/// terminal frame built by `terminal` once every sender has been dropped.
+///
+/// Dropping the stream, which warp does when the client disconnects, cancels
+/// `token`.
+pub fn event_stream<T: Frame + Send + 'static>(
+    receiver: Receiver<T>,
+    failed: Arc<AtomicBool>,
+    token: CancellationToken,
+    terminal: impl FnOnce(Status) -> T + Send + 'static,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let guard = token.drop_guard();
+    let frames = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|frame| (frame, receiver))
+    });
+    let terminal = stream::once(async move {
+        terminal(match failed.load(Ordering::Relaxed) {
+            true => Status::Failed,
+            false => Status::Done,
+        })
+    });
+
+    frames.chain(terminal).enumerate().map(move |(id, frame)| {
+        let _guard = &guard;
+        let event = Event::default().id(id.to_string()).event(frame.event());
+        Ok(event.json_data(&frame).unwrap_or_else(|e| {
+            error!("Failed to serialize event: {}", e);
+            Event::default().comment("serialization error")
+        }))
+    })
+}
+
+/// Replies with `events` as a `text/event-stream`, with keep-alive comments.
+pub fn reply(
+    events: impl Stream<Item = Result<Event, Infallible>> + Send + 'static,
+) -> impl warp::Reply {
+    warp::sse::reply(
+        warp::sse::keep_alive()
+            .interval(KEEP_ALIVE_INTERVAL)
+            .stream(events),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use searchllama_types::types::{SearchEvent, Stage, StageState};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn done(status: Status) -> SearchFrame {
+        SearchEvent::Done { status }.into()
+    }
+
+    #[tokio::test]
+    async fn frames_are_numbered_and_end_once_every_sender_is_gone() {
+        let (sender, receiver) = mpsc::channel(4);
+        let failed = Arc::new(AtomicBool::new(false));
+        let mut events = Box::pin(event_stream(
+            receiver,
+            failed.clone(),
+            CancellationToken::new(),
+            done,
+        ));
+
+        let other = sender.clone();
+        let token = SearchEvent::AnswerToken {
+            text: "Hi".to_string(),
+        };
+        sender.send(token.into()).await.unwrap();
+        drop(sender);
+        assert_eq!(
+            events.next().await.unwrap().unwrap().to_string(),
+            "event:answer_token\ndata:{\"version\":2,\"type\":\"answer_token\",\"text\":\"Hi\"}\nid:0\n\n"
+        );
+
+        // The terminal frame waits for the last sender
+        let finished = SearchEvent::StageStatus {
+            stage: Stage::Answer,
+            state: StageState::Finished,
+        };
+        other.send(finished.into()).await.unwrap();
+        failed.store(true, Ordering::Relaxed);
+        drop(other);
+        let rest = events
+            .map(|event| event.unwrap().to_string())
+            .collect::<Vec<String>>()
+            .await;
+        assert_eq!(rest.len(), 2);
+        assert!(rest[0].starts_with("event:stage_status\n"));
+        assert!(rest[0].ends_with("\nid:1\n\n"));
+        assert_eq!(
+            rest[1],
+            "event:done\ndata:{\"version\":2,\"type\":\"done\",\"status\":\"failed\"}\nid:2\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_the_stream_cancels_the_token() {
+        let (sender, receiver) = mpsc::channel::<SearchFrame>(4);
+        let token = CancellationToken::new();
+        let events = event_stream(
+            receiver,
+            Arc::new(AtomicBool::new(false)),
+            token.clone(),
+            done,
+        );
+
+        assert!(!token.is_cancelled());
+        drop(events);
+        assert!(token.is_cancelled());
+        assert!(sender.is_closed());
+    }
+}
@@ -0,0 +1,177 @@
+//! Encoding of embeddings in the database.
+//!
+//! Every row records the model that produced its embeddings, their dimension
+//! and the format they are encoded in, so that the format can change without
+//! rewriting the index and vectors of another model are never compared with
+//! the query.
+
+use crate::config::EmbeddingFormat;
+
+impl EmbeddingFormat {
+    /// Name of the format in the `embedding_format` columns.
+    pub fn name(self) -> &'static str {
+        match self {
+            EmbeddingFormat::F64 => "f64",
+            EmbeddingFormat::F32 => "f32",
+            EmbeddingFormat::Int8 => "int8",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f64" => Some(EmbeddingFormat::F64),
+            "f32" => Some(EmbeddingFormat::F32),
+            "int8" => Some(EmbeddingFormat::Int8),
+            _ => None,
+        }
+    }
+
+    /// Bytes taken by a vector of `dimension` values.
+    fn vector_size(self, dimension: usize) -> usize {
+        match self {
+            EmbeddingFormat::F64 => dimension * 8,
+            EmbeddingFormat::F32 => dimension * 4,
+            // Preceded by the scale
+            EmbeddingFormat::Int8 => 4 + dimension,
+        }
+    }
+
+    /// Appends `vector` to `bytes`, little endian.
+    fn encode_into(self, vector: &[f64], bytes: &mut Vec<u8>) {
+        match self {
+            EmbeddingFormat::F64 => {
+                for value in vector {
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            EmbeddingFormat::F32 => {
+                for value in vector {
+                    bytes.extend_from_slice(&(*value as f32).to_le_bytes());
+                }
+            }
+            EmbeddingFormat::Int8 => {
+                // Symmetric around 0, so that the largest value maps to ±127
+                let max = vector
+                    .iter()
+                    .fold(0.0, |max: f64, value| max.max(value.abs()));
+                let scale = (max / 127.0) as f32;
+                bytes.extend_from_slice(&scale.to_le_bytes());
+                for value in vector {
+                    let quantized = if scale > 0.0 {
+                        (value / scale as f64).round().clamp(-127.0, 127.0) as i8
+                    } else {
+                        0
+                    };
+                    bytes.push(quantized as u8);
+                }
+            }
+        }
+    }
+
+    fn decode_vector(self, bytes: &[u8]) -> Vec<f64> {
+        match self {
+            EmbeddingFormat::F64 => bytes
+                .chunks_exact(8)
+                .map(|value| f64::from_le_bytes(value.try_into().unwrap()))
+                .collect(),
+            EmbeddingFormat::F32 => bytes
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes(value.try_into().unwrap()) as f64)
+                .collect(),
+            EmbeddingFormat::Int8 => {
+                let scale = f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64;
+                bytes[4..]
+                    .iter()
+                    .map(|&value| value as i8 as f64 * scale)
+                    .collect()
+            }
+        }
+    }
+
+    /// The vectors concatenated in `bytes`, or `None` if there is not a
+    /// whole number of them.
+    pub fn decode(self, bytes: &[u8], dimension: usize) -> Option<Vec<Vec<f64>>> {
+        let size = self.vector_size(dimension);
+        if dimension == 0 || !bytes.len().is_multiple_of(size) {
+            return None;
+        }
+        Some(
+            bytes
+                .chunks_exact(size)
+                .map(|vector| self.decode_vector(vector))
+                .collect(),
+        )
+    }
+
+    /// Format of the chunk embeddings stored next to page embeddings in this
+    /// format. Quantized rankings are rescored from them, so they are not
+    /// quantized themselves.
+    pub fn for_chunks(self) -> Self {
+        match self {
+            EmbeddingFormat::Int8 => EmbeddingFormat::F32,
+            format => format,
+        }
+    }
+}
+
+/// The model embeddings are computed with and the format new ones are
+/// stored in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorEncoding {
+    /// `Embedder::model_id` of the embedder.
+    pub model: String,
+    pub format: EmbeddingFormat,
+}
+
+impl VectorEncoding {
+    /// `vectors` concatenated in `format`.
+    pub fn encode<V: AsRef<[f64]>>(format: EmbeddingFormat, vectors: &[V]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for vector in vectors {
+            format.encode_into(vector.as_ref(), &mut bytes);
+        }
+        bytes
+    }
+
+    /// Whether vectors stored for `model` can be compared with this model's.
+    /// Rows that do not record their model are assumed to match.
+    pub fn accepts(&self, model: Option<&str>) -> bool {
+        model.is_none_or(|model| model == self.model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_round_trip() {
+        let vectors = vec![vec![0.5, -0.25, 0.125], vec![1.0, 0.0, -1.0]];
+        for format in [EmbeddingFormat::F64, EmbeddingFormat::F32] {
+            let bytes = VectorEncoding::encode(format, &vectors);
+            assert_eq!(bytes.len(), format.vector_size(3) * 2);
+            assert_eq!(format.decode(&bytes, 3), Some(vectors.clone()));
+            assert_eq!(EmbeddingFormat::from_name(format.name()), Some(format));
+        }
+        assert_eq!(EmbeddingFormat::F64.decode(&[0; 20], 3), None);
+        assert_eq!(EmbeddingFormat::F32.decode(&[], 0), None);
+    }
+
+    #[test]
+    fn int8_is_close_to_the_original() {
+        let vector: Vec<f64> = (0..64)
+            .map(|i| ((i * 37) % 64) as f64 / 32.0 - 1.0)
+            .collect();
+        let bytes = VectorEncoding::encode(EmbeddingFormat::Int8, &[&vector, &vec![0.0; 64]]);
+        assert_eq!(bytes.len(), 2 * (4 + 64));
+
+        let decoded = EmbeddingFormat::Int8.decode(&bytes, 64).unwrap();
+        let max = vector
+            .iter()
+            .fold(0.0, |max: f64, value| max.max(value.abs()));
+        for (value, original) in decoded[0].iter().zip(&vector) {
+            assert!((value - original).abs() <= max / 254.0 + 1e-6);
+        }
+        assert_eq!(decoded[1], vec![0.0; 64]);
+    }
+}
@@ -0,0 +1,683 @@
+//! Approximate nearest-neighbour search over the page embeddings, so that
+//! ranking the local index does not decode every row of `indices`.
+//!
+//! The index is a hierarchical navigable small world graph (HNSW) over the
+//! title and body embeddings of every page. It only proposes candidate pages;
+//! `database::query_db` scores them exactly, so a page that is found is ranked
+//! as before and the approximation can only cost recall.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+
+/// Links per node on the upper layers; the bottom layer has twice as many.
+const M: usize = 16;
+/// Candidates considered when linking a new node.
+const EF_CONSTRUCTION: usize = 100;
+/// Smallest candidate list of a query.
+const EF_SEARCH: usize = 64;
+const MAX_LEVEL: usize = 16;
+/// Nodes of removed pages are dropped by rebuilding the graph once they
+/// outnumber the live ones and there are at least this many.
+const COMPACT_AFTER: usize = 1024;
+
+const MAGIC: &[u8; 8] = b"SLHNSW01";
+
+/// What the index was built from: the number of pages and the latest
+/// `updated_at` among them. An index whose stamp differs from the table is
+/// stale, e.g. after a crash between two saves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stamp {
+    pub pages: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Near {
+    distance: f32,
+    node: u32,
+}
+
+impl Eq for Near {}
+
+impl PartialOrd for Near {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Near {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Page {
+    url: String,
+    removed: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    page: u32,
+    /// Normalized, so that the cosine similarity is the dot product.
+    vector: Vec<f32>,
+    /// Neighbours on each layer the node is part of, from the bottom up.
+    links: Vec<Vec<u32>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Graph {
+    /// Length of the vectors; set by the first one inserted.
+    dimension: usize,
+    pages: Vec<Page>,
+    page_ids: HashMap<String, u32>,
+    nodes: Vec<Node>,
+    entry: Option<u32>,
+    dead_nodes: usize,
+    updated_at: i64,
+    rng: u64,
+}
+
+fn normalize(vector: &[f64]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm == 0.0 {
+        return vec![0.0; vector.len()];
+    }
+    vector.iter().map(|x| (x / norm) as f32).collect()
+}
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>()
+}
+
+impl Graph {
+    fn stamp(&self) -> Stamp {
+        Stamp {
+            pages: self.page_ids.len() as i64,
+            updated_at: self.updated_at,
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64*; the seed is fixed so that builds are reproducible
+        if self.rng == 0 {
+            self.rng = 0x2545_f491_4f6c_dd1d;
+        }
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        let uniform = (bits as f64 + 1.0) / (1u64 << 53) as f64;
+        ((-uniform.ln() / (M as f64).ln()) as usize).min(MAX_LEVEL)
+    }
+
+    fn distance_to(&self, query: &[f32], node: u32) -> f32 {
+        distance(query, &self.nodes[node as usize].vector)
+    }
+
+    /// The `ef` nodes closest to `query` on `level`, nearest first, found by
+    /// a best-first walk from `entries`.
+    fn search_layer(&self, query: &[f32], entries: &[u32], ef: usize, level: usize) -> Vec<Near> {
+        let mut visited: HashSet<u32> = entries.iter().copied().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut found = BinaryHeap::new();
+        for &node in entries {
+            let near = Near {
+                distance: self.distance_to(query, node),
+                node,
+            };
+            candidates.push(Reverse(near));
+            found.push(near);
+        }
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            let furthest = found.peek().map_or(f32::MAX, |near: &Near| near.distance);
+            if found.len() >= ef && candidate.distance > furthest {
+                break;
+            }
+            for &next in &self.nodes[candidate.node as usize].links[level] {
+                if !visited.insert(next) {
+                    continue;
+                }
+                let near = Near {
+                    distance: self.distance_to(query, next),
+                    node: next,
+                };
+                let furthest = found.peek().map_or(f32::MAX, |near: &Near| near.distance);
+                if found.len() < ef || near.distance < furthest {
+                    candidates.push(Reverse(near));
+                    found.push(near);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        found.into_sorted_vec()
+    }
+
+    /// Walks down the upper layers to the node closest to `query` on `level`.
+    fn descend(&self, query: &[f32], level: usize) -> Vec<u32> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let top = self.nodes[entry as usize].links.len() - 1;
+        let mut entries = vec![entry];
+        for layer in (level + 1..=top).rev() {
+            entries = vec![self.search_layer(query, &entries, 1, layer)[0].node];
+        }
+        entries
+    }
+
+    fn insert(&mut self, page: u32, vector: Vec<f32>) {
+        let id = self.nodes.len() as u32;
+        let level = self.random_level();
+        let top = self
+            .entry
+            .map(|entry| self.nodes[entry as usize].links.len() - 1);
+        let mut entries = self.descend(&vector, level);
+
+        let mut links = vec![Vec::new(); level + 1];
+        let mut found_on = Vec::new();
+        for layer in (0..=level.min(top.unwrap_or(0))).rev() {
+            if entries.is_empty() {
+                break;
+            }
+            let found = self.search_layer(&vector, &entries, EF_CONSTRUCTION, layer);
+            links[layer] = found.iter().take(M).map(|near| near.node).collect();
+            entries = found.iter().map(|near| near.node).collect();
+            found_on.push(layer);
+        }
+        self.nodes.push(Node {
+            page,
+            vector,
+            links,
+        });
+
+        for layer in found_on {
+            let max_links = if layer == 0 { 2 * M } else { M };
+            for neighbour in self.nodes[id as usize].links[layer].clone() {
+                let links = &mut self.nodes[neighbour as usize].links[layer];
+                links.push(id);
+                if links.len() > max_links {
+                    self.prune(neighbour, layer, max_links);
+                }
+            }
+        }
+
+        if top.is_none_or(|top| level > top) {
+            self.entry = Some(id);
+        }
+    }
+
+    /// Keeps the `max_links` nearest neighbours of `node` on `level`.
+    fn prune(&mut self, node: u32, level: usize, max_links: usize) {
+        let vector = &self.nodes[node as usize].vector;
+        let mut links: Vec<Near> = self.nodes[node as usize].links[level]
+            .iter()
+            .map(|&link| Near {
+                distance: distance(vector, &self.nodes[link as usize].vector),
+                node: link,
+            })
+            .collect();
+        links.sort();
+        links.truncate(max_links);
+        self.nodes[node as usize].links[level] = links.into_iter().map(|near| near.node).collect();
+    }
+
+    fn remove_page(&mut self, url: &str) {
+        let Some(page) = self.page_ids.remove(url) else {
+            return;
+        };
+        self.pages[page as usize].removed = true;
+        self.dead_nodes += self.nodes.iter().filter(|node| node.page == page).count();
+    }
+
+    fn add_page(&mut self, url: &str, vectors: &[&[f64]], updated_at: i64) {
+        self.remove_page(url);
+        let page = self.pages.len() as u32;
+        self.pages.push(Page {
+            url: url.to_string(),
+            removed: false,
+        });
+        self.page_ids.insert(url.to_string(), page);
+        self.updated_at = self.updated_at.max(updated_at);
+
+        for vector in vectors {
+            if self.dimension == 0 {
+                self.dimension = vector.len();
+            }
+            // Vectors of another model cannot be compared with the others
+            if vector.len() == self.dimension {
+                self.insert(page, normalize(vector));
+            }
+        }
+
+        if self.dead_nodes > COMPACT_AFTER && self.dead_nodes > self.nodes.len() / 2 {
+            self.compact();
+        }
+    }
+
+    /// Rebuilds the graph without the nodes of removed pages.
+    fn compact(&mut self) {
+        let old = std::mem::take(self);
+        self.dimension = old.dimension;
+        self.updated_at = old.updated_at;
+        self.rng = old.rng;
+        let mut remap = HashMap::new();
+        for (id, page) in old.pages.iter().enumerate() {
+            if !page.removed {
+                remap.insert(id as u32, self.pages.len() as u32);
+                self.page_ids
+                    .insert(page.url.clone(), self.pages.len() as u32);
+                self.pages.push(page.clone());
+            }
+        }
+        for node in old.nodes {
+            if let Some(&page) = remap.get(&node.page) {
+                self.insert(page, node.vector);
+            }
+        }
+    }
+
+    /// URLs of up to `limit` pages with a vector near `query`, nearest first.
+    fn candidates(&self, query: &[f64], limit: usize) -> Option<Vec<String>> {
+        if self.entry.is_none() || query.len() != self.dimension {
+            return None;
+        }
+        let query = normalize(query);
+        let entries = self.descend(&query, 0);
+        // Pages have several vectors each, so look at more than `limit`
+        let found = self.search_layer(&query, &entries, (limit * 4).max(EF_SEARCH), 0);
+
+        let mut seen = HashSet::new();
+        Some(
+            found
+                .into_iter()
+                .map(|near| self.nodes[near.node as usize].page)
+                .filter(|&page| !self.pages[page as usize].removed && seen.insert(page))
+                .take(limit)
+                .map(|page| self.pages[page as usize].url.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Serialization of the graph, little endian throughout.
+impl Graph {
+    fn to_bytes(&self) -> Vec<u8> {
+        fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        put_u32(&mut bytes, self.dimension as u32);
+        bytes.extend_from_slice(&self.updated_at.to_le_bytes());
+        bytes.extend_from_slice(&self.rng.to_le_bytes());
+        put_u32(&mut bytes, self.entry.unwrap_or(u32::MAX));
+
+        put_u32(&mut bytes, self.pages.len() as u32);
+        for page in &self.pages {
+            bytes.push(page.removed as u8);
+            put_u32(&mut bytes, page.url.len() as u32);
+            bytes.extend_from_slice(page.url.as_bytes());
+        }
+
+        put_u32(&mut bytes, self.nodes.len() as u32);
+        for node in &self.nodes {
+            put_u32(&mut bytes, node.page);
+            put_u32(&mut bytes, node.vector.len() as u32);
+            for x in &node.vector {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+            put_u32(&mut bytes, node.links.len() as u32);
+            for links in &node.links {
+                put_u32(&mut bytes, links.len() as u32);
+                for &link in links {
+                    put_u32(&mut bytes, link);
+                }
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        struct Reader<'a>(&'a [u8]);
+
+        impl<'a> Reader<'a> {
+            fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+                if len > self.0.len() {
+                    return None;
+                }
+                let (head, rest) = self.0.split_at(len);
+                self.0 = rest;
+                Some(head)
+            }
+
+            fn u32(&mut self) -> Option<u32> {
+                Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+            }
+
+            fn u64(&mut self) -> Option<u64> {
+                Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+            }
+
+            fn f32(&mut self) -> Option<f32> {
+                Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+            }
+        }
+
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let mut graph = Graph {
+            dimension: reader.u32()? as usize,
+            updated_at: reader.u64()? as i64,
+            rng: reader.u64()?,
+            ..Default::default()
+        };
+        graph.entry = Some(reader.u32()?).filter(|&entry| entry != u32::MAX);
+
+        for id in 0..reader.u32()? {
+            let removed = reader.take(1)?[0] != 0;
+            let len = reader.u32()? as usize;
+            let url = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+            if !removed {
+                graph.page_ids.insert(url.clone(), id);
+            }
+            graph.pages.push(Page { url, removed });
+        }
+
+        for _ in 0..reader.u32()? {
+            let page = reader.u32()?;
+            let vector = (0..reader.u32()?)
+                .map(|_| reader.f32())
+                .collect::<Option<Vec<f32>>>()?;
+            let mut links = Vec::new();
+            for _ in 0..reader.u32()? {
+                links.push(
+                    (0..reader.u32()?)
+                        .map(|_| reader.u32())
+                        .collect::<Option<Vec<u32>>>()?,
+                );
+            }
+            if graph.pages.get(page as usize)?.removed {
+                graph.dead_nodes += 1;
+            }
+            graph.nodes.push(Node {
+                page,
+                vector,
+                links,
+            });
+        }
+
+        // Reject links to nodes that do not exist or lack the layer they are
+        // linked on, and an entry below the top layer, rather than panic later
+        let layers = |id: u32| graph.nodes.get(id as usize).map(|node| node.links.len());
+        let top = graph.nodes.iter().map(|node| node.links.len()).max();
+        let valid = graph
+            .entry
+            .is_none_or(|entry| layers(entry).is_some() && layers(entry) == top)
+            && graph.nodes.iter().all(|node| {
+                !node.links.is_empty()
+                    && node.links.iter().enumerate().all(|(level, links)| {
+                        links
+                            .iter()
+                            .all(|&link| layers(link).is_some_and(|layers| layers > level))
+                    })
+            });
+        (reader.0.is_empty() && valid).then_some(graph)
+    }
+}
+
+/// The vector index of the pages in `indices`, shared by all requests.
+pub struct VectorIndex {
+    /// `None` for a database in memory, whose index is not saved either.
+    path: Option<PathBuf>,
+    graph: RwLock<Graph>,
+    dirty: AtomicBool,
+}
+
+impl VectorIndex {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            graph: RwLock::new(Graph::default()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Where the index of the database at `database` is saved.
+    pub fn path_for(database: &Path) -> PathBuf {
+        let mut path = OsString::from(database);
+        path.push(".hnsw");
+        PathBuf::from(path)
+    }
+
+    /// Loads the saved index. Returns `None` if there is none or it cannot
+    /// be read, in which case it has to be rebuilt.
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let Some(graph) = Graph::from_bytes(&bytes) else {
+            warn!("Ignoring corrupt vector index {}", path.display());
+            return None;
+        };
+        Some(Self {
+            path: Some(path.to_path_buf()),
+            graph: RwLock::new(graph),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn stamp(&self) -> Stamp {
+        self.graph.read().unwrap().stamp()
+    }
+
+    /// Number of pages in the index.
+    pub fn len(&self) -> usize {
+        self.graph.read().unwrap().page_ids.len()
+    }
+
+    /// Adds a page, replacing the vectors it had before.
+    pub fn add_page(
+        &self,
+        url: &str,
+        title_embedding: &[f64],
+        body_embeddings: &[Vec<f64>],
+        updated_at: i64,
+    ) {
+        let vectors: Vec<&[f64]> = std::iter::once(title_embedding)
+            .chain(body_embeddings.iter().map(Vec::as_slice))
+            .filter(|vector| !vector.is_empty())
+            .collect();
+        self.graph
+            .write()
+            .unwrap()
+            .add_page(url, &vectors, updated_at);
+        self.dirty.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// URLs of up to `limit` pages close to `query`, nearest first. `None`
+    /// when the index cannot answer, e.g. because it is empty or was built
+    /// from vectors of another length.
+    pub fn candidates(&self, query: &[f64], limit: usize) -> Option<Vec<String>> {
+        self.graph.read().unwrap().candidates(query, limit)
+    }
+
+    /// Writes the index next to the database if it changed since the last
+    /// save. The file is replaced atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, atomic::Ordering::Relaxed) {
+            return Ok(());
+        }
+        let bytes = self.graph.read().unwrap().to_bytes();
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, bytes)
+            .and_then(|_| fs::rename(&temporary, path))
+            .inspect_err(|_| self.dirty.store(true, atomic::Ordering::Relaxed))
+    }
+
+    /// Saves the index every `interval` while the server runs. An index that
+    /// is behind the database at startup is rebuilt, so changes since the
+    /// last save are not lost.
+    pub fn spawn_saves(self: &Arc<Self>, interval: Duration) {
+        if self.path.is_none() {
+            return;
+        }
+        let index = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(index) = index.upgrade() else {
+                    return;
+                };
+                match tokio::task::spawn_blocking(move || index.save()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Failed to save the vector index: {}", e),
+                    Err(e) => warn!("Failed to save the vector index: {}", e),
+                }
+            }
+        });
+        info!("Saving the vector index every {:?}", interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f64>> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                (0..dimension)
+                    .map(|_| {
+                        state = state
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407);
+                        (state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_nearest_pages() {
+        let index = VectorIndex::new(None);
+        let vectors = random_vectors(500, 16, 1);
+        for (i, vector) in vectors.iter().enumerate() {
+            index.add_page(&i.to_string(), vector, &[], 0);
+        }
+
+        let mut hits = 0;
+        for query in random_vectors(20, 16, 2) {
+            let mut exact: Vec<(f64, usize)> = vectors
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (-crate::embedding::vec_cos_sim(&query, v).unwrap(), i))
+                .collect();
+            exact.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let found = index.candidates(&query, 10).unwrap();
+            hits += exact[..10]
+                .iter()
+                .filter(|(_, i)| found.contains(&i.to_string()))
+                .count();
+        }
+        assert!(hits >= 190, "recall {} / 200", hits);
+    }
+
+    #[test]
+    fn replaced_pages_are_not_returned_twice() {
+        let index = VectorIndex::new(None);
+        index.add_page("a", &[1.0, 0.0], &[], 1);
+        index.add_page("b", &[0.0, 1.0], &[], 1);
+        index.add_page("a", &[0.0, 1.0], &[vec![0.1, 1.0]], 2);
+
+        assert_eq!(
+            index.stamp(),
+            Stamp {
+                pages: 2,
+                updated_at: 2
+            }
+        );
+        let found = index.candidates(&[0.0, 1.0], 10).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(index.candidates(&[1.0, 0.0, 0.0], 10).is_none());
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let index = VectorIndex::new(None);
+        for (i, vector) in random_vectors(100, 8, 3).iter().enumerate() {
+            index.add_page(
+                &i.to_string(),
+                vector,
+                std::slice::from_ref(vector),
+                i as i64,
+            );
+        }
+        index.add_page("0", &[1.0; 8], &[], 100);
+
+        let graph = index.graph.read().unwrap();
+        let loaded = Graph::from_bytes(&graph.to_bytes()).unwrap();
+        assert_eq!(loaded.stamp(), graph.stamp());
+        assert_eq!(loaded.dead_nodes, graph.dead_nodes);
+        let query = [0.5; 8];
+        assert_eq!(loaded.candidates(&query, 5), graph.candidates(&query, 5));
+        assert!(Graph::from_bytes(&graph.to_bytes()[..100]).is_none());
+    }
+
+    #[test]
+    fn rejects_links_above_a_nodes_layers() {
+        let index = VectorIndex::new(None);
+        for (i, vector) in random_vectors(200, 4, 5).iter().enumerate() {
+            index.add_page(&i.to_string(), vector, &[], i as i64);
+        }
+        let graph = index.graph.read().unwrap();
+        let entry = graph.entry.unwrap();
+        let top = graph.nodes[entry as usize].links.len() - 1;
+        assert!(top > 0, "the entry has upper layers");
+        let ground = (0..graph.nodes.len() as u32)
+            .find(|&id| graph.nodes[id as usize].links.len() == 1)
+            .unwrap();
+
+        let mut linked_above = graph.clone();
+        linked_above.nodes[entry as usize].links[top].push(ground);
+        assert!(Graph::from_bytes(&linked_above.to_bytes()).is_none());
+
+        let mut low_entry = graph.clone();
+        low_entry.entry = Some(ground);
+        assert!(Graph::from_bytes(&low_entry.to_bytes()).is_none());
+    }
+}